@@ -0,0 +1,70 @@
+//! Build and lookup times of the domain store backends on a synthetic list.
+//! Run with `cargo bench --bench db_backends`.
+
+use bancuh_dns::db::{DBBackend, DomainStore};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+
+const DOMAINS: usize = 300_000;
+
+fn domains() -> Vec<String> {
+    (0..DOMAINS)
+        .map(|i| format!("host{i}.domain{}.example{}.com", i % 1000, i % 7))
+        .collect()
+}
+
+/// Every other query is a subdomain of a listed name, so it walks the wildcard keys
+fn queries(domains: &[String]) -> Vec<String> {
+    domains
+        .iter()
+        .step_by(3)
+        .enumerate()
+        .map(|(i, d)| {
+            if i % 2 == 0 {
+                format!("{d}.")
+            } else {
+                format!("www.{d}.")
+            }
+        })
+        .collect()
+}
+
+fn build(backend: DBBackend, domains: &[String]) -> DomainStore {
+    let store = DomainStore::create(backend).unwrap();
+    store
+        .put_batch(domains.iter().map(|d| (d.as_str(), "ads/bench")))
+        .unwrap();
+    store.finish().unwrap();
+    store
+}
+
+fn bench_build(c: &mut Criterion) {
+    let domains = domains();
+    let mut group = c.benchmark_group("build");
+    group.sample_size(10);
+    for backend in [DBBackend::Rocksdb, DBBackend::Fst] {
+        group.bench_function(BenchmarkId::from_parameter(format!("{backend:?}")), |b| {
+            b.iter_batched(|| (), |_| build(backend, &domains), BatchSize::PerIteration)
+        });
+    }
+    group.finish();
+}
+
+fn bench_lookup(c: &mut Criterion) {
+    let domains = domains();
+    let queries = queries(&domains);
+    let mut group = c.benchmark_group("lookup");
+    for backend in [DBBackend::Rocksdb, DBBackend::Fst] {
+        let store = build(backend, &domains);
+        let mut i = 0;
+        group.bench_function(BenchmarkId::from_parameter(format!("{backend:?}")), |b| {
+            b.iter(|| {
+                i = (i + 1) % queries.len();
+                store.get(&queries[i]).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_build, bench_lookup);
+criterion_main!(benches);
@@ -1,25 +1,171 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    net::SocketAddr,
+    sync::Arc,
+};
 
 use axum::{
-    extract::{ConnectInfo, State},
-    response::Html,
-    routing::get,
-    Json, Router,
+    body::{Body, Bytes},
+    extract::{ConnectInfo, Path, Query, Request, State},
+    http::{header, HeaderMap, Method, StatusCode, Uri},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html, IntoResponse, Redirect, Response,
+    },
+    routing::{get, post},
+    Form, Json, Router,
 };
 use axum_server::{tls_rustls::RustlsConfig, Handle};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use flate2::{write::GzEncoder, Compression};
+use futures::{Stream, StreamExt};
 use rustls_acme::ResolvesServerCertAcme;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::sync::{broadcast::error::RecvError, Notify};
 use tokio_util::sync::CancellationToken;
 
-use crate::query_log::{QueryLog, QueryLogStore};
+use crate::{
+    config::Category,
+    db::AdblockDB,
+    engine::{AdblockEngine, CategoryStatus, EngineError, UpdateStatus},
+    export::{export, ExportError, ExportFormat, ExportOptions},
+    health::{Health, Readiness},
+    metrics::Metrics,
+    metrics::Outcome,
+    privacy::{OptOutError, PrivacyMode},
+    query_log::{non_empty, ClientQueryLog, LogFilter, QueryLog, QueryLogStore},
+    stats::{Period, StatsReport, StatsStore, TopEntry},
+};
+
+/// Entries in each top list when `top` is not given, and the most that can be asked for
+const DEFAULT_TOP: usize = 10;
+const MAX_TOP: usize = 100;
+
+/// Entries returned by a log search when `limit` is not given, and the most that can be asked for
+const DEFAULT_LIMIT: usize = 500;
+const MAX_LIMIT: usize = 5000;
+
+#[derive(Error, Debug)]
+pub enum AdminError {
+    #[error("InvalidTokenHash: expected the sha256 of the token as 64 hex characters")]
+    InvalidTokenHash,
+}
+
+/// Operator credentials, only the sha256 of the token is kept
+#[derive(Debug, Clone)]
+pub struct OperatorAuth {
+    token_sha256: [u8; 32],
+}
+
+impl OperatorAuth {
+    pub fn from_hex(hex: &str) -> Result<Self, AdminError> {
+        let hex = hex.trim();
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(AdminError::InvalidTokenHash);
+        }
+
+        let mut token_sha256 = [0; 32];
+        for (i, byte) in token_sha256.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+                .map_err(|_| AdminError::InvalidTokenHash)?;
+        }
+
+        Ok(Self { token_sha256 })
+    }
+
+    /// Accepts `Authorization: Bearer <token>`, or basic auth with the token as password
+    fn check(&self, headers: &HeaderMap) -> bool {
+        let Some(value) = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+        else {
+            return false;
+        };
+
+        let token = if let Some(token) = value.strip_prefix("Bearer ") {
+            token.trim().to_string()
+        } else if let Some(basic) = value.strip_prefix("Basic ") {
+            let decoded = BASE64_STANDARD.decode(basic.trim()).unwrap_or_default();
+            let decoded = String::from_utf8_lossy(&decoded);
+            match decoded.split_once(':') {
+                Some((_, password)) => password.to_string(),
+                None => return false,
+            }
+        } else {
+            return false;
+        };
+
+        // compare in constant time
+        let digest = Sha256::digest(token.as_bytes());
+        digest
+            .iter()
+            .zip(self.token_sha256)
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+    }
+}
 
 #[derive(Clone)]
-struct AppState {
-    query_log: Arc<QueryLogStore>,
+pub struct AdminState {
+    pub engine: Arc<AdblockEngine>,
+    pub query_log: Arc<QueryLogStore>,
+    pub metrics: Arc<Metrics>,
+    pub stats: Arc<StatsStore>,
+    /// Enables the operator views when set
+    pub auth: Option<OperatorAuth>,
+    /// Ends live streams, so they do not hold up a graceful shutdown
+    pub shutdown: CancellationToken,
+    /// Wakes the update loop for an update now
+    pub update: Arc<Notify>,
+    pub health: Arc<Health>,
+    /// Origin of exported RPZ zones, the zone served over `RPZ_PORT`
+    pub rpz_zone: String,
+    pub exports: Arc<ExportCache>,
+}
+
+impl AdminState {
+    fn is_operator(&self, headers: &HeaderMap) -> bool {
+        self.auth.as_ref().is_some_and(|auth| auth.check(headers))
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct StatsInput {
+    #[serde(default)]
+    period: Period,
+    top: Option<usize>,
+}
+
+impl StatsInput {
+    /// The top clients are left out unless the request comes from an operator
+    fn report(&self, state: &AdminState, headers: &HeaderMap) -> StatsReport {
+        let top = self.top.unwrap_or(DEFAULT_TOP).min(MAX_TOP);
+        let mut report = state.stats.report(self.period, top);
+        if !state.is_operator(headers) {
+            report.top_clients.clear();
+        }
+        report
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct CategoryInput {
+    enabled: bool,
+}
+
+#[derive(serde::Deserialize)]
+struct OptOutInput {
+    opt_out: bool,
 }
 
 #[derive(serde::Serialize)]
 struct LogsApiOutput {
     ip: String,
+    privacy: PrivacyMode,
+    opted_out: bool,
     queries: Vec<QueryLog>,
 }
 
@@ -29,36 +175,100 @@ fn normalize_ip(addr: SocketAddr) -> String {
     ip.strip_prefix("::ffff:").unwrap_or(&ip).to_string()
 }
 
-async fn get_logs_api(
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    State(state): State<AppState>,
-) -> Json<LogsApiOutput> {
+fn logs_output(addr: SocketAddr, query_log: &QueryLogStore) -> LogsApiOutput {
     let ip_str = normalize_ip(addr);
     let ip = ip_str.parse().unwrap_or(addr.ip());
-    let queries = state.query_log.get_logs(&ip);
+    let privacy = query_log.privacy();
 
-    Json(LogsApiOutput {
+    LogsApiOutput {
         ip: ip_str,
-        queries,
-    })
+        privacy: privacy.mode(),
+        opted_out: privacy.is_opted_out(ip),
+        queries: query_log.get_logs(ip),
+    }
+}
+
+async fn get_logs_api(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<AdminState>,
+) -> Json<LogsApiOutput> {
+    Json(logs_output(addr, &state.query_log))
+}
+
+/// Stop or resume logging the queries of the caller's own IP
+fn set_opt_out(
+    addr: SocketAddr,
+    query_log: &QueryLogStore,
+    opt_out: bool,
+) -> Result<(), StatusCode> {
+    let ip = normalize_ip(addr).parse().unwrap_or(addr.ip());
+    match query_log.privacy().set_opted_out(ip, opt_out) {
+        Ok(()) => {}
+        Err(err @ OptOutError::Full) => {
+            tracing::warn!("Refused query log opt-out for {ip}: {err}");
+            return Err(StatusCode::SERVICE_UNAVAILABLE);
+        }
+        Err(err) => {
+            tracing::error!("Failed to save query log opt-out: {err}");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+    if opt_out {
+        query_log.forget(ip);
+    }
+
+    Ok(())
+}
+
+async fn set_opt_out_api(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<AdminState>,
+    Json(input): Json<OptOutInput>,
+) -> Result<Json<LogsApiOutput>, StatusCode> {
+    set_opt_out(addr, &state.query_log, input.opt_out)?;
+    Ok(Json(logs_output(addr, &state.query_log)))
+}
+
+async fn set_opt_out_form(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<AdminState>,
+    Form(input): Form<OptOutInput>,
+) -> Result<Redirect, StatusCode> {
+    set_opt_out(addr, &state.query_log, input.opt_out)?;
+    Ok(Redirect::to("/logs"))
 }
 
 async fn get_logs_html(
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    State(state): State<AppState>,
+    State(state): State<AdminState>,
 ) -> Html<String> {
-    let ip_str = normalize_ip(addr);
-    let ip = ip_str.parse().unwrap_or(addr.ip());
-    let queries = state.query_log.get_logs(&ip);
+    let LogsApiOutput {
+        ip: ip_str,
+        privacy,
+        opted_out,
+        queries,
+    } = logs_output(addr, &state.query_log);
     let active_ips = state.query_log.active_ips();
+    let minutes = state.query_log.max_age().as_secs() / 60;
+
+    let (action, label) = if opted_out {
+        ("false", "Resume logging my queries")
+    } else {
+        ("true", "Stop logging my queries")
+    };
+    let notice = match privacy {
+        _ if opted_out => "Your queries are not being logged.",
+        PrivacyMode::Full | PrivacyMode::Hashed => "",
+        PrivacyMode::Anonymized => "Queries are logged per subnet, so they are not shown here.",
+        PrivacyMode::Domains | PrivacyMode::Off => "Queries are not logged per client.",
+    };
 
     let mut rows = String::new();
     for q in &queries {
         rows.push_str(&format!(
-            "<tr><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            "<tr><td>{}</td>{}</tr>\n",
             q.query_time.format("%Y-%m-%d %H:%M:%S"),
-            html_escape(&q.question),
-            html_escape(&q.answer),
+            log_cells(q),
         ));
     }
 
@@ -77,10 +287,15 @@ async fn get_logs_html(
 <body>
   <h2>Bancuh DNS - Query Logs</h2>
   <p>Your IP: <strong>{ip_str}</strong></p>
-  <p>Active IPs (10 min): <strong>{active_ips}</strong></p>
+  <form method="post" action="/logs/opt-out">
+    <input type="hidden" name="opt_out" value="{action}">
+    <button type="submit">{label}</button>
+  </form>
+  <p>{notice}</p>
+  <p>Active clients ({minutes} min): <strong>{active_ips}</strong></p>
   <p>Showing {count} queries</p>
   <table>
-    <tr><th>Timestamp</th><th>Query</th><th>Answer</th></tr>
+    <tr><th>Timestamp</th>{LOG_HEADERS}</tr>
     {rows}
   </table>
 </body>
@@ -91,27 +306,831 @@ async fn get_logs_html(
     Html(html)
 }
 
+async fn get_categories_api(State(state): State<AdminState>) -> Json<Vec<CategoryStatus>> {
+    Json(state.engine.categories())
+}
+
+async fn set_category_api(
+    Path(category): Path<Category>,
+    State(state): State<AdminState>,
+    Json(input): Json<CategoryInput>,
+) -> Json<Vec<CategoryStatus>> {
+    state.engine.set_category_enabled(category, input.enabled);
+    Json(state.engine.categories())
+}
+
+async fn set_category_form(
+    Path(category): Path<Category>,
+    State(state): State<AdminState>,
+    Form(input): Form<CategoryInput>,
+) -> Redirect {
+    state.engine.set_category_enabled(category, input.enabled);
+    Redirect::to("/categories")
+}
+
+async fn get_categories_html(State(state): State<AdminState>) -> Html<String> {
+    let mut rows = String::new();
+    for c in state.engine.categories() {
+        let sources = c
+            .sources
+            .iter()
+            .map(|s| {
+                format!(
+                    "{} ({}, {} entries)",
+                    html_escape(&s.name),
+                    s.kind,
+                    s.entries
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("<br>");
+        let (status, action, label) = if c.enabled {
+            ("enabled", "false", "Disable")
+        } else {
+            ("disabled", "true", "Enable")
+        };
+
+        rows.push_str(&format!(
+            "<tr><td>{category}</td><td>{status}</td><td>{sources}</td><td>\
+             <form method=\"post\" action=\"/categories/{category}\">\
+             <input type=\"hidden\" name=\"enabled\" value=\"{action}\">\
+             <button type=\"submit\">{label}</button></form></td></tr>\n",
+            category = c.category,
+        ));
+    }
+
+    let html = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+  <title>Bancuh DNS - Categories</title>
+  <style>
+    body {{ font-family: sans-serif; margin: 20px; }}
+    table, th, td {{ border: 1px solid #ccc; border-collapse: collapse; }}
+    th, td {{ padding: 8px 12px; text-align: left; }}
+    th {{ background: #f5f5f5; }}
+  </style>
+</head>
+<body>
+  <h2>Bancuh DNS - Categories</h2>
+  <table>
+    <tr><th>Category</th><th>Status</th><th>Sources</th><th></th></tr>
+    {rows}
+  </table>
+</body>
+</html>"#
+    );
+
+    Html(html)
+}
+
+async fn get_updates_api(State(state): State<AdminState>) -> Json<UpdateStatus> {
+    Json(state.engine.update_status())
+}
+
+/// The update loop runs the update, so it also restarts the update interval
+async fn trigger_update_api(State(state): State<AdminState>) -> (StatusCode, Json<UpdateStatus>) {
+    tracing::info!("update requested from admin");
+    state.update.notify_one();
+    (StatusCode::ACCEPTED, Json(state.engine.update_status()))
+}
+
+async fn trigger_update_form(State(state): State<AdminState>) -> Redirect {
+    tracing::info!("update requested from admin");
+    state.update.notify_one();
+    Redirect::to("/updates")
+}
+
+async fn rollback(engine: &AdblockEngine) -> Result<(), (StatusCode, String)> {
+    engine.rollback().await.map_err(|err| match err {
+        EngineError::NoPreviousDb | EngineError::UpdateRunning => {
+            (StatusCode::CONFLICT, err.to_string())
+        }
+        err => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+    })
+}
+
+async fn rollback_api(
+    State(state): State<AdminState>,
+) -> Result<Json<UpdateStatus>, (StatusCode, String)> {
+    rollback(&state.engine).await?;
+    Ok(Json(state.engine.update_status()))
+}
+
+async fn rollback_form(State(state): State<AdminState>) -> Result<Redirect, (StatusCode, String)> {
+    rollback(&state.engine).await?;
+    Ok(Redirect::to("/updates"))
+}
+
+async fn get_updates_html(State(state): State<AdminState>) -> Html<String> {
+    let status = state.engine.update_status();
+
+    let mut rows = String::new();
+    for r in &status.history {
+        let result = match &r.error {
+            Some(err) => format!("failed: {}", html_escape(err)),
+            None => "ok".to_string(),
+        };
+        let entries = r
+            .entries
+            .iter()
+            .map(|(store, n)| format!("{store}: {n}"))
+            .collect::<Vec<_>>()
+            .join("<br>");
+        let sources = r
+            .sources
+            .iter()
+            .map(|s| {
+                format!(
+                    "{} ({}, {} entries)",
+                    html_escape(&s.name),
+                    s.kind,
+                    s.entries
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("<br>");
+
+        rows.push_str(&format!(
+            "<tr><td>{started}</td><td>{finished}</td><td>{kind:?}</td><td>{result}</td>\
+             <td>{entries}</td><td>{sources}</td></tr>\n",
+            started = r.started_at.format("%Y-%m-%d %H:%M:%S"),
+            finished = r.finished_at.format("%Y-%m-%d %H:%M:%S"),
+            kind = r.kind,
+        ));
+    }
+
+    let running = if status.running {
+        "An update is running."
+    } else {
+        "No update is running."
+    };
+    let rollback = if status.rollback_available {
+        r#"<form method="post" action="/updates/rollback">
+    <button type="submit">Roll back to the previous db</button></form>"#
+    } else {
+        "<p>There is no previous db to roll back to.</p>"
+    };
+
+    let html = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+  <title>Bancuh DNS - Updates</title>
+  <style>
+    body {{ font-family: sans-serif; margin: 20px; }}
+    table, th, td {{ border: 1px solid #ccc; border-collapse: collapse; }}
+    th, td {{ padding: 8px 12px; text-align: left; vertical-align: top; }}
+    th {{ background: #f5f5f5; }}
+    form {{ margin-bottom: 12px; }}
+  </style>
+</head>
+<body>
+  <h2>Bancuh DNS - Updates</h2>
+  <p>{running}</p>
+  <form method="post" action="/updates"><button type="submit">Update now</button></form>
+  {rollback}
+  <table>
+    <tr><th>Started (UTC)</th><th>Finished (UTC)</th><th>Kind</th><th>Result</th><th>Entries</th>
+      <th>Sources</th></tr>
+    {rows}
+  </table>
+</body>
+</html>"#
+    );
+
+    Html(html)
+}
+
+/// The process is up and serving HTTP
+async fn get_healthz() -> &'static str {
+    "ok"
+}
+
+async fn get_readyz(State(state): State<AdminState>) -> (StatusCode, Json<Readiness>) {
+    let readiness = state.health.readiness().await;
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(readiness))
+}
+
+/// The variants of an export, each cached on its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct ExportKey {
+    format: ExportFormat,
+    gzip: bool,
+}
+
+/// The last rendered export of each variant, with the serial of the policy it shows. Its lock
+/// is held while one is rendered, so the db is dumped by one request at a time, and the requests
+/// waiting for the same export are served the result.
+#[derive(Debug, Default)]
+pub struct ExportCache(tokio::sync::Mutex<HashMap<ExportKey, (u32, Bytes)>>);
+
+fn render_export(
+    db: &AdblockDB,
+    options: &ExportOptions,
+    gzip: bool,
+) -> Result<Vec<u8>, ExportError> {
+    let mut out = Vec::new();
+    if gzip {
+        let mut gz = GzEncoder::new(out, Compression::default());
+        export(db, options, &mut gz)?;
+        out = gz.finish()?;
+    } else {
+        export(db, options, &mut out)?;
+    }
+    Ok(out)
+}
+
+/// Whether the client takes gzip, ignoring any preference between encodings
+fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|encoding| {
+            let mut params = encoding.split(';').map(str::trim);
+            params.next() == Some("gzip") && !params.any(|p| p == "q=0" || p == "q=0.0")
+        })
+}
+
+/// The effective policy of the current db as a list in `format`
+async fn get_export(
+    Path(format): Path<ExportFormat>,
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+) -> Response {
+    let key = ExportKey {
+        format,
+        gzip: accepts_gzip(&headers),
+    };
+    let serial = state.engine.serial();
+
+    let mut cached = state.exports.0.lock().await;
+    let body = match cached.get(&key).filter(|(cached, _)| *cached == serial) {
+        Some((_, body)) => body.clone(),
+        None => {
+            let options = ExportOptions {
+                format,
+                zone: state.rpz_zone.clone(),
+                serial,
+                disabled: HashSet::clone(&state.engine.disabled_categories()),
+            };
+            let db = state.engine.db();
+            let gzip = key.gzip;
+            let rendered =
+                tokio::task::spawn_blocking(move || render_export(&db, &options, gzip)).await;
+            match rendered {
+                Ok(Ok(body)) => {
+                    let body = Bytes::from(body);
+                    cached.insert(key, (serial, body.clone()));
+                    body
+                }
+                Ok(Err(err)) => {
+                    tracing::warn!("Could not export the db: {err}");
+                    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                }
+                Err(err) => {
+                    tracing::error!("Export task failed: {err}");
+                    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                }
+            }
+        }
+    };
+    drop(cached);
+
+    let mut response = Response::new(Body::from(body));
+    let headers = response.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static(format.content_type()),
+    );
+    if let Ok(disposition) =
+        header::HeaderValue::from_str(&format!("attachment; filename=\"{}\"", format.file_name()))
+    {
+        headers.insert(header::CONTENT_DISPOSITION, disposition);
+    }
+    headers.insert(
+        header::VARY,
+        header::HeaderValue::from_static("accept-encoding"),
+    );
+    if key.gzip {
+        headers.insert(
+            header::CONTENT_ENCODING,
+            header::HeaderValue::from_static("gzip"),
+        );
+    }
+    response
+}
+
+async fn get_metrics(State(state): State<AdminState>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(&state.engine),
+    )
+}
+
+async fn get_stats_api(
+    Query(input): Query<StatsInput>,
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+) -> Json<StatsReport> {
+    Json(input.report(&state, &headers))
+}
+
+fn top_table(title: &str, entries: &[TopEntry]) -> String {
+    let mut rows = String::new();
+    for e in entries {
+        rows.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td></tr>\n",
+            html_escape(&e.name),
+            e.count
+        ));
+    }
+
+    format!(
+        "<div class=\"top\"><h3>{title}</h3><table>\
+         <tr><th>Name</th><th>Queries</th></tr>\n{rows}</table></div>"
+    )
+}
+
+async fn get_stats_html(
+    Query(input): Query<StatsInput>,
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+) -> Html<String> {
+    let operator = state.is_operator(&headers);
+    let report = input.report(&state, &headers);
+
+    let periods = Period::ALL
+        .iter()
+        .map(|p| {
+            if *p == report.period {
+                format!("<strong>{p}</strong>")
+            } else {
+                format!("<a href=\"/stats?period={p}\">{p}</a>")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" | ");
+
+    let time_format = match report.period {
+        Period::Minute => "%H:%M",
+        Period::Hour => "%m-%d %H:00",
+        Period::Day => "%Y-%m-%d",
+    };
+    let max = report
+        .buckets
+        .iter()
+        .map(|b| b.queries)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let mut rows = String::new();
+    for b in report.buckets.iter().rev() {
+        rows.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{:.1}%</td>\
+             <td><div class=\"bar\" style=\"width: {}px\">\
+             <div class=\"blocked\" style=\"width: {:.0}%\"></div></div></td></tr>\n",
+            b.start.format(time_format),
+            b.queries,
+            b.blocked,
+            b.blocked_percent,
+            b.queries * 300 / max,
+            b.blocked_percent,
+        ));
+    }
+
+    let html = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+  <title>Bancuh DNS - Statistics</title>
+  <style>
+    body {{ font-family: sans-serif; margin: 20px; }}
+    table, th, td {{ border: 1px solid #ccc; border-collapse: collapse; }}
+    th, td {{ padding: 4px 12px; text-align: left; }}
+    th {{ background: #f5f5f5; }}
+    .top {{ display: inline-block; vertical-align: top; margin-right: 20px; }}
+    .bar {{ height: 12px; background: #8bc34a; }}
+    .blocked {{ height: 12px; background: #e57373; }}
+  </style>
+</head>
+<body>
+  <h2>Bancuh DNS - Statistics</h2>
+  <p>Per {periods}</p>
+  <p>{queries} queries, {blocked} blocked ({blocked_percent:.1}%)</p>
+  {top_domains}
+  {top_blocked}
+  {top_clients}
+  <h3>Over time</h3>
+  <table>
+    <tr><th>Time (UTC)</th><th>Queries</th><th>Blocked</th><th>Blocked %</th><th></th></tr>
+    {rows}
+  </table>
+</body>
+</html>"#,
+        queries = report.queries,
+        blocked = report.blocked,
+        blocked_percent = report.blocked_percent,
+        top_domains = top_table("Top domains", &report.top_domains),
+        top_blocked = top_table("Top blocked", &report.top_blocked),
+        top_clients = if operator {
+            top_table("Top clients", &report.top_clients)
+        } else {
+            String::new()
+        },
+    );
+
+    Html(html)
+}
+
+/// Reject requests without valid operator credentials, prompting browsers for basic auth.
+/// Without `ADMIN_TOKEN_SHA256` nobody is an operator, and every request is forbidden.
+async fn require_operator(
+    State(state): State<AdminState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match &state.auth {
+        Some(auth) if auth.check(request.headers()) => next.run(request).await,
+        Some(_) => (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Basic realm=\"bancuh-dns\"")],
+        )
+            .into_response(),
+        None => (
+            StatusCode::FORBIDDEN,
+            "Operator auth is not configured, set ADMIN_TOKEN_SHA256 to enable this",
+        )
+            .into_response(),
+    }
+}
+
+/// Whether a request was sent by a page of this server, or by something other than a browser.
+/// Browsers send an `Origin`, or at least a `Referer`, with their POSTs, which must then
+/// name the host the request went to.
+fn is_same_origin(headers: &HeaderMap) -> bool {
+    let Some(source) = headers
+        .get(header::ORIGIN)
+        .or_else(|| headers.get(header::REFERER))
+    else {
+        return true;
+    };
+    let host = headers.get(header::HOST).and_then(|h| h.to_str().ok());
+    let source = source
+        .to_str()
+        .ok()
+        .and_then(|s| s.parse::<Uri>().ok())
+        .and_then(|uri| uri.authority().map(|a| a.to_string()));
+
+    host.is_some_and(|host| source.is_some_and(|source| source.eq_ignore_ascii_case(host)))
+}
+
+/// Refuse cross-site requests that change something, as browsers resend basic auth
+/// credentials on their own and any page could otherwise submit the forms
+async fn require_same_origin(request: Request, next: Next) -> Response {
+    let safe = matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    );
+    if safe || is_same_origin(request.headers()) {
+        next.run(request).await
+    } else {
+        (StatusCode::FORBIDDEN, "Cross-origin request refused").into_response()
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct SearchInput {
+    #[serde(flatten)]
+    filter: LogFilter,
+    #[serde(default, deserialize_with = "non_empty")]
+    limit: Option<usize>,
+}
+
+impl SearchInput {
+    /// Search on the blocking pool, as it may read the on-disk log
+    async fn search(&self, query_log: &Arc<QueryLogStore>) -> Vec<ClientQueryLog> {
+        let limit = self.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+        let query_log = query_log.clone();
+        let filter = self.filter.clone();
+
+        tokio::task::spawn_blocking(move || query_log.search(&filter, limit))
+            .await
+            .unwrap_or_default()
+    }
+}
+
+#[derive(serde::Serialize)]
+struct SearchApiOutput {
+    count: usize,
+    queries: Vec<ClientQueryLog>,
+}
+
+async fn search_logs_api(
+    Query(input): Query<SearchInput>,
+    State(state): State<AdminState>,
+) -> Json<SearchApiOutput> {
+    let queries = input.search(&state.query_log).await;
+
+    Json(SearchApiOutput {
+        count: queries.len(),
+        queries,
+    })
+}
+
+async fn search_logs_html(
+    Query(input): Query<SearchInput>,
+    State(state): State<AdminState>,
+) -> Html<String> {
+    let queries = input.search(&state.query_log).await;
+    let filter = &input.filter;
+
+    let mut rows = String::new();
+    for q in &queries {
+        rows.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td>{}</tr>\n",
+            q.log.query_time.format("%Y-%m-%d %H:%M:%S"),
+            html_escape(&q.client),
+            log_cells(&q.log),
+        ));
+    }
+
+    let outcomes = Outcome::ALL
+        .iter()
+        .map(|o| {
+            let selected = if filter.outcome == Some(*o) {
+                " selected"
+            } else {
+                ""
+            };
+            format!("<option{selected}>{}</option>", o.as_str())
+        })
+        .collect::<String>();
+    let transports = TRANSPORTS
+        .iter()
+        .map(|t| {
+            let selected = if filter.transport.as_deref() == Some(*t) {
+                " selected"
+            } else {
+                ""
+            };
+            format!("<option{selected}>{t}</option>")
+        })
+        .collect::<String>();
+    let value = |v: Option<String>| html_escape(&v.unwrap_or_default());
+    let time = |t: Option<chrono::DateTime<chrono::Utc>>| {
+        t.map(|t| t.format("%Y-%m-%dT%H:%M").to_string())
+    };
+
+    let html = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+  <title>Bancuh DNS - All Query Logs</title>
+  <style>
+    body {{ font-family: sans-serif; margin: 20px; }}
+    table, th, td {{ border: 1px solid #ccc; border-collapse: collapse; }}
+    th, td {{ padding: 8px 12px; text-align: left; }}
+    th {{ background: #f5f5f5; }}
+    form input, form select {{ margin-right: 12px; }}
+  </style>
+</head>
+<body>
+  <h2>Bancuh DNS - All Query Logs</h2>
+  <form method="get" action="/admin/logs">
+    Client <input name="client" value="{client}">
+    Domain <input name="domain" value="{domain}">
+    Outcome <select name="outcome"><option value="">any</option>{outcomes}</select>
+    RCode <input name="rcode" size="8" value="{rcode}">
+    Transport <select name="transport"><option value="">any</option>{transports}</select>
+    ID <input name="query_id" size="5" value="{query_id}">
+    Upstream <input name="upstream" value="{upstream}">
+    Min latency (ms) <input name="min_latency_ms" size="5" value="{min_latency_ms}">
+    From <input type="datetime-local" name="from" value="{from}">
+    To <input type="datetime-local" name="to" value="{to}">
+    Limit <input name="limit" size="5" value="{limit}">
+    <button type="submit">Search</button>
+  </form>
+  <p>Showing {count} queries (times in UTC)</p>
+  <table>
+    <tr><th>Timestamp</th><th>Client</th>{LOG_HEADERS}</tr>
+    {rows}
+  </table>
+</body>
+</html>"#,
+        client = value(filter.client.clone()),
+        domain = value(filter.domain.clone()),
+        rcode = value(filter.rcode.clone()),
+        query_id = value(filter.query_id.map(|id| id.to_string())),
+        upstream = value(filter.upstream.clone()),
+        min_latency_ms = value(filter.min_latency_ms.map(|ms| ms.to_string())),
+        from = value(time(filter.from)),
+        to = value(time(filter.to)),
+        limit = value(input.limit.map(|l| l.to_string())),
+        count = queries.len(),
+    );
+
+    Html(html)
+}
+
+/// Queries of all clients as server-sent events while they happen, `lagged` events tell how many
+/// a slow reader missed
+async fn live_logs_api(
+    Query(filter): Query<LogFilter>,
+    State(state): State<AdminState>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let filter = state.query_log.client_filter(&filter);
+    let receiver = state.query_log.subscribe();
+
+    let events = futures::stream::unfold(receiver, move |mut receiver| {
+        let filter = filter.clone();
+        async move {
+            loop {
+                let event = match receiver.recv().await {
+                    Ok(entry) if filter.matches(&entry.client, &entry.log) => {
+                        Event::default().json_data(&entry).ok()?
+                    }
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(missed)) => {
+                        Event::default().event("lagged").data(missed.to_string())
+                    }
+                    Err(RecvError::Closed) => return None,
+                };
+                return Some((Ok(event), receiver));
+            }
+        }
+    })
+    .take_until(state.shutdown.cancelled_owned());
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+async fn live_logs_html(Query(filter): Query<LogFilter>) -> Html<String> {
+    let outcomes = Outcome::ALL
+        .iter()
+        .map(|o| {
+            let selected = if filter.outcome == Some(*o) {
+                " selected"
+            } else {
+                ""
+            };
+            format!("<option{selected}>{}</option>", o.as_str())
+        })
+        .collect::<String>();
+    let value = |v: Option<String>| html_escape(&v.unwrap_or_default());
+
+    let html = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+  <title>Bancuh DNS - Live Queries</title>
+  <style>
+    body {{ font-family: sans-serif; margin: 20px; }}
+    table, th, td {{ border: 1px solid #ccc; border-collapse: collapse; }}
+    th, td {{ padding: 8px 12px; text-align: left; }}
+    th {{ background: #f5f5f5; }}
+    form input, form select {{ margin-right: 12px; }}
+  </style>
+</head>
+<body>
+  <h2>Bancuh DNS - Live Queries</h2>
+  <form method="get" action="/admin/live">
+    Client <input name="client" value="{client}">
+    Domain <input name="domain" value="{domain}">
+    Outcome <select name="outcome"><option value="">any</option>{outcomes}</select>
+    <button type="submit">Watch</button>
+  </form>
+  <p id="status">Connecting...</p>
+  <table>
+    <thead><tr><th>Timestamp</th><th>Client</th><th>Query</th><th>Answer</th><th>Outcome</th>
+      <th>RCode</th><th>Latency (ms)</th><th>Transport</th></tr></thead>
+    <tbody id="rows"></tbody>
+  </table>
+  <script>
+    const MAX_ROWS = 500;
+    const rows = document.getElementById("rows");
+    const status = document.getElementById("status");
+    const source = new EventSource("/api/admin/live" + window.location.search);
+    source.onopen = () => {{ status.textContent = "Watching"; }};
+    source.onerror = () => {{ status.textContent = "Disconnected, retrying..."; }};
+    source.addEventListener("lagged", (e) => {{
+      status.textContent = "Watching, skipped " + e.data + " queries to keep up";
+    }});
+    source.onmessage = (e) => {{
+      const q = JSON.parse(e.data);
+      const row = document.createElement("tr");
+      for (const cell of [q.query_time, q.client, q.question, q.answer, q.outcome,
+                          q.rcode ?? "dropped", q.latency_ms.toFixed(1), q.transport]) {{
+        const td = document.createElement("td");
+        td.textContent = cell;
+        row.appendChild(td);
+      }}
+      rows.prepend(row);
+      while (rows.children.length > MAX_ROWS) rows.lastChild.remove();
+    }};
+  </script>
+</body>
+</html>"#,
+        client = value(filter.client.clone()),
+        domain = value(filter.domain.clone()),
+    );
+
+    Html(html)
+}
+
+const LOG_HEADERS: &str = "<th>Query</th><th>Answer</th><th>Outcome</th><th>RCode</th>\
+    <th>Latency (ms)</th><th>Transport</th><th>ID</th><th>Upstream</th>";
+
+const TRANSPORTS: [&str; 4] = ["udp", "tcp", "tls", "https"];
+
+/// Table cells for `LOG_HEADERS`
+fn log_cells(log: &QueryLog) -> String {
+    format!(
+        "<td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{:.1}</td><td>{}</td><td>{}</td><td>{}</td>",
+        html_escape(&log.question),
+        html_escape(&log.answer),
+        log.outcome.as_str(),
+        log.rcode.as_deref().unwrap_or("dropped"),
+        log.latency_ms,
+        log.transport,
+        log.query_id,
+        html_escape(log.upstream.as_deref().unwrap_or_default()),
+    )
+}
+
+/// Escape for text and quoted attribute values alike
 fn html_escape(s: &str) -> String {
     s.replace('&', "&amp;")
         .replace('<', "&lt;")
         .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
 }
 
-fn make_app(query_log: Arc<QueryLogStore>) -> Router {
-    let state = AppState { query_log };
+fn make_app(state: AdminState) -> Router {
+    // with operator auth configured, the operator views are served too
+    let operator = match state.auth {
+        Some(_) => Router::new()
+            .route("/admin/logs", get(search_logs_html))
+            .route("/api/admin/logs", get(search_logs_api))
+            .route("/admin/live", get(live_logs_html))
+            .route("/api/admin/live", get(live_logs_api))
+            .route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                require_operator,
+            )),
+        None => Router::new(),
+    };
+
+    // running updates and changing the policy always need operator auth, and are refused
+    // while none is configured
+    let controls = Router::new()
+        .route("/updates", post(trigger_update_form))
+        .route("/api/updates", post(trigger_update_api))
+        .route("/updates/rollback", post(rollback_form))
+        .route("/api/updates/rollback", post(rollback_api))
+        .route("/categories/{category}", post(set_category_form))
+        .route("/api/categories/{category}", post(set_category_api))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            require_operator,
+        ));
+
     Router::new()
         .route("/logs", get(get_logs_html))
         .route("/api/logs", get(get_logs_api))
+        .route("/logs/opt-out", post(set_opt_out_form))
+        .route("/api/logs/opt-out", post(set_opt_out_api))
+        .route("/categories", get(get_categories_html))
+        .route("/api/categories", get(get_categories_api))
+        .route("/updates", get(get_updates_html))
+        .route("/api/updates", get(get_updates_api))
+        .route("/api/export/{format}", get(get_export))
+        .route("/metrics", get(get_metrics))
+        .route("/healthz", get(get_healthz))
+        .route("/readyz", get(get_readyz))
+        .route("/stats", get(get_stats_html))
+        .route("/api/stats", get(get_stats_api))
+        .merge(operator)
+        .merge(controls)
+        .layer(middleware::from_fn(require_same_origin))
         .with_state(state)
 }
 
 pub async fn serve(
     port: u16,
-    query_log: Arc<QueryLogStore>,
+    state: AdminState,
     tls_resolver: Option<Arc<ResolvesServerCertAcme>>,
     token: CancellationToken,
 ) {
-    let app = make_app(query_log);
+    let app = make_app(state);
 
     // HTTP on port (default 8080)
     let http_app = app.clone();
@@ -208,3 +1227,81 @@ pub async fn serve(
         let _ = h.await;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(authorization: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, authorization.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn it_checks_the_operator_token() {
+        // sha256 of "secret"
+        let auth = OperatorAuth::from_hex(
+            "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b",
+        )
+        .unwrap();
+
+        assert!(auth.check(&headers("Bearer secret")));
+        assert!(auth.check(&headers(&format!(
+            "Basic {}",
+            BASE64_STANDARD.encode("admin:secret")
+        ))));
+        assert!(!auth.check(&headers("Bearer wrong")));
+        assert!(!auth.check(&headers(&format!(
+            "Basic {}",
+            BASE64_STANDARD.encode("secret")
+        ))));
+        assert!(!auth.check(&HeaderMap::new()));
+
+        assert!(OperatorAuth::from_hex("abc").is_err());
+    }
+
+    #[test]
+    fn it_refuses_cross_origin_requests() {
+        let request = |pairs: &[(header::HeaderName, &str)]| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::HOST, "dns.lan:8080".parse().unwrap());
+            for (name, value) in pairs {
+                headers.insert(name, value.parse().unwrap());
+            }
+            headers
+        };
+
+        // clients other than browsers send neither
+        assert!(is_same_origin(&request(&[])));
+        assert!(is_same_origin(&request(&[(
+            header::ORIGIN,
+            "http://dns.lan:8080"
+        )])));
+        assert!(is_same_origin(&request(&[(
+            header::REFERER,
+            "http://dns.lan:8080/updates"
+        )])));
+        assert!(!is_same_origin(&request(&[(
+            header::ORIGIN,
+            "https://evil.example"
+        )])));
+        assert!(!is_same_origin(&request(&[(header::ORIGIN, "null")])));
+        // the origin is checked before the referer
+        assert!(!is_same_origin(&request(&[
+            (header::ORIGIN, "http://dns.lan:8081"),
+            (header::REFERER, "http://dns.lan:8080/updates"),
+        ])));
+    }
+
+    #[test]
+    fn it_escapes_values_put_into_attributes() {
+        let client = html_escape("\" autofocus onfocus='alert(1)' x=\"<b>&");
+        let input = format!("<input name=\"client\" value=\"{client}\">");
+        assert_eq!(
+            input,
+            "<input name=\"client\" value=\"&quot; autofocus onfocus=&#39;alert(1)&#39; \
+             x=&quot;&lt;b&gt;&amp;\">"
+        );
+    }
+}
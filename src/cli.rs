@@ -0,0 +1,276 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    path::PathBuf,
+    time::Duration,
+};
+
+use itertools::Itertools;
+use thiserror::Error;
+
+use crate::{
+    compiler::SourceReport,
+    config::{Category, FileOrUrl},
+    db::{AdblockDB, DBError},
+    engine::{compile, EngineError, Lookup},
+    export::{export, ExportError, ExportFormat, ExportOptions, DEFAULT_RPZ_ZONE},
+    fetch::{FetchLimiter, Fetcher, SourceCache},
+    health::{self, HealthError},
+    Args, BIND_IP,
+};
+
+#[derive(Error, Debug)]
+pub enum CliError {
+    #[error(transparent)]
+    Engine(#[from] EngineError),
+
+    #[error(transparent)]
+    DB(#[from] DBError),
+
+    #[error(transparent)]
+    Health(#[from] HealthError),
+
+    #[error(transparent)]
+    Export(#[from] ExportError),
+
+    #[error("EmptySources: {0} sources have no entries, they may have failed to load")]
+    EmptySources(usize),
+
+    #[error("NoSnapshot: no db snapshot in {0}")]
+    NoSnapshot(PathBuf),
+}
+
+/// Offline tools, run instead of the server. They use the same settings, e.g. `DB_BACKEND`,
+/// `SOURCE_CACHE_DIR` and `DISABLED_CATEGORIES`.
+#[derive(clap::Subcommand, Clone, Debug)]
+pub enum Command {
+    /// Query the running server for a name it answers itself and exit non-zero unless it answers,
+    /// for container healthchecks
+    Healthcheck {
+        /// Seconds to wait for the answer
+        #[arg(long, default_value = "3")]
+        timeout: u64,
+    },
+
+    /// Fetch and compile all sources of a config and print the report of each. Fails when a
+    /// source ends up without entries
+    Validate {
+        config: FileOrUrl,
+
+        /// Accept sources without entries
+        #[arg(long)]
+        allow_empty: bool,
+    },
+
+    /// Compile a config into a db snapshot, usable as DB_SNAPSHOT_DIR
+    Compile {
+        config: FileOrUrl,
+
+        /// Directory to write the snapshot to
+        #[arg(long)]
+        out: PathBuf,
+    },
+
+    /// Print the verdict for a domain and the rules it matches, against a config (CONFIG_URL by
+    /// default) or a compiled db
+    Check {
+        domain: String,
+
+        #[arg(long, conflicts_with = "db")]
+        config: Option<FileOrUrl>,
+
+        /// Directory of a db snapshot, as written by `compile`
+        #[arg(long)]
+        db: Option<PathBuf>,
+    },
+
+    /// List the names whose verdict differs between two configs
+    Diff { before: FileOrUrl, after: FileOrUrl },
+
+    /// Write the effective policy of a config (CONFIG_URL by default) or a compiled db to
+    /// stdout as a list for other resolvers and blockers
+    Export {
+        #[arg(value_enum)]
+        format: ExportFormat,
+
+        #[arg(long, conflicts_with = "db")]
+        config: Option<FileOrUrl>,
+
+        /// Directory of a db snapshot, as written by `compile`
+        #[arg(long)]
+        db: Option<PathBuf>,
+
+        /// Origin of the RPZ zone
+        #[arg(long, default_value = DEFAULT_RPZ_ZONE)]
+        zone: String,
+    },
+}
+
+fn fetcher(args: &Args) -> Fetcher {
+    Fetcher::new(
+        SourceCache::new(args.source_cache_dir.clone()),
+        FetchLimiter::new(args.fetch_concurrency, args.fetch_per_host),
+    )
+}
+
+async fn compile_config(
+    args: &Args,
+    config_urls: &[FileOrUrl],
+) -> Result<(AdblockDB, Vec<SourceReport>), CliError> {
+    let (db, sources, _) = compile(config_urls, &fetcher(args), args.db_backend).await?;
+    Ok((db, sources))
+}
+
+fn print_report(sources: &[SourceReport]) {
+    for s in sources {
+        let category = s.category.map(|c| c.to_string()).unwrap_or_default();
+        println!(
+            "{:<10} {:>9} {:<10} {}",
+            s.kind, s.entries, category, s.name
+        );
+    }
+}
+
+fn disabled(args: &Args) -> HashSet<Category> {
+    args.disabled_categories.iter().copied().collect()
+}
+
+async fn validate(args: &Args, config: FileOrUrl, allow_empty: bool) -> Result<(), CliError> {
+    let (_, sources) = compile_config(args, &[config]).await?;
+    print_report(&sources);
+
+    let empty = sources.iter().filter(|s| s.entries == 0).count();
+    if empty > 0 && !allow_empty {
+        return Err(CliError::EmptySources(empty));
+    }
+    Ok(())
+}
+
+async fn compile_to(args: &Args, config: FileOrUrl, out: PathBuf) -> Result<(), CliError> {
+    let (db, sources) = compile_config(args, &[config]).await?;
+    print_report(&sources);
+
+    std::fs::create_dir_all(&out).map_err(DBError::from)?;
+    db.save_snapshot(&out)?;
+    println!("Saved db snapshot to {}", out.display());
+    Ok(())
+}
+
+/// A db snapshot when given, else the compiled config, CONFIG_URL by default
+async fn load_db(
+    args: &Args,
+    config: Option<FileOrUrl>,
+    db: Option<PathBuf>,
+) -> Result<AdblockDB, CliError> {
+    Ok(match (db, config) {
+        (Some(dir), _) => {
+            AdblockDB::open_snapshot(args.db_backend, &dir)?.ok_or(CliError::NoSnapshot(dir))?
+        }
+        (None, Some(config)) => compile_config(args, &[config]).await?.0,
+        (None, None) => compile_config(args, &args.config_url).await?.0,
+    })
+}
+
+async fn check(
+    args: &Args,
+    domain: String,
+    config: Option<FileOrUrl>,
+    db: Option<PathBuf>,
+) -> Result<(), CliError> {
+    let db = load_db(args, config, db).await?;
+
+    let name = format!("{}.", domain.trim_end_matches('.'));
+    let lookup = Lookup::new(&db, &name, &disabled(args))?;
+    println!("{name} {}", lookup.verdict());
+    if let Some(rule) = &lookup.block {
+        println!("  block {} ({})", rule.key, rule.tags.iter().join(", "));
+    }
+    if let Some(rule) = &lookup.allow {
+        println!("  allow {} ({})", rule.key, rule.tags.iter().join(", "));
+    }
+    if let Some(alias) = &lookup.rewrite {
+        println!("  rewrite to {alias}");
+    }
+    Ok(())
+}
+
+/// Names stored in any of the stores, wildcards included
+fn stored_names(db: &AdblockDB, names: &mut BTreeSet<String>) -> Result<(), DBError> {
+    for store in [&db.blacklist, &db.whitelist, &db.rewrites] {
+        for entry in store.iter() {
+            names.insert(entry?.0);
+        }
+    }
+    Ok(())
+}
+
+/// Only stored names can change verdict. A wildcard is looked up as itself, standing for the
+/// names below it that have no rule of their own.
+async fn diff(args: &Args, before: FileOrUrl, after: FileOrUrl) -> Result<(), CliError> {
+    let (before, _) = compile_config(args, &[before]).await?;
+    let (after, _) = compile_config(args, &[after]).await?;
+
+    let mut names = BTreeSet::new();
+    stored_names(&before, &mut names)?;
+    stored_names(&after, &mut names)?;
+
+    let disabled = disabled(args);
+    let mut changed = 0;
+    for name in &names {
+        let was = Lookup::new(&before, name, &disabled)?.verdict();
+        let is = Lookup::new(&after, name, &disabled)?.verdict();
+        if was != is {
+            println!("{name} {was} -> {is}");
+            changed += 1;
+        }
+    }
+    eprintln!("{changed} of {} names changed", names.len());
+    Ok(())
+}
+
+async fn export_to_stdout(
+    args: &Args,
+    format: ExportFormat,
+    config: Option<FileOrUrl>,
+    db: Option<PathBuf>,
+    zone: String,
+) -> Result<(), CliError> {
+    let db = load_db(args, config, db).await?;
+    let options = ExportOptions {
+        format,
+        zone,
+        serial: chrono::Utc::now().timestamp() as u32,
+        disabled: disabled(args),
+    };
+
+    let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+    let (written, skipped) = export(&db, &options, &mut out)?;
+    eprintln!("{written} rules written, {skipped} left out");
+    Ok(())
+}
+
+async fn healthcheck(args: &Args, timeout: u64) -> Result<(), CliError> {
+    let ip = args.listen_addrs.first().copied().unwrap_or(BIND_IP);
+    let addr = health::local_addr(ip, args.port);
+    health::self_query(addr, Duration::from_secs(timeout)).await?;
+    println!("ok");
+    Ok(())
+}
+
+pub async fn run(command: Command, args: &Args) -> Result<(), CliError> {
+    match command {
+        Command::Healthcheck { timeout } => healthcheck(args, timeout).await,
+        Command::Validate {
+            config,
+            allow_empty,
+        } => validate(args, config, allow_empty).await,
+        Command::Compile { config, out } => compile_to(args, config, out).await,
+        Command::Check { domain, config, db } => check(args, domain, config, db).await,
+        Command::Diff { before, after } => diff(args, before, after).await,
+        Command::Export {
+            format,
+            config,
+            db,
+            zone,
+        } => export_to_stdout(args, format, config, db, zone).await,
+    }
+}
@@ -1,6 +1,12 @@
-use crate::config::{BlacklistFormat, BlacklistSource, FileOrUrl};
+use crate::{
+    config::{BlacklistFormat, BlacklistSource, FileOrUrl, Integrity, SourceMeta},
+    fetch::Fetcher,
+};
 
-use super::parser::{Domain, Host};
+use super::{
+    loader::load_source,
+    parser::{Domain, Host},
+};
 
 #[derive(Debug, Clone)]
 pub enum ParseBlacklist {
@@ -29,27 +35,22 @@ impl From<&BlacklistFormat> for ParseBlacklist {
 #[derive(Debug)]
 pub struct BlacklistCompiler {
     pub(crate) source: FileOrUrl,
+    pub(crate) integrity: Integrity,
+    pub(crate) meta: SourceMeta,
     pub(crate) parser: ParseBlacklist,
 }
 
 impl BlacklistCompiler {
-    pub async fn load_blacklist(&self) -> Vec<Domain> {
-        let source = match self.source.to_fetch().fetch().await {
-            Ok(s) => s,
-            Err(err) => {
-                println!("Could not fetch from {:?}", &self.source);
-                println!("{}", err);
-                println!("Skipping");
-                return Vec::new();
-            }
-        };
-
+    pub async fn load_blacklist(&self, fetcher: &Fetcher) -> Vec<Domain> {
         let parser = self.parser.clone();
-        tokio::task::spawn_blocking(move || {
-            source.lines().filter_map(|l| parser.parse(l)).collect()
-        })
+        load_source(
+            fetcher,
+            &self.source,
+            &self.integrity,
+            self.meta.update_interval,
+            move |l| parser.parse(l),
+        )
         .await
-        .unwrap_or_default()
     }
 }
 
@@ -57,6 +58,8 @@ impl From<&BlacklistSource> for BlacklistCompiler {
     fn from(bl: &BlacklistSource) -> Self {
         Self {
             source: bl.file_or_url.clone(),
+            integrity: bl.integrity.clone(),
+            meta: bl.meta.clone(),
             parser: ParseBlacklist::from(&bl.format),
         }
     }
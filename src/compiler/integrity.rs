@@ -0,0 +1,183 @@
+use minisign_verify::{PublicKey, Signature};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::{
+    config::Integrity,
+    fetch::{FetchError, Fetcher},
+};
+
+#[derive(Error, Debug)]
+pub enum IntegrityError {
+    #[error("Sha256Mismatch: expected {expected}, got {actual}")]
+    Sha256Mismatch { expected: String, actual: String },
+
+    #[error("SignatureFetch: {0}")]
+    SignatureFetch(FetchError),
+
+    #[error("InvalidSignature: {0}")]
+    InvalidSignature(#[from] minisign_verify::Error),
+
+    #[error("TooFewEntries: {entries} < min_entries {min_entries}")]
+    TooFewEntries { entries: usize, min_entries: usize },
+
+    #[error("Shrunk: {entries} entries, down from {previous} (max_shrink {max_shrink}%)")]
+    Shrunk {
+        entries: usize,
+        previous: usize,
+        max_shrink: f64,
+    },
+
+    #[error("Fetch: {0}")]
+    Fetch(#[from] FetchError),
+}
+
+fn check_sha256(expected: &str, raw: &[u8]) -> Result<(), IntegrityError> {
+    let actual = format!("{:x}", Sha256::digest(raw));
+    if actual != expected {
+        return Err(IntegrityError::Sha256Mismatch {
+            expected: expected.to_string(),
+            actual,
+        });
+    }
+
+    Ok(())
+}
+
+fn check_signature(public_key: &str, signature: &str, raw: &[u8]) -> Result<(), IntegrityError> {
+    let public_key = PublicKey::from_base64(public_key)?;
+    let signature = Signature::decode(signature)?;
+    public_key.verify(raw, &signature, true)?;
+
+    Ok(())
+}
+
+/// Check the published file against its sha256 pin and minisign signature. The signature
+/// is fetched like a list, through the fetcher's limits and cache.
+pub async fn verify_raw(
+    fetcher: &Fetcher,
+    integrity: &Integrity,
+    raw: &[u8],
+) -> Result<(), IntegrityError> {
+    if let Some(expected) = &integrity.sha256 {
+        check_sha256(expected, raw)?;
+    }
+
+    if let (Some(signature), Some(public_key)) = (&integrity.signature, &integrity.public_key) {
+        let fetch = signature.to_fetch();
+        let fetched = fetcher
+            .fetch(&fetch, None)
+            .await
+            .map_err(IntegrityError::SignatureFetch)?;
+        let text = fetch
+            .decode(fetched.raw.clone())
+            .await
+            .map_err(IntegrityError::SignatureFetch)?;
+        check_signature(public_key, &text, raw)?;
+        fetcher.commit(&fetch, fetched, 0).await;
+    }
+
+    Ok(())
+}
+
+/// Sanity check the number of parsed entries, against the minimum and the last compile
+pub fn check_entries(
+    integrity: &Integrity,
+    entries: usize,
+    previous: Option<usize>,
+) -> Result<(), IntegrityError> {
+    if let Some(min_entries) = integrity.min_entries {
+        if entries < min_entries {
+            return Err(IntegrityError::TooFewEntries {
+                entries,
+                min_entries,
+            });
+        }
+    }
+
+    if let (Some(max_shrink), Some(previous)) = (integrity.max_shrink, previous) {
+        let floor = previous as f64 * (1.0 - max_shrink / 100.0);
+        if (entries as f64) < floor {
+            return Err(IntegrityError::Shrunk {
+                entries,
+                previous,
+                max_shrink,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// The baseline for the next `max_shrink` check after a shrunk list is rejected. It moves
+/// halfway to the rejected count, so a list that really shrank is accepted after a few
+/// updates, while a one-off truncated download is still rejected.
+pub fn lowered_baseline(previous: usize, rejected: usize) -> usize {
+    (previous + rejected) / 2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUBLIC_KEY: &str = "RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3";
+    const SIGNATURE: &str = "untrusted comment: signature from minisign secret key
+RWQf6LRCGA9i59SLOFxz6NxvASXDJeRtuZykwQepbDEGt87ig1BNpWaVWuNrm73YiIiJbq71Wi+dP9eKL8OC351vwIasSSbXxwA=
+trusted comment: timestamp:1555779966\tfile:test
+QtKMXWyYcwdpZAlPF7tE2ENJkRd1ujvKjlj1m9RtHTBnZPa5WKU5uWRs5GoP5M/VqE81QFuMKI5k/SfNQUaOAA==";
+
+    #[test]
+    fn it_checks_sha256_and_signature() {
+        let sha256 = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+        assert!(check_sha256(sha256, b"test").is_ok());
+        assert!(matches!(
+            check_sha256(sha256, b"Test"),
+            Err(IntegrityError::Sha256Mismatch { .. })
+        ));
+
+        assert!(check_signature(PUBLIC_KEY, SIGNATURE, b"test").is_ok());
+        assert!(check_signature(PUBLIC_KEY, SIGNATURE, b"Test").is_err());
+    }
+
+    #[test]
+    fn it_checks_entries() {
+        let integrity = Integrity {
+            min_entries: Some(100),
+            max_shrink: Some(20.0),
+            ..Default::default()
+        };
+
+        assert!(check_entries(&integrity, 100, None).is_ok());
+        assert!(check_entries(&integrity, 900, Some(1000)).is_ok());
+        assert!(check_entries(&integrity, 800, Some(1000)).is_ok());
+        assert!(matches!(
+            check_entries(&integrity, 99, None),
+            Err(IntegrityError::TooFewEntries { .. })
+        ));
+        assert!(matches!(
+            check_entries(&integrity, 799, Some(1000)),
+            Err(IntegrityError::Shrunk { .. })
+        ));
+    }
+
+    #[test]
+    fn it_accepts_a_shrink_that_persists() {
+        for max_shrink in [0.0, 20.0] {
+            let integrity = Integrity {
+                max_shrink: Some(max_shrink),
+                ..Default::default()
+            };
+
+            let mut previous = 1000;
+            let mut rejections = 0;
+            while let Err(IntegrityError::Shrunk { entries, .. }) =
+                check_entries(&integrity, 500, Some(previous))
+            {
+                previous = lowered_baseline(previous, entries);
+                rejections += 1;
+                assert!(rejections < 16, "shrink never accepted");
+            }
+            assert!(rejections > 0);
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use std::time::Duration;
+
+use crate::{
+    config::{FileOrUrl, Integrity},
+    fetch::{Fetch, FetchError, Fetcher, Origin},
+};
+
+use super::integrity::{check_entries, lowered_baseline, verify_raw, IntegrityError};
+
+async fn parse_raw<T, F>(fetch: &Fetch, raw: Vec<u8>, parse: F) -> Result<Vec<T>, FetchError>
+where
+    T: Send + 'static,
+    F: Fn(&str) -> Option<T> + Send + 'static,
+{
+    let text = fetch.decode(raw).await?;
+    let entries =
+        tokio::task::spawn_blocking(move || text.lines().filter_map(parse).collect()).await?;
+
+    Ok(entries)
+}
+
+async fn parse_checked<T, F>(
+    fetcher: &Fetcher,
+    fetch: &Fetch,
+    raw: &[u8],
+    previous_entries: Option<usize>,
+    integrity: &Integrity,
+    parse: F,
+) -> Result<Vec<T>, IntegrityError>
+where
+    T: Send + 'static,
+    F: Fn(&str) -> Option<T> + Send + 'static,
+{
+    verify_raw(fetcher, integrity, raw).await?;
+    let entries = parse_raw(fetch, raw.to_vec(), parse).await?;
+    check_entries(integrity, entries.len(), previous_entries)?;
+
+    Ok(entries)
+}
+
+/// Fetch and parse a list source.
+///
+/// A fresh copy must pass the source's integrity checks before it is committed
+/// to the source cache; otherwise the last committed copy is used instead.
+pub(super) async fn load_source<T, F>(
+    fetcher: &Fetcher,
+    source: &FileOrUrl,
+    integrity: &Integrity,
+    update_interval: Option<Duration>,
+    parse: F,
+) -> Vec<T>
+where
+    T: Send + 'static,
+    F: Fn(&str) -> Option<T> + Clone + Send + 'static,
+{
+    let fetch = source.to_fetch();
+    let mut fetched = match fetcher.fetch(&fetch, update_interval).await {
+        Ok(fetched) => fetched,
+        Err(err) => {
+            tracing::warn!("Could not fetch from {source}: {err}. Skipping");
+            return Vec::new();
+        }
+    };
+
+    if fetched.origin == Origin::Fresh {
+        let checked = parse_checked(
+            fetcher,
+            &fetch,
+            &fetched.raw,
+            fetched.previous_entries,
+            integrity,
+            parse.clone(),
+        )
+        .await;
+
+        match checked {
+            Ok(entries) => {
+                fetcher.commit(&fetch, fetched, entries.len()).await;
+                return entries;
+            }
+            Err(err) => {
+                tracing::warn!("Rejected {source}: {err}. Keeping the previous copy");
+                if let IntegrityError::Shrunk {
+                    entries, previous, ..
+                } = err
+                {
+                    let baseline = lowered_baseline(previous, entries);
+                    tracing::warn!(
+                        "{source} shrank to {entries} entries, comparing the next copy against {baseline}"
+                    );
+                    fetcher.lower_baseline(&fetch, baseline).await;
+                }
+                fetched = match fetcher.fallback(&fetch).await {
+                    Some(fetched) => fetched,
+                    None => {
+                        tracing::warn!("No previous copy of {source}, skipping");
+                        return Vec::new();
+                    }
+                };
+            }
+        }
+    }
+
+    parse_raw(&fetch, fetched.raw, parse)
+        .await
+        .unwrap_or_default()
+}
@@ -1,14 +1,84 @@
 mod blacklist;
+mod integrity;
+mod loader;
 mod parser;
 mod rewrites;
 mod whitelist;
 
-use crate::{config::Config, db::AdblockDB};
+use std::collections::HashMap;
+
+use futures::future::join_all;
+
+use crate::{
+    config::{Category, Config, MatchMode, SourceMeta, Tag},
+    db::AdblockDB,
+    fetch::Fetcher,
+};
 
 use self::{
-    blacklist::BlacklistCompiler, rewrites::RewritesCompiler, whitelist::WhitelistCompiler,
+    blacklist::BlacklistCompiler, parser::Domain, rewrites::RewritesCompiler,
+    whitelist::WhitelistCompiler,
 };
 
+/// What a single source contributed to a compile
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct SourceReport {
+    pub kind: &'static str,
+    pub name: String,
+    pub category: Option<Category>,
+    pub description: Option<String>,
+    pub entries: usize,
+}
+
+impl SourceReport {
+    fn new(kind: &'static str, meta: &SourceMeta, entries: usize) -> Self {
+        Self {
+            kind,
+            name: meta.name.clone(),
+            category: meta.category,
+            description: meta.description.clone(),
+            entries,
+        }
+    }
+}
+
+/// Keys a listed domain is stored under, the name itself and, for
+/// subdomain matching sources, a wildcard covering everything below it
+fn domain_keys(domain: String, match_mode: MatchMode) -> Vec<String> {
+    match match_mode {
+        MatchMode::Subdomains if !domain.starts_with("*.") => {
+            let wildcard = format!("*.{domain}");
+            vec![domain, wildcard]
+        }
+        _ => vec![domain],
+    }
+}
+
+/// Merge the lists into `domain => tags`, where tags is the encoded
+/// attribution of every source that listed the domain
+fn merge_tagged<'a>(
+    lists: impl IntoIterator<Item = (&'a SourceMeta, Vec<Domain>)>,
+) -> HashMap<String, String> {
+    let mut merged: HashMap<String, Vec<Tag>> = HashMap::new();
+    for (meta, domains) in lists {
+        let tag = meta.tag();
+        let keys = domains
+            .into_iter()
+            .flat_map(|domain| domain_keys(domain.0, meta.match_mode));
+        for key in keys {
+            let tags = merged.entry(key).or_default();
+            if !tags.contains(&tag) {
+                tags.push(tag.clone());
+            }
+        }
+    }
+
+    merged
+        .into_iter()
+        .map(|(domain, tags)| (domain, Tag::encode(&tags)))
+        .collect()
+}
+
 #[derive(Debug)]
 pub struct AdblockCompiler {
     blacklists: Vec<BlacklistCompiler>,
@@ -29,26 +99,42 @@ impl AdblockCompiler {
         }
     }
 
-    pub async fn compile(&self, db: &AdblockDB) {
-        for wl in &self.whitelists {
-            let domains = wl.load_whitelist().await;
-            for d in domains {
-                let _ = db.whitelist.put(&d.0);
-            }
-        }
+    /// Fetch and parse all sources concurrently, within the fetcher's limits,
+    /// then write each store in batches, attributing entries to their sources
+    pub async fn compile(&self, db: &AdblockDB, fetcher: &Fetcher) -> Vec<SourceReport> {
+        let whitelists = join_all(self.whitelists.iter().map(|wl| wl.load_whitelist(fetcher)));
+        let blacklists = join_all(self.blacklists.iter().map(|bl| bl.load_blacklist(fetcher)));
+        let rewrites = join_all(self.rewrites.iter().map(|rw| rw.load_rewrites(fetcher)));
+        let (whitelists, blacklists, rewrites) = tokio::join!(whitelists, blacklists, rewrites);
 
-        for bl in &self.blacklists {
-            let domains = bl.load_blacklist().await;
-            for d in domains {
-                let _ = db.blacklist.put(&d.0);
-            }
+        let mut reports = Vec::new();
+        for (wl, domains) in self.whitelists.iter().zip(&whitelists) {
+            reports.push(SourceReport::new("whitelist", &wl.meta, domains.len()));
+        }
+        for (bl, domains) in self.blacklists.iter().zip(&blacklists) {
+            reports.push(SourceReport::new("blacklist", &bl.meta, domains.len()));
+        }
+        for (rw, cnames) in self.rewrites.iter().zip(&rewrites) {
+            reports.push(SourceReport::new("overrides", &rw.meta, cnames.len()));
         }
 
-        for rw in &self.rewrites {
-            let cnames = rw.load_rewrites().await;
-            for c in cnames {
-                let _ = db.rewrites.put_alias(&c.domain.0, &c.alias.0);
-            }
+        let whitelist = merge_tagged(self.whitelists.iter().map(|wl| &wl.meta).zip(whitelists));
+        let _ = db
+            .whitelist
+            .put_batch(whitelist.iter().map(|(d, t)| (d.as_str(), t.as_str())));
+
+        let blacklist = merge_tagged(self.blacklists.iter().map(|bl| &bl.meta).zip(blacklists));
+        let _ = db
+            .blacklist
+            .put_batch(blacklist.iter().map(|(d, t)| (d.as_str(), t.as_str())));
+
+        for cnames in rewrites {
+            let aliases = cnames
+                .iter()
+                .map(|c| (c.domain.0.as_str(), c.alias.0.as_str()));
+            let _ = db.rewrites.put_alias_batch(aliases);
         }
+
+        reports
     }
 }
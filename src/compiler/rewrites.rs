@@ -1,6 +1,9 @@
-use crate::config::{FileOrUrl, OverrideFormat, OverridesSource};
+use crate::{
+    config::{FileOrUrl, Integrity, OverrideFormat, OverridesSource, SourceMeta},
+    fetch::Fetcher,
+};
 
-use super::parser::CName;
+use super::{loader::load_source, parser::CName};
 
 #[derive(Debug, Clone)]
 pub(super) enum ParseRewrite {
@@ -26,27 +29,22 @@ impl From<&OverrideFormat> for ParseRewrite {
 #[derive(Debug)]
 pub struct RewritesCompiler {
     pub(super) source: FileOrUrl,
+    pub(super) integrity: Integrity,
+    pub(super) meta: SourceMeta,
     pub(super) parser: ParseRewrite,
 }
 
 impl RewritesCompiler {
-    pub async fn load_rewrites(&self) -> Vec<CName> {
-        let source = match self.source.to_fetch().fetch().await {
-            Ok(s) => s,
-            Err(err) => {
-                println!("Could not fetch from {:?}", &self.source);
-                println!("{}", err);
-                println!("Skipping");
-                return Vec::new();
-            }
-        };
-
+    pub async fn load_rewrites(&self, fetcher: &Fetcher) -> Vec<CName> {
         let parser = self.parser.clone();
-        tokio::task::spawn_blocking(move || {
-            source.lines().filter_map(|l| parser.parse(l)).collect()
-        })
+        load_source(
+            fetcher,
+            &self.source,
+            &self.integrity,
+            self.meta.update_interval,
+            move |l| parser.parse(l),
+        )
         .await
-        .unwrap_or_default()
     }
 }
 
@@ -54,6 +52,8 @@ impl From<&OverridesSource> for RewritesCompiler {
     fn from(rw: &OverridesSource) -> Self {
         Self {
             source: rw.file_or_url.clone(),
+            integrity: rw.integrity.clone(),
+            meta: rw.meta.clone(),
             parser: ParseRewrite::from(&rw.format),
         }
     }
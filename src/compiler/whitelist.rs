@@ -1,6 +1,12 @@
-use crate::config::{FileOrUrl, WhitelistFormat, WhitelistSource};
+use crate::{
+    config::{FileOrUrl, Integrity, SourceMeta, WhitelistFormat, WhitelistSource},
+    fetch::Fetcher,
+};
 
-use super::parser::{CName, Domain, Host};
+use super::{
+    loader::load_source,
+    parser::{CName, Domain, Host},
+};
 
 #[derive(Debug, Clone)]
 pub enum ParseWhitelist {
@@ -32,27 +38,22 @@ impl From<&WhitelistFormat> for ParseWhitelist {
 #[derive(Debug)]
 pub struct WhitelistCompiler {
     pub(crate) source: FileOrUrl,
+    pub(crate) integrity: Integrity,
+    pub(crate) meta: SourceMeta,
     pub(crate) parser: ParseWhitelist,
 }
 
 impl WhitelistCompiler {
-    pub async fn load_whitelist(&self) -> Vec<Domain> {
-        let source = match self.source.to_fetch().fetch().await {
-            Ok(s) => s,
-            Err(err) => {
-                println!("Could not fetch from {:?}", &self.source);
-                println!("{}", err);
-                println!("Skipping");
-                return Vec::new();
-            }
-        };
-
+    pub async fn load_whitelist(&self, fetcher: &Fetcher) -> Vec<Domain> {
         let parser = self.parser.clone();
-        tokio::task::spawn_blocking(move || {
-            source.lines().filter_map(|l| parser.parse(l)).collect()
-        })
+        load_source(
+            fetcher,
+            &self.source,
+            &self.integrity,
+            self.meta.update_interval,
+            move |l| parser.parse(l),
+        )
         .await
-        .unwrap_or_default()
     }
 }
 
@@ -60,6 +61,8 @@ impl From<&WhitelistSource> for WhitelistCompiler {
     fn from(wl: &WhitelistSource) -> Self {
         Self {
             source: wl.file_or_url.clone(),
+            integrity: wl.integrity.clone(),
+            meta: wl.meta.clone(),
             parser: ParseWhitelist::from(&wl.format),
         }
     }
@@ -2,21 +2,36 @@ mod file_or_url;
 mod raw_config;
 mod source;
 
+use std::path::PathBuf;
+
+use futures::{future::BoxFuture, FutureExt};
+use itertools::Itertools;
 use thiserror::Error;
 
 pub use self::file_or_url::FileOrUrl;
-pub use self::raw_config::{BlacklistFormat, OverrideFormat, WhitelistFormat};
-pub use self::source::{BlacklistSource, OverridesSource, Source, WhitelistSource};
+pub use self::raw_config::{BlacklistFormat, Category, MatchMode, OverrideFormat, WhitelistFormat};
+pub use self::source::{
+    BlacklistSource, Integrity, OverridesSource, Source, SourceMeta, Tag, WhitelistSource,
+};
+
+use crate::fetch::{split_member, FetchError};
 
-use crate::fetch::FetchError;
+use self::{
+    raw_config::{RawConfig, RawSource},
+    source::{resolve_path, FromRawSourceError},
+};
 
-use self::{raw_config::RawConfig, source::FromRawSourceError};
+/// Guards against include chains that never end
+const MAX_INCLUDE_DEPTH: usize = 8;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct Config {
     pub blacklist: Vec<BlacklistSource>,
     pub whitelist: Vec<WhitelistSource>,
     pub overrides: Vec<OverridesSource>,
+
+    /// Every config that was loaded, includes first
+    pub files: Vec<FileOrUrl>,
 }
 
 #[derive(Error, Debug)]
@@ -29,6 +44,39 @@ pub enum LoadConfigError {
 
     #[error("FromSourceError: {0}")]
     FromSource(#[from] FromRawSourceError),
+
+    #[error("IncludeCycle: {0}")]
+    IncludeCycle(String),
+
+    #[error("IncludeTooDeep: {0}")]
+    IncludeTooDeep(String),
+}
+
+fn sources_from_raw<T: Clone>(
+    config_url: &FileOrUrl,
+    raw_sources: &[RawSource<T>],
+) -> Result<Vec<Source<T>>, FromRawSourceError> {
+    let mut sources = Vec::new();
+    for raw_source in raw_sources {
+        match Source::try_from_raw_source(config_url, raw_source) {
+            Ok(source) => sources.push(source),
+            // a disabled source that does not resolve cannot override anything either
+            Err(_) if !raw_source.enabled => continue,
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(sources)
+}
+
+/// Append `other` to `sources`, a later source replaces an earlier one at the same location
+fn merge_sources<T: Clone>(sources: &mut Vec<Source<T>>, other: Vec<Source<T>>) {
+    for source in other {
+        match sources.iter_mut().find(|s| s.key() == source.key()) {
+            Some(existing) => *existing = source,
+            None => sources.push(source),
+        }
+    }
 }
 
 impl Config {
@@ -36,38 +84,142 @@ impl Config {
         config_url: &FileOrUrl,
         source_config: &RawConfig,
     ) -> Result<Self, FromRawSourceError> {
-        let mut blacklist: Vec<BlacklistSource> = Vec::new();
+        Ok(Self {
+            blacklist: sources_from_raw(config_url, &source_config.blacklist)?,
+            whitelist: sources_from_raw(config_url, &source_config.whitelist)?,
+            overrides: sources_from_raw(config_url, &source_config.overrides)?,
+            files: vec![config_url.clone()],
+        })
+    }
 
-        for raw_source in &source_config.blacklist {
-            let source = BlacklistSource::try_from_raw_source(config_url, raw_source)?;
-            blacklist.push(source);
-        }
+    fn merge(&mut self, other: Config) {
+        merge_sources(&mut self.blacklist, other.blacklist);
+        merge_sources(&mut self.whitelist, other.whitelist);
+        merge_sources(&mut self.overrides, other.overrides);
+        self.files.extend(other.files);
+    }
+
+    /// Local configs and list files, the ones worth watching for changes
+    pub fn local_paths(&self) -> Vec<PathBuf> {
+        let sources = self
+            .blacklist
+            .iter()
+            .map(|s| &s.file_or_url)
+            .chain(self.whitelist.iter().map(|s| &s.file_or_url))
+            .chain(self.overrides.iter().map(|s| &s.file_or_url));
 
-        let mut whitelist: Vec<WhitelistSource> = Vec::new();
-        for raw_source in &source_config.whitelist {
-            let source = WhitelistSource::try_from_raw_source(config_url, raw_source)?;
-            whitelist.push(source);
+        self.files
+            .iter()
+            .chain(sources)
+            .filter_map(|f| match f {
+                FileOrUrl::File(path) => {
+                    let path = path.to_string_lossy();
+                    Some(PathBuf::from(split_member(&path).0))
+                }
+                FileOrUrl::Url(_) => None,
+            })
+            .unique()
+            .collect()
+    }
+
+    /// Load a single config, merging its includes in before its own sources
+    fn load_one<'a>(
+        config_url: &'a FileOrUrl,
+        stack: &'a mut Vec<String>,
+    ) -> BoxFuture<'a, Result<Self, LoadConfigError>> {
+        async move {
+            let key = config_url.to_string();
+            if stack.contains(&key) {
+                return Err(LoadConfigError::IncludeCycle(key));
+            }
+            if stack.len() >= MAX_INCLUDE_DEPTH {
+                return Err(LoadConfigError::IncludeTooDeep(key));
+            }
+
+            let content = config_url.to_fetch().fetch().await?;
+            let raw_config: RawConfig = serde_yaml::from_str(&content)?;
+
+            let mut config = Config::default();
+            stack.push(key);
+            for include in &raw_config.include {
+                let include_url = resolve_path(config_url, include)?;
+                tracing::info!("Including config: {include_url}");
+                config.merge(Self::load_one(&include_url, stack).await?);
+            }
+            stack.pop();
+
+            config.merge(Self::try_from_raw_config(config_url, &raw_config)?);
+            Ok(config)
         }
+        .boxed()
+    }
 
-        let mut overrides: Vec<OverridesSource> = Vec::new();
-        for raw_source in &source_config.overrides {
-            let source: Source<OverrideFormat> =
-                OverridesSource::try_from_raw_source(config_url, raw_source)?;
-            overrides.push(source);
+    /// Load and merge configs in order, later configs override earlier ones
+    pub async fn load(config_urls: &[FileOrUrl]) -> Result<Self, LoadConfigError> {
+        let mut config = Config::default();
+        for config_url in config_urls {
+            config.merge(Self::load_one(config_url, &mut Vec::new()).await?);
         }
 
-        Ok(Self {
-            blacklist,
-            whitelist,
-            overrides,
-        })
+        config.blacklist.retain(|s| s.meta.enabled);
+        config.whitelist.retain(|s| s.meta.enabled);
+        config.overrides.retain(|s| s.meta.enabled);
+
+        Ok(config)
     }
+}
 
-    pub async fn load(config_url: &FileOrUrl) -> Result<Self, LoadConfigError> {
-        let content = config_url.to_fetch().fetch().await?;
-        let raw_config: RawConfig = serde_yaml::from_str(&content)?;
-        let config = Self::try_from_raw_config(config_url, &raw_config)?;
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
 
-        Ok(config)
+    use super::*;
+
+    fn write(dir: &std::path::Path, name: &str, content: &str) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn it_merges_includes_and_configs() {
+        let dir = std::env::temp_dir().join(format!("bancuh-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        write(&dir, "a.hosts", "");
+        write(&dir, "b.hosts", "");
+        write(&dir, "c.txt", "");
+
+        write(
+            &dir,
+            "upstream.yaml",
+            "blacklist:\n  - { format: hosts, path: ./a.hosts }\n  - { format: hosts, path: ./b.hosts }\n",
+        );
+        let local = write(
+            &dir,
+            "local.yaml",
+            "include: [./upstream.yaml]\nblacklist:\n  - { format: hosts, path: ./b.hosts, enabled: false }\n",
+        );
+        let extra = write(
+            &dir,
+            "extra.yaml",
+            "whitelist:\n  - { format: domains, path: ./c.txt }\n",
+        );
+
+        let config = Config::load(&[FileOrUrl::File(local), FileOrUrl::File(extra)])
+            .await
+            .unwrap();
+        let names: Vec<_> = config
+            .blacklist
+            .iter()
+            .map(|s| s.meta.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["a"]);
+        assert_eq!(config.whitelist.len(), 1);
+
+        let cycle = write(&dir, "cycle.yaml", "include: [./cycle.yaml]\n");
+        let output = Config::load(&[FileOrUrl::File(cycle)]).await;
+        assert!(matches!(output, Err(LoadConfigError::IncludeCycle(_))));
+
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
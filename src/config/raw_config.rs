@@ -19,15 +19,126 @@ pub enum OverrideFormat {
     Cname,
 }
 
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Category {
+    Ads,
+    Trackers,
+    Malware,
+    Adult,
+    Social,
+}
+
+impl Category {
+    pub const ALL: [Category; 5] = [
+        Category::Ads,
+        Category::Trackers,
+        Category::Malware,
+        Category::Adult,
+        Category::Social,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Category::Ads => "ads",
+            Category::Trackers => "trackers",
+            Category::Malware => "malware",
+            Category::Adult => "adult",
+            Category::Social => "social",
+        }
+    }
+}
+
+impl std::fmt::Display for Category {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for Category {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Category::ALL
+            .into_iter()
+            .find(|c| c.as_str() == s)
+            .ok_or_else(|| format!("unknown category: {s}"))
+    }
+}
+
+/// Which names a listed domain covers
+#[derive(serde::Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchMode {
+    /// Only the listed name, plus any explicit `*.` entries
+    #[default]
+    Exact,
+    /// The listed name and every name below it
+    Subdomains,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
 #[derive(serde::Deserialize, Debug, Clone)]
 pub struct RawSource<T: Clone> {
     pub format: T,
     pub path: String,
+
+    /// Short name used to attribute matches, defaults to the file name of `path`
+    #[serde(default)]
+    pub name: Option<String>,
+
+    #[serde(default)]
+    pub category: Option<Category>,
+
+    /// Whether entries also cover their subdomains
+    #[serde(default, rename = "match")]
+    pub match_mode: MatchMode,
+
+    /// Disabled sources are skipped entirely
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+
+    /// Minimum seconds between downloads of this source, reusing the cached copy in between
+    #[serde(default)]
+    pub update_interval: Option<u64>,
+
+    #[serde(default)]
+    pub description: Option<String>,
+
+    /// Expected sha256 of the published file, hex encoded
+    #[serde(default)]
+    pub sha256: Option<String>,
+
+    /// Path or url of a minisign signature of the published file
+    #[serde(default)]
+    pub signature: Option<String>,
+
+    /// Minisign public key used to check `signature`, base64 encoded
+    #[serde(default)]
+    pub public_key: Option<String>,
+
+    /// Reject the list if it yields fewer entries than this
+    #[serde(default)]
+    pub min_entries: Option<usize>,
+
+    /// Reject the list if it shrinks by more than this percentage since the last compile
+    #[serde(default)]
+    pub max_shrink: Option<f64>,
 }
 
 #[derive(serde::Deserialize, Debug, Clone)]
 pub struct RawConfig {
+    /// Other configs to merge in before this one, local paths or urls
+    #[serde(default)]
+    pub include: Vec<String>,
+
+    #[serde(default)]
     pub blacklist: Vec<RawSource<BlacklistFormat>>,
+    #[serde(default)]
     pub whitelist: Vec<RawSource<WhitelistFormat>>,
+    #[serde(default)]
     pub overrides: Vec<RawSource<OverrideFormat>>,
 }
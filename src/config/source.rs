@@ -1,8 +1,13 @@
+use std::time::Duration;
+
 use thiserror::Error;
 
+use crate::fetch::split_member;
+
 use super::{
-    file_or_url::ParseFileOrUrlError, raw_config::RawSource, BlacklistFormat, FileOrUrl,
-    OverrideFormat, WhitelistFormat,
+    file_or_url::ParseFileOrUrlError,
+    raw_config::{MatchMode, RawSource},
+    BlacklistFormat, Category, FileOrUrl, OverrideFormat, WhitelistFormat,
 };
 
 #[derive(Error, Debug)]
@@ -21,42 +26,194 @@ pub enum FromRawSourceError {
 
     #[error("InvalidFileOrUrl")]
     InvalidFileOrUrl(#[from] ParseFileOrUrlError),
+
+    #[error("MissingPublicKey: signature is set without public_key")]
+    MissingPublicKey,
+
+    #[error("InvalidMaxShrink: expected a percentage between 0 and 100, got {0}")]
+    InvalidMaxShrink(f64),
+}
+
+/// Checks a source must pass before it replaces the previously compiled copy
+#[derive(Debug, Clone, Default)]
+pub struct Integrity {
+    pub sha256: Option<String>,
+    pub signature: Option<FileOrUrl>,
+    pub public_key: Option<String>,
+    pub min_entries: Option<usize>,
+    pub max_shrink: Option<f64>,
+}
+
+/// Descriptive fields of a source, used for attribution and the admin UI
+#[derive(Debug, Clone)]
+pub struct SourceMeta {
+    pub name: String,
+    pub enabled: bool,
+    pub category: Option<Category>,
+    pub match_mode: MatchMode,
+    pub update_interval: Option<Duration>,
+    pub description: Option<String>,
+}
+
+impl SourceMeta {
+    /// Attribution tag stored against each entry
+    pub fn tag(&self) -> Tag {
+        Tag {
+            category: self.category,
+            name: self.name.clone(),
+        }
+    }
+}
+
+/// Attribution of a stored entry to a source that listed it
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Tag {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<Category>,
+    pub name: String,
+}
+
+impl Tag {
+    /// The stored value for the tags of an entry, a JSON array
+    pub fn encode(tags: &[Tag]) -> String {
+        serde_json::to_string(tags).expect("tags always serialize")
+    }
+
+    /// The tags of a stored value. Dbs written before tags were structured stored them as a
+    /// comma separated list of `category/name` or `name`, which is still read.
+    pub fn decode(value: &str) -> Vec<Tag> {
+        if let Ok(tags) = serde_json::from_str(value) {
+            return tags;
+        }
+
+        value
+            .split(',')
+            .map(|tag| match tag.split_once('/') {
+                Some((category, name)) if category.parse::<Category>().is_ok() => Tag {
+                    category: category.parse().ok(),
+                    name: name.to_string(),
+                },
+                _ => Tag {
+                    category: None,
+                    name: tag.to_string(),
+                },
+            })
+            .collect()
+    }
+}
+
+/// `category/name`, or just `name`, as shown to users
+impl std::fmt::Display for Tag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.category {
+            Some(category) => write!(f, "{category}/{}", self.name),
+            None => f.write_str(&self.name),
+        }
+    }
+}
+
+/// Default source name, the file name of the path without extensions
+fn default_name(path: &str) -> String {
+    let (path, member) = split_member(path);
+    let path = member.unwrap_or(path);
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    let name = file_name.split('.').next().unwrap_or(file_name);
+
+    if name.is_empty() {
+        path.to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+pub(super) fn resolve_path(
+    config_url: &FileOrUrl,
+    path: &str,
+) -> Result<FileOrUrl, FromRawSourceError> {
+    let file_or_url = if path.starts_with("./") {
+        match config_url {
+            FileOrUrl::Url(u) => FileOrUrl::Url(u.join(path)?),
+            FileOrUrl::File(p) => {
+                let path = p
+                    .parent()
+                    .ok_or(FromRawSourceError::InvalidPath)?
+                    .join(path);
+
+                // `archive.zip#member` points inside an archive, check the archive itself
+                let (archive, _) = split_member(path.to_str().unwrap_or_default());
+                if std::path::Path::new(archive).exists() {
+                    FileOrUrl::File(path)
+                } else {
+                    return Err(FromRawSourceError::FileNotExists);
+                }
+            }
+        }
+    } else {
+        path.parse()?
+    };
+
+    Ok(file_or_url)
 }
 
 #[derive(Debug, Clone)]
 pub struct Source<T: Clone> {
     pub format: T,
     pub file_or_url: FileOrUrl,
+    pub integrity: Integrity,
+    pub meta: SourceMeta,
 }
 
 impl<T: Clone> Source<T> {
+    /// Identifies the source across merged configs
+    pub fn key(&self) -> String {
+        self.file_or_url.to_string()
+    }
+
     pub fn try_from_raw_source(
         config_url: &FileOrUrl,
         source: &RawSource<T>,
     ) -> Result<Self, FromRawSourceError> {
-        let file_or_url = if source.path.starts_with("./") {
-            match config_url {
-                FileOrUrl::Url(u) => FileOrUrl::Url(u.join(&source.path)?),
-                FileOrUrl::File(p) => {
-                    let path = p
-                        .parent()
-                        .ok_or(FromRawSourceError::InvalidPath)?
-                        .join(&source.path);
-
-                    if path.exists() {
-                        FileOrUrl::File(path)
-                    } else {
-                        return Err(FromRawSourceError::FileNotExists);
-                    }
-                }
+        let file_or_url = resolve_path(config_url, &source.path)?;
+
+        let signature = match &source.signature {
+            Some(_) if source.public_key.is_none() => {
+                return Err(FromRawSourceError::MissingPublicKey)
+            }
+            Some(signature) => Some(resolve_path(config_url, signature)?),
+            None => None,
+        };
+
+        if let Some(max_shrink) = source.max_shrink {
+            if !(0.0..=100.0).contains(&max_shrink) {
+                return Err(FromRawSourceError::InvalidMaxShrink(max_shrink));
             }
-        } else {
-            source.path.parse()?
+        }
+
+        let integrity = Integrity {
+            sha256: source.sha256.as_ref().map(|s| s.trim().to_lowercase()),
+            signature,
+            public_key: source.public_key.clone(),
+            min_entries: source.min_entries,
+            max_shrink: source.max_shrink,
+        };
+
+        let meta = SourceMeta {
+            name: source
+                .name
+                .clone()
+                .unwrap_or_else(|| default_name(&source.path)),
+            enabled: source.enabled,
+            category: source.category,
+            match_mode: source.match_mode,
+            update_interval: source.update_interval.map(Duration::from_secs),
+            description: source.description.clone(),
         };
 
         Ok(Self {
             format: source.format.clone(),
             file_or_url,
+            integrity,
+            meta,
         })
     }
 }
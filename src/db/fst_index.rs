@@ -0,0 +1,442 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
+
+use fst::{
+    map::Stream,
+    raw::{Fst, Node, Output},
+    Map, MapBuilder, Streamer,
+};
+use itertools::Itertools;
+use memmap2::Mmap;
+
+use super::{link_or_copy, working_path, DBError};
+
+const INDEX_FILE: &str = "index.fst";
+const VALUES_FILE: &str = "values.json";
+
+/// Entries held in memory before they are sorted and spilled to a run file
+const RUN_SIZE: usize = 100_000;
+
+/// Reverse the labels of a domain, so names sharing a suffix share a prefix in the index,
+/// e.g. `ads.example.com.` becomes `com.example.ads`
+fn reverse_labels(domain: &str) -> String {
+    domain.split('.').filter(|l| !l.is_empty()).rev().join(".")
+}
+
+/// Follow `bytes` from `node`, returning the value if they end on a key
+fn follow<D: AsRef<[u8]>>(fst: &Fst<D>, node: Node, output: Output, bytes: &[u8]) -> Option<u64> {
+    let (mut node, mut output) = (node, output);
+    for &b in bytes {
+        let t = node.transition(node.find_input(b)?);
+        output = output.cat(t.out);
+        node = fst.node(t.addr);
+    }
+
+    node.is_final()
+        .then(|| output.cat(node.final_output()).value())
+}
+
+#[derive(Debug)]
+struct Index {
+    map: Map<Mmap>,
+    values: Vec<String>,
+}
+
+impl Index {
+    fn load(dir: &Path) -> Result<Self, DBError> {
+        let file = File::open(dir.join(INDEX_FILE))?;
+        // SAFETY: the index file is private to this store, and never modified once written
+        let mmap = unsafe { Mmap::map(&file)? };
+        let map = Map::new(mmap)?;
+        let values = serde_json::from_slice(&std::fs::read(dir.join(VALUES_FILE))?)?;
+
+        Ok(Self { map, values })
+    }
+
+    /// Values matching `domain` with the number of labels their key covers,
+    /// the exact name first and then each parent wildcard, from the most to
+    /// the least specific. Takes a single walk down the reversed name.
+    fn matches(&self, domain: &str) -> Vec<(&str, usize)> {
+        let key = reverse_labels(domain);
+        let fst = self.map.as_fst();
+
+        let mut found = Vec::new();
+        let mut node = fst.root();
+        let mut output = Output::zero();
+        let mut labels = 1;
+        let mut exact = true;
+        for &b in key.as_bytes() {
+            // at each label boundary, a `*` label matches anything below it
+            if b == b'.' {
+                found.extend(follow(fst, node, output, b".*").map(|id| (id, labels)));
+                labels += 1;
+            }
+
+            let Some(i) = node.find_input(b) else {
+                exact = false;
+                break;
+            };
+            let t = node.transition(i);
+            output = output.cat(t.out);
+            node = fst.node(t.addr);
+        }
+        if exact {
+            found.extend(follow(fst, node, output, b"").map(|id| (id, labels)));
+        }
+
+        found
+            .into_iter()
+            .rev()
+            .filter_map(|(id, labels)| Some((self.values.get(id as usize)?.as_str(), labels)))
+            .collect()
+    }
+}
+
+/// Keys of an index, turned back into domain names, with their values
+struct Entries<'a> {
+    stream: Stream<'a>,
+    values: &'a [String],
+}
+
+impl Iterator for Entries<'_> {
+    type Item = (String, String);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, id) = self.stream.next()?;
+        let domain = format!("{}.", reverse_labels(&String::from_utf8_lossy(key)));
+        let value = self.values.get(id as usize).cloned().unwrap_or_default();
+        Some((domain, value))
+    }
+}
+
+/// Sort entries by key, keeping the last written value of a key
+fn sort_run(entries: &mut Vec<(String, String)>) {
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    entries.dedup_by(|later, earlier| {
+        let same = later.0 == earlier.0;
+        if same {
+            std::mem::swap(&mut later.1, &mut earlier.1);
+        }
+        same
+    });
+}
+
+fn write_field(out: &mut impl Write, field: &str) -> std::io::Result<()> {
+    out.write_all(&(field.len() as u32).to_le_bytes())?;
+    out.write_all(field.as_bytes())
+}
+
+fn read_field(input: &mut impl Read) -> std::io::Result<Option<String>> {
+    let mut len = [0; 4];
+    match input.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let mut field = vec![0; u32::from_le_bytes(len) as usize];
+    input.read_exact(&mut field)?;
+    String::from_utf8(field)
+        .map(Some)
+        .map_err(std::io::Error::other)
+}
+
+/// Sorted entries spilled to a file, read back in order
+struct Run(BufReader<File>);
+
+impl Iterator for Run {
+    type Item = Result<(String, String), DBError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = (|| {
+            let Some(key) = read_field(&mut self.0)? else {
+                return Ok(None);
+            };
+            let value = read_field(&mut self.0)?.ok_or(std::io::ErrorKind::UnexpectedEof)?;
+            Ok(Some((key, value)))
+        })();
+        entry.map_err(DBError::IOError).transpose()
+    }
+}
+
+/// Entries in key order, from a run file or from memory
+type SortedEntries = Box<dyn Iterator<Item = Result<(String, String), DBError>>>;
+
+/// Entries written so far: the latest in memory, older ones spilled to sorted run files
+#[derive(Debug, Default)]
+struct Pending {
+    entries: Vec<(String, String)>,
+    runs: Vec<PathBuf>,
+}
+
+/// Domain store backed by an immutable, memory mapped FST over reversed domain names.
+///
+/// Entries are collected until `finish` builds the index, lookups before then find nothing.
+/// They are sorted externally, in runs of `RUN_SIZE` spilled to disk and merged into the index,
+/// so a build holds only one run in memory on top of the index being written.
+#[derive(Debug)]
+pub struct FstStore {
+    dir: PathBuf,
+    pending: Mutex<Pending>,
+    run_size: usize,
+    index: OnceLock<Index>,
+}
+
+impl FstStore {
+    pub fn create() -> Result<Self, DBError> {
+        let dir = working_path();
+        std::fs::create_dir_all(&dir)?;
+
+        Ok(Self {
+            dir,
+            pending: Mutex::default(),
+            run_size: RUN_SIZE,
+            index: OnceLock::new(),
+        })
+    }
+
+    /// Open a working copy of the store snapshotted at `path`, leaving the snapshot untouched
+    pub fn open_snapshot(path: &Path) -> Result<Self, DBError> {
+        let store = Self::create()?;
+        for file in [INDEX_FILE, VALUES_FILE] {
+            link_or_copy(&path.join(file), &store.dir.join(file))?;
+        }
+        let _ = store.index.set(Index::load(&store.dir)?);
+
+        Ok(store)
+    }
+
+    /// Write a copy of the store to `path`, which must not exist yet
+    pub fn snapshot(&self, path: &Path) -> Result<(), DBError> {
+        std::fs::create_dir(path)?;
+        for file in [INDEX_FILE, VALUES_FILE] {
+            link_or_copy(&self.dir.join(file), &path.join(file))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn write_batches<'a, V: AsRef<[u8]>>(
+        &self,
+        entries: impl IntoIterator<Item = (&'a str, V)>,
+    ) -> Result<(), DBError> {
+        let mut pending = self.pending.lock().unwrap();
+        for (domain, value) in entries {
+            let value = String::from_utf8(value.as_ref().to_vec())?;
+            pending.entries.push((reverse_labels(domain), value));
+            if pending.entries.len() >= self.run_size {
+                self.spill(&mut pending)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sort the entries in memory into a new run file
+    fn spill(&self, pending: &mut Pending) -> Result<(), DBError> {
+        let mut entries = std::mem::take(&mut pending.entries);
+        sort_run(&mut entries);
+
+        let path = self.dir.join(format!("run-{}", pending.runs.len()));
+        let mut out = BufWriter::new(File::create(&path)?);
+        for (key, value) in &entries {
+            write_field(&mut out, key)?;
+            write_field(&mut out, value)?;
+        }
+        out.into_inner().map_err(|err| err.into_error())?;
+        pending.runs.push(path);
+
+        Ok(())
+    }
+
+    /// Build the index by merging the sorted runs, values are deduplicated into a side table.
+    /// A key written more than once keeps the value written last.
+    pub fn finish(&self) -> Result<(), DBError> {
+        if self.index.get().is_some() {
+            return Ok(());
+        }
+
+        let Pending { mut entries, runs } = std::mem::take(&mut *self.pending.lock().unwrap());
+        sort_run(&mut entries);
+        let mut sources: Vec<SortedEntries> = runs
+            .iter()
+            .map(|path| {
+                let run = Run(BufReader::new(File::open(path)?));
+                Ok(Box::new(run) as SortedEntries)
+            })
+            .collect::<Result<_, DBError>>()?;
+        sources.push(Box::new(entries.into_iter().map(Ok)));
+
+        // the next entry of each source, smallest key first and the latest source first among
+        // equal keys, so the first of a key popped is the one that is kept
+        let mut heap = BinaryHeap::new();
+        for (i, source) in sources.iter_mut().enumerate() {
+            if let Some(entry) = source.next() {
+                let (key, value) = entry?;
+                heap.push((Reverse(key), i, value));
+            }
+        }
+
+        let mut values = Vec::new();
+        let mut ids = HashMap::new();
+        let writer = BufWriter::new(File::create(self.dir.join(INDEX_FILE))?);
+        let mut builder = MapBuilder::new(writer)?;
+        let mut last: Option<String> = None;
+        while let Some((Reverse(key), i, value)) = heap.pop() {
+            if let Some(entry) = sources[i].next() {
+                let (key, value) = entry?;
+                heap.push((Reverse(key), i, value));
+            }
+            if last.as_ref() == Some(&key) {
+                continue;
+            }
+
+            let id = match ids.get(&value) {
+                Some(id) => *id,
+                None => {
+                    let id = values.len() as u64;
+                    ids.insert(value.clone(), id);
+                    values.push(value);
+                    id
+                }
+            };
+            builder.insert(&key, id)?;
+            last = Some(key);
+        }
+        builder
+            .into_inner()?
+            .into_inner()
+            .map_err(|err| err.into_error())?;
+        std::fs::write(self.dir.join(VALUES_FILE), serde_json::to_vec(&values)?)?;
+        for run in runs {
+            std::fs::remove_file(run)?;
+        }
+
+        let _ = self.index.set(Index::load(&self.dir)?);
+        Ok(())
+    }
+
+    /// Number of indexed keys, zero until `finish`
+    pub fn entries(&self) -> usize {
+        self.index.get().map_or(0, |index| index.map.len())
+    }
+
+    /// All indexed keys with their values, ordered by reversed name, nothing until `finish`
+    pub fn iter(&self) -> impl Iterator<Item = (String, String)> + '_ {
+        self.index.get().into_iter().flat_map(|index| Entries {
+            stream: index.map.stream(),
+            values: &index.values,
+        })
+    }
+
+    /// Return the first matching value accepted by `filter`, from the most
+    /// specific, with the number of labels its key covers
+    pub fn find<T>(
+        &self,
+        domain: &str,
+        filter: impl Fn(&str) -> Option<T>,
+    ) -> Result<Option<(T, usize)>, DBError> {
+        let Some(index) = self.index.get() else {
+            return Ok(None);
+        };
+
+        let found = index
+            .matches(domain)
+            .into_iter()
+            .find_map(|(value, labels)| Some((filter(value)?, labels)));
+        Ok(found)
+    }
+}
+
+impl Drop for FstStore {
+    fn drop(&mut self) {
+        drop(self.index.take());
+
+        let path_str = self.dir.to_string_lossy().to_string();
+        tracing::info!("Destroying db: {path_str}");
+        let res = std::fs::remove_dir_all(&self.dir);
+        tracing::info!("Destroying db: {path_str}. DONE: {res:?}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_matches_exact_names_and_wildcards() {
+        let store = FstStore::create().unwrap();
+        store
+            .write_batches([
+                ("example.com", "exact"),
+                ("*.example.com", "wild"),
+                ("ads.example.com.", "ads"),
+                ("*.com", "tld"),
+                ("other.net", "net"),
+            ])
+            .unwrap();
+        store.finish().unwrap();
+
+        let index = store.index.get().unwrap();
+        assert_eq!(
+            index.matches("example.com."),
+            vec![("exact", 2), ("tld", 1)]
+        );
+        assert_eq!(
+            index.matches("ads.example.com."),
+            vec![("ads", 3), ("wild", 2), ("tld", 1)]
+        );
+        assert_eq!(
+            index.matches("x.y.example.com"),
+            vec![("wild", 2), ("tld", 1)]
+        );
+        assert!(index.matches("example.org.").is_empty());
+        assert!(index.matches("sub.other.net.").is_empty());
+        assert_eq!(index.matches("other.net."), vec![("net", 2)]);
+
+        let names: Vec<_> = store.iter().map(|(name, _)| name).collect();
+        assert_eq!(
+            names,
+            [
+                "*.com.",
+                "example.com.",
+                "*.example.com.",
+                "ads.example.com.",
+                "other.net."
+            ]
+        );
+    }
+
+    #[test]
+    fn it_merges_spilled_runs_keeping_the_last_value() {
+        let mut store = FstStore::create().unwrap();
+        store.run_size = 2;
+        store
+            .write_batches([("b.com", "1"), ("a.com", "1"), ("c.com", "1")])
+            .unwrap();
+        store
+            .write_batches([("a.com", "2"), ("d.com", "2"), ("c.com", "2")])
+            .unwrap();
+        store.write_batches([("a.com", "3")]).unwrap();
+        assert_eq!(store.pending.lock().unwrap().runs.len(), 3);
+        store.finish().unwrap();
+
+        let entries: Vec<_> = store.iter().collect();
+        let expected = [
+            ("a.com.", "3"),
+            ("b.com.", "1"),
+            ("c.com.", "2"),
+            ("d.com.", "2"),
+        ];
+        assert_eq!(
+            entries,
+            expected.map(|(name, value)| (name.to_string(), value.to_string()))
+        );
+        assert!(!store.dir.join("run-0").exists());
+    }
+}
@@ -0,0 +1,264 @@
+use std::{
+    path::{Path, PathBuf},
+    string::FromUtf8Error,
+};
+
+mod fst_index;
+mod rocks;
+
+use rand::{distr::Alphanumeric, Rng};
+use thiserror::Error;
+
+use self::{fst_index::FstStore, rocks::RocksStore};
+
+/// Directory holding the working copies of the stores
+const WORKING_DIR: &str = "./bancuh_db";
+
+/// File in a snapshot directory naming the current snapshot
+const CURRENT: &str = "CURRENT";
+
+fn rand_string() -> String {
+    rand::rng()
+        .sample_iter(Alphanumeric)
+        .take(10)
+        .map(char::from)
+        .collect()
+}
+
+fn rand_name() -> String {
+    format!("db-{}", rand_string())
+}
+
+fn working_path() -> PathBuf {
+    Path::new(WORKING_DIR).join(rand_name())
+}
+
+fn link_or_copy(from: &Path, to: &Path) -> std::io::Result<()> {
+    std::fs::hard_link(from, to).or_else(|_| std::fs::copy(from, to).map(|_| ()))
+}
+
+fn normalize_name(name: &str) -> String {
+    if name.ends_with('.') {
+        name.to_string()
+    } else {
+        format!("{name}.")
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum DBError {
+    #[error(transparent)]
+    RocksDB(#[from] rocksdb::Error),
+
+    #[error(transparent)]
+    FromUtf8(#[from] FromUtf8Error),
+
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Fst(#[from] fst::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+/// Storage used for the compiled domain lists
+#[derive(clap::ValueEnum, serde::Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DBBackend {
+    /// RocksDB instances on disk
+    #[default]
+    Rocksdb,
+    /// Immutable, memory mapped FST indexes
+    Fst,
+}
+
+/// Keys to look up for a domain, the exact name first and then each
+/// parent wildcard, from the most to the least specific
+fn lookup_keys(domain: &str) -> Vec<String> {
+    let parts: Vec<&str> = domain.split('.').filter(|s| !s.is_empty()).collect();
+
+    let mut keys: Vec<String> = vec![normalize_name(domain)];
+    for i in 1..parts.len() {
+        let star_key = format!("*.{}.", parts[i..parts.len()].join("."));
+        keys.push(star_key);
+    }
+
+    keys
+}
+
+#[derive(Debug)]
+pub enum DomainStore {
+    RocksDB(RocksStore),
+    Fst(FstStore),
+}
+
+impl DomainStore {
+    pub fn create(backend: DBBackend) -> Result<Self, DBError> {
+        match backend {
+            DBBackend::Rocksdb => Ok(Self::RocksDB(RocksStore::create()?)),
+            DBBackend::Fst => Ok(Self::Fst(FstStore::create()?)),
+        }
+    }
+
+    pub fn open_snapshot(backend: DBBackend, path: &Path) -> Result<Self, DBError> {
+        match backend {
+            DBBackend::Rocksdb => Ok(Self::RocksDB(RocksStore::open_snapshot(path)?)),
+            DBBackend::Fst => Ok(Self::Fst(FstStore::open_snapshot(path)?)),
+        }
+    }
+
+    pub fn snapshot(&self, path: &Path) -> Result<(), DBError> {
+        match self {
+            Self::RocksDB(store) => store.snapshot(path),
+            Self::Fst(store) => store.snapshot(path),
+        }
+    }
+
+    /// Store domains with their attribution tags
+    pub fn put_batch<'a>(
+        &self,
+        entries: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Result<(), DBError> {
+        match self {
+            Self::RocksDB(store) => store.write_batches(entries),
+            Self::Fst(store) => store.write_batches(entries),
+        }
+    }
+
+    pub fn put_alias_batch<'a>(
+        &self,
+        aliases: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Result<(), DBError> {
+        let entries = aliases
+            .into_iter()
+            .map(|(domain, alias)| (domain, normalize_name(alias)));
+        match self {
+            Self::RocksDB(store) => store.write_batches(entries),
+            Self::Fst(store) => store.write_batches(entries),
+        }
+    }
+
+    /// Make the written entries available for lookups
+    pub fn finish(&self) -> Result<(), DBError> {
+        match self {
+            Self::RocksDB(_) => Ok(()),
+            Self::Fst(store) => store.finish(),
+        }
+    }
+
+    /// Return the first stored value accepted by `filter`, from the most
+    /// specific match, with the number of labels the matched key covers:
+    /// all of them for the exact name, fewer for a parent wildcard
+    pub fn find<T>(
+        &self,
+        domain: &str,
+        filter: impl Fn(&str) -> Option<T>,
+    ) -> Result<Option<(T, usize)>, DBError> {
+        match self {
+            Self::RocksDB(store) => store.find(domain, filter),
+            Self::Fst(store) => store.find(domain, filter),
+        }
+    }
+
+    /// Number of stored keys, estimated for RocksDB
+    pub fn entries(&self) -> Result<usize, DBError> {
+        match self {
+            Self::RocksDB(store) => store.entries(),
+            Self::Fst(store) => Ok(store.entries()),
+        }
+    }
+
+    /// All stored names with their values, wildcards as `*.example.com.`
+    pub fn iter(&self) -> Box<dyn Iterator<Item = Result<(String, String), DBError>> + '_> {
+        match self {
+            Self::RocksDB(store) => Box::new(store.iter()),
+            Self::Fst(store) => Box::new(store.iter().map(Ok)),
+        }
+    }
+
+    /// Whether `domain` itself is stored, rather than only matched by a wildcard
+    pub fn contains(&self, domain: &str) -> Result<bool, DBError> {
+        let labels = domain.split('.').filter(|l| !l.is_empty()).count();
+        let found = self.find(domain, |_| Some(()))?;
+        Ok(found.is_some_and(|(_, n)| n == labels))
+    }
+
+    pub fn get(&self, domain: &str) -> Result<Option<String>, DBError> {
+        let found = self.find(domain, |value| Some(value.to_string()))?;
+        Ok(found.map(|(value, _)| value))
+    }
+}
+
+#[derive(Debug)]
+pub struct AdblockDB {
+    pub blacklist: DomainStore,
+    pub whitelist: DomainStore,
+    pub rewrites: DomainStore,
+}
+
+impl AdblockDB {
+    pub fn create(backend: DBBackend) -> Result<Self, DBError> {
+        let blacklist = DomainStore::create(backend)?;
+        let whitelist = DomainStore::create(backend)?;
+        let rewrites = DomainStore::create(backend)?;
+
+        Ok(Self {
+            blacklist,
+            whitelist,
+            rewrites,
+        })
+    }
+
+    /// Open working copies of the current snapshot in `dir`, if there is one
+    pub fn open_snapshot(backend: DBBackend, dir: &Path) -> Result<Option<Self>, DBError> {
+        let name = match std::fs::read_to_string(dir.join(CURRENT)) {
+            Ok(name) => name,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let path = dir.join(name.trim());
+
+        Ok(Some(Self {
+            blacklist: DomainStore::open_snapshot(backend, &path.join("blacklist"))?,
+            whitelist: DomainStore::open_snapshot(backend, &path.join("whitelist"))?,
+            rewrites: DomainStore::open_snapshot(backend, &path.join("rewrites"))?,
+        }))
+    }
+
+    /// Make all stores available for lookups once they are written
+    pub fn finish(&self) -> Result<(), DBError> {
+        self.blacklist.finish()?;
+        self.whitelist.finish()?;
+        self.rewrites.finish()
+    }
+
+    /// Snapshot all stores into a new directory in `dir`, then make it the current one
+    pub fn save_snapshot(&self, dir: &Path) -> Result<(), DBError> {
+        let name = format!("snapshot-{}", rand_string());
+        let path = dir.join(&name);
+        std::fs::create_dir_all(&path)?;
+
+        self.blacklist.snapshot(&path.join("blacklist"))?;
+        self.whitelist.snapshot(&path.join("whitelist"))?;
+        self.rewrites.snapshot(&path.join("rewrites"))?;
+
+        // switch over with a rename, a crash before it leaves the previous snapshot current
+        let tmp = dir.join(format!("{CURRENT}.tmp"));
+        std::fs::write(&tmp, &name)?;
+        std::fs::rename(tmp, dir.join(CURRENT))?;
+
+        // clean up older snapshots, and any left unfinished by a crash
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let file_name = entry.file_name();
+            let file_name = file_name.to_string_lossy();
+            if file_name.starts_with("snapshot-") && file_name != name {
+                std::fs::remove_dir_all(entry.path())?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+use std::path::Path;
+
+use rocksdb::{
+    checkpoint::Checkpoint, DBWithThreadMode, IteratorMode, MultiThreaded, Options, WriteBatch,
+};
+
+use super::{link_or_copy, lookup_keys, normalize_name, working_path, DBError};
+
+pub type DB = DBWithThreadMode<MultiThreaded>;
+
+/// Number of keys written per `WriteBatch`
+const BATCH_SIZE: usize = 10_000;
+
+/// Domain store backed by a RocksDB instance in the working dir
+#[derive(Debug)]
+pub struct RocksStore {
+    db: Option<DB>,
+}
+
+impl RocksStore {
+    pub fn create() -> Result<Self, DBError> {
+        let db = DB::open_default(working_path())?;
+        let db = Some(db);
+
+        Ok(Self { db })
+    }
+
+    /// Open a working copy of the store snapshotted at `path`, leaving the snapshot untouched.
+    ///
+    /// Opening the snapshot itself, even read-only, can write to it, so its files are copied
+    /// instead: table files are immutable and hard linked, the rest is copied.
+    pub fn open_snapshot(path: &Path) -> Result<Self, DBError> {
+        let working = working_path();
+        std::fs::create_dir_all(&working)?;
+        for entry in std::fs::read_dir(path)? {
+            let entry = entry?;
+            let from = entry.path();
+            let to = working.join(entry.file_name());
+            if from.extension().is_some_and(|ext| ext == "sst") {
+                link_or_copy(&from, &to)?;
+            } else {
+                std::fs::copy(&from, &to)?;
+            }
+        }
+
+        let db = DB::open_default(working)?;
+        Ok(Self { db: Some(db) })
+    }
+
+    /// Write a consistent copy of the store to `path`, which must not exist yet
+    pub fn snapshot(&self, path: &Path) -> Result<(), DBError> {
+        if let Some(db) = &self.db {
+            Checkpoint::new(db)?.create_checkpoint(path)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn write_batches<'a, V: AsRef<[u8]>>(
+        &self,
+        entries: impl IntoIterator<Item = (&'a str, V)>,
+    ) -> Result<(), DBError> {
+        let Some(db) = &self.db else {
+            return Ok(());
+        };
+
+        let mut batch = WriteBatch::default();
+        for (domain, value) in entries {
+            batch.put(normalize_name(domain), value);
+            if batch.len() >= BATCH_SIZE {
+                db.write(std::mem::take(&mut batch))?;
+            }
+        }
+        db.write(batch)?;
+
+        Ok(())
+    }
+
+    pub fn entries(&self) -> Result<usize, DBError> {
+        let Some(db) = &self.db else {
+            return Ok(0);
+        };
+
+        let keys = db.property_int_value("rocksdb.estimate-num-keys")?;
+        Ok(keys.unwrap_or_default() as usize)
+    }
+
+    /// All stored keys with their values, in key order
+    pub fn iter(&self) -> impl Iterator<Item = Result<(String, String), DBError>> + '_ {
+        self.db
+            .iter()
+            .flat_map(|db| db.iterator(IteratorMode::Start))
+            .map(|item| {
+                let (key, value) = item?;
+                Ok((
+                    String::from_utf8(key.into_vec())?,
+                    String::from_utf8(value.into_vec())?,
+                ))
+            })
+    }
+
+    fn get_exact(&self, key: &str) -> Result<Option<String>, DBError> {
+        if let Some(db) = &self.db {
+            if let Some(s) = db.get(key)? {
+                return Ok(Some(String::from_utf8(s)?));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Walk the lookup keys, returning the first stored value accepted by
+    /// `filter`, with the number of labels its key covers
+    pub fn find<T>(
+        &self,
+        domain: &str,
+        filter: impl Fn(&str) -> Option<T>,
+    ) -> Result<Option<(T, usize)>, DBError> {
+        let keys = lookup_keys(domain);
+        let labels = keys.len();
+        for (i, key) in keys.into_iter().enumerate() {
+            if let Some(found) = self.get_exact(&key)?.as_deref().and_then(&filter) {
+                return Ok(Some((found, labels - i)));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl Drop for RocksStore {
+    fn drop(&mut self) {
+        if let Some(db) = std::mem::take(&mut self.db) {
+            let path = db.path().to_path_buf();
+            let path_str = path.to_string_lossy().to_string();
+            let opts = Options::default();
+
+            tracing::info!("Destroying db: {path_str}");
+            db.cancel_all_background_work(true);
+            drop(db);
+            self.db = None;
+            let res = DB::destroy(&opts, path);
+            tracing::info!("Destroying db: {path_str}. DONE: {res:?}");
+        }
+    }
+}
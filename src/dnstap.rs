@@ -0,0 +1,591 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hickory_server::proto::xfer::Protocol;
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, UnixStream},
+    sync::mpsc::{self, error::TrySendError},
+};
+
+use crate::metrics::Outcome;
+
+/// Frames waiting for the writer, more are dropped rather than slowing down queries
+const QUEUE_SIZE: usize = 10_000;
+
+/// Wait between attempts to reach the collector
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+// Frame Streams control frames and fields
+const CONTROL_ACCEPT: u32 = 1;
+const CONTROL_START: u32 = 2;
+const CONTROL_STOP: u32 = 3;
+const CONTROL_READY: u32 = 4;
+const CONTROL_FIELD_CONTENT_TYPE: u32 = 1;
+
+/// Largest control frame accepted from the collector
+const MAX_CONTROL_SIZE: u32 = 512;
+
+#[derive(Error, Debug)]
+pub enum DnstapError {
+    #[error("InvalidTarget: expected unix:<path> or tcp:<ip>:<port>, got {0}")]
+    InvalidTarget(String),
+    #[error("Handshake: {0}")]
+    Handshake(String),
+}
+
+/// Where the dnstap collector listens
+#[derive(Debug, Clone, PartialEq)]
+pub enum DnstapTarget {
+    Unix(PathBuf),
+    Tcp(SocketAddr),
+}
+
+impl FromStr for DnstapTarget {
+    type Err = DnstapError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(Self::Unix(PathBuf::from(path)));
+        }
+        if let Some(addr) = s.strip_prefix("tcp:") {
+            if let Ok(addr) = addr.parse() {
+                return Ok(Self::Tcp(addr));
+            }
+        }
+
+        Err(DnstapError::InvalidTarget(s.to_string()))
+    }
+}
+
+impl std::fmt::Display for DnstapTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+            Self::Tcp(addr) => write!(f, "tcp:{addr}"),
+        }
+    }
+}
+
+/// dnstap `Message.Type`
+#[derive(Debug, Clone, Copy)]
+enum MessageType {
+    ClientQuery = 5,
+    ClientResponse = 6,
+    ForwarderQuery = 7,
+    ForwarderResponse = 8,
+}
+
+/// dnstap `Policy.Action`
+#[derive(Debug, Clone, Copy)]
+enum PolicyAction {
+    NxDomain = 1,
+    LocalData = 6,
+}
+
+/// dnstap `Policy.Match`
+const POLICY_MATCH_QNAME: u64 = 1;
+
+/// Protobuf encoder for the few field types dnstap uses
+#[derive(Default)]
+struct Proto(Vec<u8>);
+
+impl Proto {
+    fn raw_varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn varint(&mut self, field: u64, value: u64) -> &mut Self {
+        self.raw_varint(field << 3);
+        self.raw_varint(value);
+        self
+    }
+
+    fn bytes(&mut self, field: u64, value: &[u8]) -> &mut Self {
+        self.raw_varint(field << 3 | 2);
+        self.raw_varint(value.len() as u64);
+        self.0.extend_from_slice(value);
+        self
+    }
+
+    fn fixed32(&mut self, field: u64, value: u32) -> &mut Self {
+        self.raw_varint(field << 3 | 5);
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+}
+
+fn socket_protocol(protocol: Protocol) -> u64 {
+    match protocol {
+        Protocol::Udp => 1,
+        Protocol::Tcp => 2,
+        Protocol::Tls => 3,
+        Protocol::Https => 4,
+        _ => 2,
+    }
+}
+
+fn socket_family(ip: IpAddr) -> u64 {
+    match ip {
+        IpAddr::V4(_) => 1,
+        IpAddr::V6(_) => 2,
+    }
+}
+
+fn ip_bytes(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+fn time(message: &mut Proto, sec_field: u64, time: SystemTime) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    message
+        .varint(sec_field, since_epoch.as_secs())
+        .fixed32(sec_field + 1, since_epoch.subsec_nanos());
+}
+
+/// A query or response seen by the server, with the DNS message in wire format
+#[derive(Debug, Clone, Copy)]
+pub struct Exchange<'a> {
+    /// The client for client messages, as far as the privacy mode allows, the upstream for
+    /// forwarder messages
+    pub peer: Option<SocketAddr>,
+    pub protocol: Protocol,
+    pub query_time: SystemTime,
+    pub response_time: Option<SystemTime>,
+    pub message: &'a [u8],
+}
+
+/// Emits dnstap frames to a collector over Frame Streams, from a background task
+#[derive(Debug)]
+pub struct Dnstap {
+    sender: mpsc::Sender<Vec<u8>>,
+    identity: Option<String>,
+    dropping: AtomicBool,
+}
+
+impl Dnstap {
+    /// Start writing to `target`, reconnecting whenever the collector goes away.
+    /// `identity` names this server in the frames.
+    pub fn spawn(target: DnstapTarget, identity: Option<String>) -> Self {
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(run(target, receiver));
+
+        Self {
+            sender,
+            identity,
+            dropping: AtomicBool::new(false),
+        }
+    }
+
+    pub fn client_query(&self, exchange: Exchange<'_>) {
+        self.emit(MessageType::ClientQuery, exchange, None);
+    }
+
+    /// The response to a client, annotated with how it was decided, e.g. `blocked by ads/easylist`
+    pub fn client_response(&self, exchange: Exchange<'_>, outcome: Outcome, decision: &str) {
+        self.emit(
+            MessageType::ClientResponse,
+            exchange,
+            Some((outcome, decision)),
+        );
+    }
+
+    /// A query sent upstream, as it went out
+    pub fn forwarder_query(&self, exchange: Exchange<'_>) {
+        self.emit(MessageType::ForwarderQuery, exchange, None);
+    }
+
+    /// The upstream's response, as it came back
+    pub fn forwarder_response(&self, exchange: Exchange<'_>) {
+        self.emit(MessageType::ForwarderResponse, exchange, None);
+    }
+
+    fn emit(&self, kind: MessageType, exchange: Exchange<'_>, decision: Option<(Outcome, &str)>) {
+        let frame = encode(kind, exchange, self.identity.as_deref(), decision);
+        match self.sender.try_send(frame) {
+            Ok(()) => self.dropping.store(false, Ordering::Relaxed),
+            Err(TrySendError::Full(_)) => {
+                if !self.dropping.swap(true, Ordering::Relaxed) {
+                    tracing::warn!("dnstap collector is behind, dropping frames");
+                }
+            }
+            Err(TrySendError::Closed(_)) => {}
+        }
+    }
+}
+
+/// Encode a `Dnstap` protobuf message
+fn encode(
+    kind: MessageType,
+    exchange: Exchange<'_>,
+    identity: Option<&str>,
+    decision: Option<(Outcome, &str)>,
+) -> Vec<u8> {
+    let is_query = matches!(kind, MessageType::ClientQuery | MessageType::ForwarderQuery);
+    let is_client = matches!(kind, MessageType::ClientQuery | MessageType::ClientResponse);
+
+    let mut message = Proto::default();
+    message
+        .varint(1, kind as u64)
+        .varint(3, socket_protocol(exchange.protocol));
+    if let Some(peer) = exchange.peer {
+        // the client sent the query, the upstream answers it
+        let (address, port) = if is_client { (4, 6) } else { (5, 7) };
+        message
+            .varint(2, socket_family(peer.ip()))
+            .bytes(address, &ip_bytes(peer.ip()))
+            .varint(port, peer.port() as u64);
+    }
+    time(&mut message, 8, exchange.query_time);
+    if is_query {
+        message.bytes(10, exchange.message);
+    } else {
+        if let Some(response_time) = exchange.response_time {
+            time(&mut message, 12, response_time);
+        }
+        message.bytes(14, exchange.message);
+    }
+
+    let mut dnstap = Proto::default();
+    if let Some(identity) = identity {
+        dnstap.bytes(1, identity.as_bytes());
+    }
+    dnstap.bytes(
+        2,
+        concat!("bancuh-dns ", env!("CARGO_PKG_VERSION")).as_bytes(),
+    );
+
+    if let Some((outcome, decision)) = decision {
+        let action = match outcome {
+            Outcome::Blocked if rcode(exchange.message) == Some(3) => Some(PolicyAction::NxDomain),
+            Outcome::Blocked | Outcome::Rewritten => Some(PolicyAction::LocalData),
+            _ => None,
+        };
+        if let Some(action) = action {
+            let mut policy = Proto::default();
+            policy
+                .bytes(1, b"bancuh-dns")
+                .bytes(2, decision.as_bytes())
+                .varint(3, action as u64)
+                .varint(4, POLICY_MATCH_QNAME);
+            message.bytes(15, &policy.0);
+        }
+        dnstap.bytes(3, decision.as_bytes());
+    }
+
+    dnstap.bytes(14, &message.0).varint(15, 1);
+    dnstap.0
+}
+
+/// Response code from the header of a wire format message
+fn rcode(message: &[u8]) -> Option<u8> {
+    message.get(3).map(|flags| flags & 0x0f)
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+async fn write_control(stream: &mut dyn Stream, control: u32) -> std::io::Result<()> {
+    let mut frame = Vec::new();
+    let mut body = control.to_be_bytes().to_vec();
+    if control != CONTROL_STOP {
+        body.extend_from_slice(&CONTROL_FIELD_CONTENT_TYPE.to_be_bytes());
+        body.extend_from_slice(&(CONTENT_TYPE.len() as u32).to_be_bytes());
+        body.extend_from_slice(CONTENT_TYPE);
+    }
+    // the escape, a zero length, marks a control frame
+    frame.extend_from_slice(&0u32.to_be_bytes());
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&body);
+
+    stream.write_all(&frame).await?;
+    stream.flush().await
+}
+
+async fn read_control(stream: &mut dyn Stream) -> anyhow::Result<u32> {
+    if stream.read_u32().await? != 0 {
+        return Err(DnstapError::Handshake("expected a control frame".to_string()).into());
+    }
+    let len = stream.read_u32().await?;
+    if !(4..=MAX_CONTROL_SIZE).contains(&len) {
+        return Err(DnstapError::Handshake(format!("bad control frame length {len}")).into());
+    }
+
+    let mut body = vec![0; len as usize];
+    stream.read_exact(&mut body).await?;
+    Ok(u32::from_be_bytes([body[0], body[1], body[2], body[3]]))
+}
+
+/// Connect and run the bidirectional Frame Streams handshake
+async fn connect(target: &DnstapTarget) -> anyhow::Result<Box<dyn Stream>> {
+    let mut stream: Box<dyn Stream> = match target {
+        DnstapTarget::Unix(path) => Box::new(UnixStream::connect(path).await?),
+        DnstapTarget::Tcp(addr) => Box::new(TcpStream::connect(addr).await?),
+    };
+
+    write_control(&mut *stream, CONTROL_READY).await?;
+    let control = read_control(&mut *stream).await?;
+    if control != CONTROL_ACCEPT {
+        return Err(DnstapError::Handshake(format!("expected ACCEPT, got {control}")).into());
+    }
+    write_control(&mut *stream, CONTROL_START).await?;
+
+    Ok(stream)
+}
+
+/// Write frames as they come, batching whatever is queued into one write
+async fn run(target: DnstapTarget, mut receiver: mpsc::Receiver<Vec<u8>>) {
+    loop {
+        let mut stream = match connect(&target).await {
+            Ok(stream) => {
+                tracing::info!("dnstap connected to {target}");
+                stream
+            }
+            Err(err) => {
+                tracing::warn!("dnstap cannot reach {target}: {err}");
+                tokio::time::sleep(RECONNECT_DELAY).await;
+                continue;
+            }
+        };
+
+        let mut buffer = Vec::new();
+        while let Some(frame) = receiver.recv().await {
+            buffer.clear();
+            let mut next = Some(frame);
+            while let Some(frame) = next {
+                buffer.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+                buffer.extend_from_slice(&frame);
+                next = receiver.try_recv().ok();
+            }
+
+            if let Err(err) = stream.write_all(&buffer).await {
+                tracing::warn!("dnstap write to {target} failed: {err}");
+                break;
+            }
+        }
+
+        if receiver.is_closed() && receiver.is_empty() {
+            let _ = write_control(&mut *stream, CONTROL_STOP).await;
+            return;
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, sync::Arc};
+
+    use hickory_server::proto::{
+        op::{Message, MessageType as DnsMessageType},
+        rr::{rdata::A, RData, Record, RecordType},
+    };
+    use tokio::net::{UdpSocket, UnixListener};
+
+    use super::*;
+    use crate::{
+        metrics::Metrics,
+        resolver::{for_query, Resolver},
+    };
+
+    /// Fields of a protobuf message, length delimited ones as bytes, the others as numbers
+    fn fields(mut buf: &[u8]) -> Vec<(u64, Result<u64, Vec<u8>>)> {
+        fn varint(buf: &mut &[u8]) -> u64 {
+            let mut value = 0;
+            for shift in (0..64).step_by(7) {
+                let byte = buf[0];
+                *buf = &buf[1..];
+                value |= ((byte & 0x7f) as u64) << shift;
+                if byte < 0x80 {
+                    break;
+                }
+            }
+            value
+        }
+
+        let mut fields = Vec::new();
+        while !buf.is_empty() {
+            let key = varint(&mut buf);
+            let value = match key & 7 {
+                0 => Ok(varint(&mut buf)),
+                2 => {
+                    let len = varint(&mut buf) as usize;
+                    let (value, rest) = buf.split_at(len);
+                    buf = rest;
+                    Err(value.to_vec())
+                }
+                5 => {
+                    let (value, rest) = buf.split_at(4);
+                    buf = rest;
+                    Ok(u32::from_le_bytes(value.try_into().unwrap()) as u64)
+                }
+                wire => panic!("unexpected wire type {wire}"),
+            };
+            fields.push((key >> 3, value));
+        }
+        fields
+    }
+
+    fn field(fields: &[(u64, Result<u64, Vec<u8>>)], number: u64) -> &Result<u64, Vec<u8>> {
+        &fields.iter().find(|(n, _)| *n == number).unwrap().1
+    }
+
+    /// Accept the server's connection and run the collector side of the handshake
+    async fn collector(listener: &UnixListener) -> tokio::net::UnixStream {
+        let (mut collector, _) = listener.accept().await.unwrap();
+        let stream: &mut dyn Stream = &mut collector;
+        assert_eq!(read_control(stream).await.unwrap(), CONTROL_READY);
+        write_control(stream, CONTROL_ACCEPT).await.unwrap();
+        assert_eq!(read_control(stream).await.unwrap(), CONTROL_START);
+        collector
+    }
+
+    async fn read_frame(collector: &mut tokio::net::UnixStream) -> Vec<u8> {
+        let len = collector.read_u32().await.unwrap();
+        let mut frame = vec![0; len as usize];
+        collector.read_exact(&mut frame).await.unwrap();
+        frame
+    }
+
+    #[tokio::test]
+    async fn it_taps_the_messages_exchanged_with_the_forwarder() {
+        let path = std::env::temp_dir().join(format!(
+            "bancuh-dnstap-forwarder-{}.sock",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let target: DnstapTarget = format!("unix:{}", path.display()).parse().unwrap();
+        let dnstap = Arc::new(Dnstap::spawn(target, None));
+        let mut collector = collector(&listener).await;
+
+        // an upstream answering one A query
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let (len, client) = upstream.recv_from(&mut buf).await.unwrap();
+            let query = Message::from_vec(&buf[..len]).unwrap();
+            let mut response = Message::new();
+            response
+                .set_id(query.id())
+                .set_message_type(DnsMessageType::Response)
+                .set_recursion_available(true)
+                .add_queries(query.queries().to_vec())
+                .add_answer(Record::from_rdata(
+                    query.queries()[0].name().clone(),
+                    60,
+                    RData::A(A(Ipv4Addr::new(192, 0, 2, 1))),
+                ));
+            let response = response.to_vec().unwrap();
+            upstream.send_to(&response, client).await.unwrap();
+        });
+
+        let resolver = Resolver::new(
+            &[upstream_addr.ip()],
+            &upstream_addr.port(),
+            Duration::from_secs(2),
+            Arc::new(Metrics::new()),
+            Some(dnstap),
+        );
+        let (records, answered_by) =
+            for_query(true, resolver.lookup("example.com.", RecordType::A)).await;
+        assert_eq!(records.unwrap().len(), 1);
+        assert_eq!(answered_by, Some(upstream_addr));
+
+        let mut ids = Vec::new();
+        for (kind, message_field) in [(7, 10), (8, 14)] {
+            let frame = read_frame(&mut collector).await;
+            let dnstap = fields(&frame);
+            let Err(message) = field(&dnstap, 14) else {
+                panic!("no message in the frame");
+            };
+            let message = fields(message);
+
+            assert_eq!(field(&message, 1), &Ok(kind));
+            assert_eq!(field(&message, 3), &Ok(1));
+            assert_eq!(field(&message, 5), &Err(vec![127, 0, 0, 1]));
+            assert_eq!(field(&message, 7), &Ok(upstream_addr.port() as u64));
+            let Err(wire) = field(&message, message_field) else {
+                panic!("no dns message in the frame");
+            };
+            let wire = Message::from_vec(wire).unwrap();
+            assert_eq!(wire.queries()[0].name().to_string(), "example.com.");
+            ids.push(wire.id());
+        }
+        // the query as it went out, with the id the response came back with
+        assert_eq!(ids[0], ids[1]);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_sends_frames_after_the_handshake() {
+        let path = std::env::temp_dir().join(format!("bancuh-dnstap-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let target: DnstapTarget = format!("unix:{}", path.display()).parse().unwrap();
+        let dnstap = Dnstap::spawn(target, Some("test".to_string()));
+        let mut collector = collector(&listener).await;
+
+        // a blocked AAAA answer with NXDOMAIN in its header
+        let response = [0, 1, 0x81, 0x83];
+        let exchange = Exchange {
+            peer: Some("10.0.0.1:5353".parse().unwrap()),
+            protocol: Protocol::Udp,
+            query_time: SystemTime::now(),
+            response_time: Some(SystemTime::now()),
+            message: &response,
+        };
+        dnstap.client_response(exchange, Outcome::Blocked, "blocked by ads/bl");
+
+        let frame = read_frame(&mut collector).await;
+        let expected = encode(
+            MessageType::ClientResponse,
+            exchange,
+            Some("test"),
+            Some((Outcome::Blocked, "blocked by ads/bl")),
+        );
+        assert_eq!(frame, expected);
+
+        // identity, then the decision as extra
+        assert!(frame.starts_with(b"\x0a\x04test"));
+        let extra = b"\x1a\x11blocked by ads/bl";
+        assert!(frame.windows(extra.len()).any(|w| w == extra));
+        // Message.type is CLIENT_RESPONSE over UDP, Dnstap.type is MESSAGE
+        assert!(frame.windows(4).any(|w| w == [0x08, 0x06, 0x18, 0x01]));
+        assert!(frame.ends_with(&[0x78, 0x01]));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn it_parses_targets() {
+        assert_eq!(
+            "unix:/run/dnstap.sock".parse::<DnstapTarget>().unwrap(),
+            DnstapTarget::Unix("/run/dnstap.sock".into())
+        );
+        assert_eq!(
+            "tcp:127.0.0.1:6000".parse::<DnstapTarget>().unwrap(),
+            DnstapTarget::Tcp("127.0.0.1:6000".parse().unwrap())
+        );
+        assert!("127.0.0.1:6000".parse::<DnstapTarget>().is_err());
+    }
+}
@@ -1,25 +1,165 @@
-use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
-use arc_swap::ArcSwap;
+use arc_swap::{ArcSwap, ArcSwapOption};
+use chrono::{DateTime, Utc};
+use itertools::Itertools;
 use thiserror::Error;
+use tokio::sync::watch;
 
 use crate::{
-    compiler::AdblockCompiler,
-    config::{Config, FileOrUrl, LoadConfigError},
-    db::AdblockDB,
+    compiler::{AdblockCompiler, SourceReport},
+    config::{Category, Config, FileOrUrl, LoadConfigError, Tag},
+    db::{AdblockDB, DBBackend, DBError},
+    fetch::Fetcher,
 };
 
-async fn load_definition(db: &AdblockDB, config_url: &FileOrUrl) -> Result<(), LoadConfigError> {
+/// Updates and rollbacks kept in the history
+const HISTORY_SIZE: usize = 20;
+
+async fn load_definition(
+    db: &AdblockDB,
+    config_urls: &[FileOrUrl],
+    fetcher: &Fetcher,
+) -> Result<(Vec<SourceReport>, Vec<PathBuf>), LoadConfigError> {
+    let config_url = config_urls.iter().join(", ");
     tracing::info!("Loading adblock config. config_url: {config_url}");
-    let config = Config::load(config_url).await?;
+    let config = Config::load(config_urls).await?;
     let compiler = AdblockCompiler::from_config(&config);
     tracing::info!("Loading adblock config. config_url: {config_url}. DONE");
 
     tracing::info!("Compiling adblock");
-    compiler.compile(db).await;
+    let sources = compiler.compile(db, fetcher).await;
     tracing::info!("Compiling adblock DONE");
 
-    Ok(())
+    Ok((sources, config.local_paths()))
+}
+
+/// Start from the last snapshot when there is one, so blocking applies before the first update
+fn restore_snapshot(backend: DBBackend, snapshot_dir: &Path) -> Option<AdblockDB> {
+    match AdblockDB::open_snapshot(backend, snapshot_dir) {
+        Ok(Some(db)) => {
+            tracing::info!("Restored db snapshot from {}", snapshot_dir.display());
+            Some(db)
+        }
+        Ok(None) => None,
+        Err(err) => {
+            tracing::warn!("Could not restore db snapshot: {err}. Starting with an empty db");
+            None
+        }
+    }
+}
+
+/// Load a config and compile its sources into a new db
+pub async fn compile(
+    config_urls: &[FileOrUrl],
+    fetcher: &Fetcher,
+    backend: DBBackend,
+) -> Result<(AdblockDB, Vec<SourceReport>, Vec<PathBuf>), EngineError> {
+    let db = AdblockDB::create(backend)?;
+    let (sources, local_paths) = load_definition(&db, config_urls, fetcher).await?;
+    db.finish()?;
+
+    Ok((db, sources, local_paths))
+}
+
+/// Whether an attribution tag belongs to a category that is not disabled
+pub fn tag_enabled(tag: &Tag, disabled: &HashSet<Category>) -> bool {
+    !tag.category.is_some_and(|c| disabled.contains(&c))
+}
+
+/// Whether an allow covering `allow` labels beats a block covering `block` labels: the most
+/// specific wins, and an allow wins a tie
+fn allow_wins(allow: usize, block: usize) -> bool {
+    allow >= block
+}
+
+/// A stored name matched by a lookup
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    /// The stored name, `*.example.com.` for a wildcard
+    pub key: String,
+    /// Attribution tags of the categories that are enabled
+    pub tags: Vec<Tag>,
+    labels: usize,
+}
+
+/// How the server answers a name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Allowed,
+    Blocked,
+    Rewritten(String),
+}
+
+impl std::fmt::Display for Verdict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Allowed => write!(f, "allowed"),
+            Self::Blocked => write!(f, "blocked"),
+            Self::Rewritten(alias) => write!(f, "rewritten to {alias}"),
+        }
+    }
+}
+
+/// The stored name a match covering `labels` labels of `name` came from
+fn matched_key(name: &str, labels: usize) -> String {
+    let parts: Vec<_> = name.split('.').filter(|l| !l.is_empty()).collect();
+    if labels >= parts.len() {
+        format!("{}.", parts.join("."))
+    } else {
+        format!("*.{}.", parts[parts.len() - labels..].join("."))
+    }
+}
+
+/// The rules a name matches in each store of a db, to explain and compare verdicts offline
+#[derive(Debug, Clone)]
+pub struct Lookup {
+    pub allow: Option<Rule>,
+    pub block: Option<Rule>,
+    pub rewrite: Option<String>,
+}
+
+impl Lookup {
+    pub fn new(db: &AdblockDB, name: &str, disabled: &HashSet<Category>) -> Result<Self, DBError> {
+        let enabled = |value: &str| {
+            let tags: Vec<_> = Tag::decode(value)
+                .into_iter()
+                .filter(|t| tag_enabled(t, disabled))
+                .collect();
+            (!tags.is_empty()).then_some(tags)
+        };
+        let rule = |(tags, labels)| Rule {
+            key: matched_key(name, labels),
+            tags,
+            labels,
+        };
+
+        Ok(Self {
+            allow: db.whitelist.find(name, enabled)?.map(rule),
+            block: db.blacklist.find(name, enabled)?.map(rule),
+            rewrite: db.rewrites.get(name)?,
+        })
+    }
+
+    /// Rewrites apply first, then the same precedence as `AdblockEngine::is_blocked`
+    pub fn verdict(&self) -> Verdict {
+        if let Some(alias) = &self.rewrite {
+            return Verdict::Rewritten(alias.clone());
+        }
+
+        match (&self.allow, &self.block) {
+            (Some(allow), Some(block)) if allow_wins(allow.labels, block.labels) => {
+                Verdict::Allowed
+            }
+            (_, Some(_)) => Verdict::Blocked,
+            _ => Verdict::Allowed,
+        }
+    }
 }
 
 #[derive(Debug, Error)]
@@ -29,34 +169,329 @@ pub enum EngineError {
 
     #[error(transparent)]
     LoadConfig(#[from] crate::config::LoadConfigError),
+
+    #[error("NoPreviousDb: there is no previous db to roll back to")]
+    NoPreviousDb,
+
+    #[error("UpdateRunning: an update is running")]
+    UpdateRunning,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct CategoryStatus {
+    pub category: Category,
+    pub enabled: bool,
+    pub sources: Vec<SourceReport>,
+}
+
+/// When the last successful update finished, and how long it took
+#[derive(Debug, Clone)]
+pub struct LastUpdate {
+    pub finished_at: DateTime<Utc>,
+    pub duration: Duration,
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpdateKind {
+    Update,
+    Rollback,
+}
+
+/// One update or rollback, as shown in the update history
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct UpdateRecord {
+    pub kind: UpdateKind,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Entries in each store of the db that was swapped in
+    pub entries: BTreeMap<&'static str, usize>,
+    pub sources: Vec<SourceReport>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct UpdateStatus {
+    pub running: bool,
+    pub rollback_available: bool,
+    /// Newest first
+    pub history: Vec<UpdateRecord>,
+}
+
+/// A compiled db with the source reports it was built from
+#[derive(Debug)]
+struct Generation {
+    db: Arc<AdblockDB>,
+    sources: Arc<Vec<SourceReport>>,
+}
+
+fn store_entries(db: &AdblockDB) -> BTreeMap<&'static str, usize> {
+    [
+        ("blacklist", &db.blacklist),
+        ("whitelist", &db.whitelist),
+        ("rewrites", &db.rewrites),
+    ]
+    .into_iter()
+    .map(|(name, store)| (name, store.entries().unwrap_or_default()))
+    .collect()
 }
 
 #[derive(Debug)]
 pub struct AdblockEngine {
     db: Arc<ArcSwap<AdblockDB>>,
-    config_url: FileOrUrl,
+    config_urls: Vec<FileOrUrl>,
+    fetcher: Fetcher,
+    sources: ArcSwap<Vec<SourceReport>>,
+    disabled_categories: ArcSwap<HashSet<Category>>,
+    local_paths: ArcSwap<Vec<PathBuf>>,
+    last_update: ArcSwapOption<LastUpdate>,
+    /// The db replaced by the last update, kept for a rollback
+    previous: ArcSwapOption<Generation>,
+    /// Held while an update or rollback runs
+    updating: tokio::sync::Mutex<()>,
+    history: Mutex<VecDeque<UpdateRecord>>,
+    /// Whether the db was restored from a snapshot at startup
+    restored: bool,
+    /// Zone serial of the effective policy, raised on every swap and category toggle
+    serial: watch::Sender<u32>,
+    snapshot_dir: PathBuf,
+    backend: DBBackend,
 }
 
 impl AdblockEngine {
-    pub fn new(config_url: FileOrUrl) -> Result<Self, EngineError> {
-        let db = Arc::new(ArcSwap::from_pointee(AdblockDB::create()?));
+    pub fn new(
+        config_urls: Vec<FileOrUrl>,
+        fetcher: Fetcher,
+        disabled_categories: HashSet<Category>,
+        snapshot_dir: PathBuf,
+        backend: DBBackend,
+    ) -> Result<Self, EngineError> {
+        let restored = restore_snapshot(backend, &snapshot_dir);
+        let is_restored = restored.is_some();
+        let db = match restored {
+            Some(db) => db,
+            None => AdblockDB::create(backend)?,
+        };
+        let db = Arc::new(ArcSwap::from_pointee(db));
 
-        Ok(Self { db, config_url })
+        Ok(Self {
+            db,
+            config_urls,
+            fetcher,
+            sources: ArcSwap::default(),
+            disabled_categories: ArcSwap::from_pointee(disabled_categories),
+            local_paths: ArcSwap::default(),
+            last_update: ArcSwapOption::empty(),
+            previous: ArcSwapOption::empty(),
+            updating: tokio::sync::Mutex::new(()),
+            history: Mutex::default(),
+            restored: is_restored,
+            serial: watch::Sender::new(Utc::now().timestamp() as u32),
+            snapshot_dir,
+            backend,
+        })
     }
 
     pub async fn run_update(&self) -> Result<(), EngineError> {
-        let config_url = self.config_url.clone();
+        let _updating = self.updating.lock().await;
+        let started_at = Utc::now();
+        let start = Instant::now();
 
-        // instantiate a new_db and load adblock definition into it
-        let new_db = AdblockDB::create()?;
-        load_definition(&new_db, &config_url).await?;
+        let (new_db, sources, local_paths) =
+            match compile(&self.config_urls, &self.fetcher, self.backend).await {
+                Ok(compiled) => compiled,
+                Err(err) => {
+                    self.record(UpdateRecord {
+                        kind: UpdateKind::Update,
+                        started_at,
+                        finished_at: Utc::now(),
+                        success: false,
+                        error: Some(err.to_string()),
+                        entries: BTreeMap::new(),
+                        sources: Vec::new(),
+                    });
+                    return Err(err);
+                }
+            };
 
-        // atomically swap the new_db in place; old_db is dropped here
-        self.db.store(Arc::new(new_db));
+        // atomically swap the new_db in place, keeping the old one for a rollback
+        let entries = store_entries(&new_db);
+        let new_db = Arc::new(new_db);
+        let old = Generation {
+            db: self.db.swap(new_db.clone()),
+            sources: self.sources.swap(Arc::new(sources.clone())),
+        };
+        // an empty db is only the placeholder from before the first update
+        if store_entries(&old.db).values().any(|n| *n > 0) {
+            self.previous.store(Some(Arc::new(old)));
+        }
+        self.bump_serial();
+        self.local_paths.store(Arc::new(local_paths));
+        self.last_update.store(Some(Arc::new(LastUpdate {
+            finished_at: Utc::now(),
+            duration: start.elapsed(),
+        })));
+        self.record(UpdateRecord {
+            kind: UpdateKind::Update,
+            started_at,
+            finished_at: Utc::now(),
+            success: true,
+            error: None,
+            entries,
+            sources,
+        });
 
+        self.save_snapshot(new_db).await;
         Ok(())
     }
 
+    /// Swap the db replaced by the last update back in. The replaced db becomes the
+    /// previous one, so a second rollback undoes the first.
+    pub async fn rollback(&self) -> Result<(), EngineError> {
+        let Ok(_updating) = self.updating.try_lock() else {
+            return Err(EngineError::UpdateRunning);
+        };
+        let started_at = Utc::now();
+        let previous = self.previous.swap(None).ok_or(EngineError::NoPreviousDb)?;
+
+        let current = Generation {
+            db: self.db.swap(previous.db.clone()),
+            sources: self.sources.swap(previous.sources.clone()),
+        };
+        self.previous.store(Some(Arc::new(current)));
+        self.bump_serial();
+        tracing::info!("Rolled back to the previous db");
+        self.record(UpdateRecord {
+            kind: UpdateKind::Rollback,
+            started_at,
+            finished_at: Utc::now(),
+            success: true,
+            error: None,
+            entries: store_entries(&previous.db),
+            sources: previous.sources.to_vec(),
+        });
+
+        self.save_snapshot(previous.db.clone()).await;
+        Ok(())
+    }
+
+    /// Keep `db` as the last known good copy for the next start
+    async fn save_snapshot(&self, db: Arc<AdblockDB>) {
+        let snapshot_dir = self.snapshot_dir.clone();
+        match tokio::task::spawn_blocking(move || db.save_snapshot(&snapshot_dir)).await {
+            Ok(Ok(())) => tracing::info!("Saved db snapshot"),
+            Ok(Err(err)) => tracing::warn!("Could not save db snapshot: {err}"),
+            Err(err) => tracing::warn!("Could not save db snapshot: {err}"),
+        }
+    }
+
+    /// The current time as a serial, or one past the last one when that is not later
+    fn bump_serial(&self) {
+        let now = Utc::now().timestamp() as u32;
+        self.serial
+            .send_modify(|serial| *serial = now.max(serial.wrapping_add(1)));
+    }
+
+    fn record(&self, record: UpdateRecord) {
+        let mut history = self.history.lock().unwrap();
+        if history.len() == HISTORY_SIZE {
+            history.pop_back();
+        }
+        history.push_front(record);
+    }
+
+    pub fn update_status(&self) -> UpdateStatus {
+        let history = self.history.lock().unwrap();
+        UpdateStatus {
+            running: self.updating.try_lock().is_err(),
+            rollback_available: self.previous.load().is_some(),
+            history: history.iter().cloned().collect(),
+        }
+    }
+
+    /// Local config and list files used by the last update
+    pub fn local_paths(&self) -> Arc<Vec<PathBuf>> {
+        self.local_paths.load_full()
+    }
+
+    pub fn last_update(&self) -> Option<Arc<LastUpdate>> {
+        self.last_update.load_full()
+    }
+
+    /// Whether blocking is in force, from a restored snapshot or a finished update
+    pub fn is_loaded(&self) -> bool {
+        self.restored || self.last_update.load().is_some()
+    }
+
+    pub fn db(&self) -> Arc<AdblockDB> {
+        self.db.load_full()
+    }
+
+    /// Zone serial of the effective policy, for exports and zone transfers
+    pub fn serial(&self) -> u32 {
+        *self.serial.borrow()
+    }
+
+    /// Notified whenever the effective policy changes
+    pub fn watch_serial(&self) -> watch::Receiver<u32> {
+        self.serial.subscribe()
+    }
+
+    pub fn disabled_categories(&self) -> Arc<HashSet<Category>> {
+        self.disabled_categories.load_full()
+    }
+
+    /// Entries in each store of the current db
+    pub fn db_entries(&self) -> BTreeMap<&'static str, usize> {
+        store_entries(&self.db.load())
+    }
+
+    /// Pick the first attribution tag whose category is not disabled
+    fn enabled_tag(&self, value: &str) -> Option<String> {
+        let disabled = self.disabled_categories.load();
+
+        Tag::decode(value)
+            .into_iter()
+            .find(|tag| tag_enabled(tag, &disabled))
+            .map(|tag| tag.to_string())
+    }
+
+    pub fn categories(&self) -> Vec<CategoryStatus> {
+        let disabled = self.disabled_categories.load();
+        let sources = self.sources.load();
+
+        Category::ALL
+            .into_iter()
+            .map(|category| CategoryStatus {
+                category,
+                enabled: !disabled.contains(&category),
+                sources: sources
+                    .iter()
+                    .filter(|s| s.category == Some(category))
+                    .cloned()
+                    .collect(),
+            })
+            .collect()
+    }
+
+    pub fn set_category_enabled(&self, category: Category, enabled: bool) {
+        let before = self.disabled_categories.rcu(|disabled| {
+            let mut disabled = HashSet::clone(disabled);
+            if enabled {
+                disabled.remove(&category);
+            } else {
+                disabled.insert(category);
+            }
+            disabled
+        });
+        if before.contains(&category) == enabled {
+            self.bump_serial();
+        }
+        tracing::info!("category {category} enabled: {enabled}");
+    }
+
     pub async fn get_redirect(&self, name: &str) -> Result<Option<String>, EngineError> {
         let db_guard = self.db.load();
         let alias = db_guard.rewrites.get(name)?;
@@ -68,19 +503,231 @@ impl AdblockEngine {
         Ok(alias)
     }
 
-    pub async fn is_blocked(&self, name: &str) -> Result<bool, EngineError> {
+    /// Returns the list blocking `name`, if any.
+    ///
+    /// The most specific match wins: an allow on `ads.example.com` beats a
+    /// block on `*.example.com`, and a block on `ads.example.com` beats an
+    /// allow on `*.example.com`. An allow and a block that are equally
+    /// specific resolve to allow.
+    pub async fn is_blocked(&self, name: &str) -> Result<Option<String>, EngineError> {
         let db_guard = self.db.load();
 
-        if db_guard.whitelist.contains(name)? {
-            tracing::info!("whitelist: {name}");
-            return Ok(false);
+        let allow = db_guard.whitelist.find(name, |t| self.enabled_tag(t))?;
+        let block = db_guard.blacklist.find(name, |t| self.enabled_tag(t))?;
+
+        match (allow, block) {
+            (Some((tag, allow)), Some((_, block))) if allow_wins(allow, block) => {
+                tracing::info!("whitelist: {name} by {tag}");
+                Ok(None)
+            }
+            (_, Some((tag, _))) => {
+                tracing::info!("blacklist: {name} by {tag}");
+                Ok(Some(tag))
+            }
+            (Some((tag, _)), None) => {
+                tracing::info!("whitelist: {name} by {tag}");
+                Ok(None)
+            }
+            (None, None) => Ok(None),
         }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fetch::{FetchLimiter, SourceCache};
+
+    use super::*;
+
+    fn engine(backend: DBBackend, allow: &[&str], block: &[&str]) -> AdblockEngine {
+        let tmp = std::env::temp_dir().join(format!("bancuh-engine-{}", std::process::id()));
+        let fetcher = Fetcher::new(SourceCache::new(tmp.join("cache")), FetchLimiter::new(1, 1));
+        let engine = AdblockEngine::new(
+            Vec::new(),
+            fetcher,
+            HashSet::new(),
+            tmp.join("snapshot"),
+            backend,
+        )
+        .unwrap();
+
+        let db = AdblockDB::create(backend).unwrap();
+        db.whitelist
+            .put_batch(allow.iter().map(|d| (*d, "allow")))
+            .unwrap();
+        db.blacklist
+            .put_batch(block.iter().map(|d| (*d, "block")))
+            .unwrap();
+        db.finish().unwrap();
+        engine.db.store(Arc::new(db));
+
+        engine
+    }
+
+    #[tokio::test]
+    async fn it_prefers_the_most_specific_match() {
+        // (allow, block, query, blocked)
+        let matrix: &[(&[&str], &[&str], &str, bool)] = &[
+            (&[], &[], "example.com", false),
+            (&[], &["example.com"], "example.com", true),
+            (&[], &["example.com"], "ads.example.com", false),
+            (&[], &["*.example.com"], "ads.example.com", true),
+            (&[], &["*.example.com"], "example.com", false),
+            (&["example.com"], &["example.com"], "example.com", false),
+            (
+                &["*.example.com"],
+                &["*.example.com"],
+                "ads.example.com",
+                false,
+            ),
+            (
+                &["ads.example.com"],
+                &["*.example.com"],
+                "ads.example.com",
+                false,
+            ),
+            (
+                &["ads.example.com"],
+                &["*.example.com"],
+                "cdn.example.com",
+                true,
+            ),
+            (
+                &["*.example.com"],
+                &["ads.example.com"],
+                "ads.example.com",
+                true,
+            ),
+            (
+                &["*.example.com"],
+                &["ads.example.com"],
+                "cdn.example.com",
+                false,
+            ),
+            (
+                &["*.example.com"],
+                &["*.ads.example.com"],
+                "x.ads.example.com",
+                true,
+            ),
+            (
+                &["*.x.ads.example.com"],
+                &["*.ads.example.com"],
+                "y.x.ads.example.com",
+                false,
+            ),
+            (&["*.com"], &["*.example.com"], "ads.example.com", true),
+        ];
 
-        if db_guard.blacklist.contains(name)? {
-            tracing::info!("blacklist: {name}");
-            return Ok(true);
+        for backend in [DBBackend::Rocksdb, DBBackend::Fst] {
+            for (allow, block, query, blocked) in matrix {
+                let engine = engine(backend, allow, block);
+                let result = engine.is_blocked(&format!("{query}.")).await.unwrap();
+                assert_eq!(
+                    result.is_some(),
+                    *blocked,
+                    "{backend:?}: allow {allow:?}, block {block:?}, query {query}"
+                );
+            }
         }
+    }
+
+    #[tokio::test]
+    async fn it_rolls_back_to_the_previous_db() {
+        let tmp = std::env::temp_dir().join(format!("bancuh-rollback-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&tmp);
+        std::fs::create_dir_all(&tmp).unwrap();
+        let config = tmp.join("config.yaml");
+        std::fs::write(
+            &config,
+            "blacklist:\n  - format: domains\n    path: ./list.txt\nwhitelist: []\noverrides: []\n",
+        )
+        .unwrap();
+
+        let fetcher = Fetcher::new(SourceCache::new(tmp.join("cache")), FetchLimiter::new(1, 1));
+        let engine = AdblockEngine::new(
+            vec![FileOrUrl::File(config)],
+            fetcher,
+            HashSet::new(),
+            tmp.join("snapshot"),
+            DBBackend::Fst,
+        )
+        .unwrap();
+        assert!(matches!(
+            engine.rollback().await,
+            Err(EngineError::NoPreviousDb)
+        ));
+
+        std::fs::write(tmp.join("list.txt"), "old.example.com\n").unwrap();
+        engine.run_update().await.unwrap();
+        std::fs::write(tmp.join("list.txt"), "new.example.com\n").unwrap();
+        engine.run_update().await.unwrap();
+        assert!(engine
+            .is_blocked("new.example.com.")
+            .await
+            .unwrap()
+            .is_some());
+
+        engine.rollback().await.unwrap();
+        assert!(engine
+            .is_blocked("old.example.com.")
+            .await
+            .unwrap()
+            .is_some());
+        assert!(engine
+            .is_blocked("new.example.com.")
+            .await
+            .unwrap()
+            .is_none());
+
+        let status = engine.update_status();
+        assert!(!status.running);
+        assert!(status.rollback_available);
+        let kinds: Vec<_> = status.history.iter().map(|r| r.kind).collect();
+        assert!(matches!(
+            kinds[..],
+            [UpdateKind::Rollback, UpdateKind::Update, UpdateKind::Update]
+        ));
+        assert_eq!(status.history[0].entries["blacklist"], 1);
+
+        std::fs::remove_dir_all(tmp).unwrap();
+    }
+
+    #[test]
+    fn it_explains_the_verdict() {
+        let engine = engine(DBBackend::Fst, &["ads.example.com"], &["*.example.com"]);
+        let db = engine.db.load();
+
+        let lookup = Lookup::new(&db, "ads.example.com.", &HashSet::new()).unwrap();
+        assert_eq!(lookup.allow.as_ref().unwrap().key, "ads.example.com.");
+        assert_eq!(lookup.block.as_ref().unwrap().key, "*.example.com.");
+        assert_eq!(lookup.verdict(), Verdict::Allowed);
+
+        let lookup = Lookup::new(&db, "cdn.example.com.", &HashSet::new()).unwrap();
+        assert_eq!(lookup.block.as_ref().unwrap().tags, Tag::decode("block"));
+        assert_eq!(lookup.verdict(), Verdict::Blocked);
+    }
+
+    #[test]
+    fn it_attributes_names_with_separators_to_their_category() {
+        let tag = Tag {
+            category: Some(Category::Ads),
+            name: "lists/ads,extra".to_string(),
+        };
+        let db = AdblockDB::create(DBBackend::Fst).unwrap();
+        db.blacklist
+            .put_batch([(
+                "ads.example.com",
+                Tag::encode(std::slice::from_ref(&tag)).as_str(),
+            )])
+            .unwrap();
+        db.finish().unwrap();
+
+        let lookup = Lookup::new(&db, "ads.example.com.", &HashSet::new()).unwrap();
+        assert_eq!(lookup.block.unwrap().tags, [tag]);
 
-        Ok(false)
+        let disabled = HashSet::from([Category::Ads]);
+        let lookup = Lookup::new(&db, "ads.example.com.", &disabled).unwrap();
+        assert_eq!(lookup.verdict(), Verdict::Allowed);
     }
 }
@@ -0,0 +1,187 @@
+mod sinks;
+
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+    },
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use thiserror::Error;
+
+use crate::metrics::Outcome;
+
+pub use self::sinks::{JsonlFile, Stdout, Syslog};
+
+/// Events waiting for the writer, more are dropped rather than slowing down queries
+const QUEUE_SIZE: usize = 10_000;
+
+/// How long written events may sit in buffers before they are flushed
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Error, Debug)]
+pub enum EventsError {
+    #[error(
+        "InvalidSyslogTarget: expected udp:<ip>:<port>, tcp:<ip>:<port> or unix:<path>, got {0}"
+    )]
+    InvalidSyslogTarget(String),
+}
+
+/// One handled query, as exported to the event sinks
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct QueryEvent {
+    pub timestamp: DateTime<Utc>,
+    /// Client as identified by the privacy mode
+    pub client: String,
+    pub transport: &'static str,
+    pub qname: String,
+    pub qtype: String,
+    pub rcode: String,
+    pub answers: Vec<String>,
+    pub decision: Outcome,
+    /// The list that blocked the query
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub list: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream: Option<String>,
+    pub latency_ms: f64,
+}
+
+/// Where a syslog receiver listens
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyslogTarget {
+    Udp(SocketAddr),
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for SyslogTarget {
+    type Err = EventsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || EventsError::InvalidSyslogTarget(s.to_string());
+        match s.split_once(':') {
+            Some(("udp", addr)) => addr.parse().map(Self::Udp).map_err(|_| invalid()),
+            Some(("tcp", addr)) => addr.parse().map(Self::Tcp).map_err(|_| invalid()),
+            Some(("unix", path)) => Ok(Self::Unix(PathBuf::from(path))),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl std::fmt::Display for SyslogTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Udp(addr) => write!(f, "udp:{addr}"),
+            Self::Tcp(addr) => write!(f, "tcp:{addr}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// A destination for query events
+pub trait EventSink: Send {
+    fn name(&self) -> String;
+
+    fn write(&mut self, event: &QueryEvent) -> std::io::Result<()>;
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A sink with its failure state, so a broken one warns once rather than on every event
+struct Output {
+    sink: Box<dyn EventSink>,
+    failing: bool,
+}
+
+impl Output {
+    fn check(&mut self, result: std::io::Result<()>) {
+        match result {
+            Ok(()) if self.failing => {
+                tracing::info!("query events to {} recovered", self.sink.name());
+                self.failing = false;
+            }
+            Err(err) if !self.failing => {
+                tracing::warn!("query events to {} failed: {err}", self.sink.name());
+                self.failing = true;
+            }
+            _ => {}
+        }
+    }
+}
+
+fn run(sinks: Vec<Box<dyn EventSink>>, receiver: Receiver<QueryEvent>) {
+    let mut outputs: Vec<_> = sinks
+        .into_iter()
+        .map(|sink| Output {
+            sink,
+            failing: false,
+        })
+        .collect();
+
+    let mut last_flush = Instant::now();
+    loop {
+        let disconnected = match receiver.recv_timeout(FLUSH_INTERVAL) {
+            Ok(event) => {
+                for output in &mut outputs {
+                    let result = output.sink.write(&event);
+                    output.check(result);
+                }
+                false
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => true,
+        };
+
+        if disconnected || last_flush.elapsed() >= FLUSH_INTERVAL {
+            for output in &mut outputs {
+                let result = output.sink.flush();
+                output.check(result);
+            }
+            last_flush = Instant::now();
+        }
+        if disconnected {
+            return;
+        }
+    }
+}
+
+/// Exports query events to the configured sinks, from a background thread
+#[derive(Debug)]
+pub struct EventLog {
+    sender: SyncSender<QueryEvent>,
+    dropping: AtomicBool,
+}
+
+impl EventLog {
+    pub fn spawn(sinks: Vec<Box<dyn EventSink>>) -> std::io::Result<Self> {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        std::thread::Builder::new()
+            .name("query-events".to_string())
+            .spawn(move || run(sinks, receiver))?;
+
+        Ok(Self {
+            sender,
+            dropping: AtomicBool::new(false),
+        })
+    }
+
+    pub fn send(&self, event: QueryEvent) {
+        match self.sender.try_send(event) {
+            Ok(()) => self.dropping.store(false, Ordering::Relaxed),
+            Err(TrySendError::Full(_)) => {
+                if !self.dropping.swap(true, Ordering::Relaxed) {
+                    tracing::warn!("query event sinks are behind, dropping events");
+                }
+            }
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
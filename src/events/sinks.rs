@@ -0,0 +1,307 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    net::{TcpStream, UdpSocket},
+    os::unix::net::UnixDatagram,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use chrono::SecondsFormat;
+
+use super::{EventSink, QueryEvent, SyslogTarget};
+
+/// Wait before connecting again to a TCP syslog receiver that went away
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// `daemon` facility, `informational` severity
+const SYSLOG_PRIORITY: u8 = 3 * 8 + 6;
+
+/// Events as JSON lines in a file, rotated to `<path>.1`, `<path>.2`, ... when it grows
+/// past `max_bytes`, keeping `keep` rotated files
+pub struct JsonlFile {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    writer: BufWriter<File>,
+    size: u64,
+}
+
+impl JsonlFile {
+    pub fn open(path: PathBuf, max_bytes: u64, keep: usize) -> std::io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path,
+            max_bytes,
+            keep,
+            writer: BufWriter::new(file),
+            size,
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{n}"));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.writer.flush()?;
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let from = self.rotated(n);
+                if from.exists() {
+                    std::fs::rename(from, self.rotated(n + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, self.rotated(1))?;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.writer = BufWriter::new(file);
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl EventSink for JsonlFile {
+    fn name(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn write(&mut self, event: &QueryEvent) -> std::io::Result<()> {
+        if self.max_bytes > 0 && self.size >= self.max_bytes {
+            self.rotate()?;
+        }
+
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        self.writer.write_all(&line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Events as JSON lines on stdout, each written whole under the stdout lock so other
+/// output cannot end up in the middle of one
+pub struct Stdout;
+
+impl EventSink for Stdout {
+    fn name(&self) -> String {
+        "stdout".to_string()
+    }
+
+    fn write(&mut self, event: &QueryEvent) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        std::io::stdout().lock().write_all(&line)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stdout().flush()
+    }
+}
+
+enum Transport {
+    Udp(UdpSocket),
+    Tcp {
+        stream: Option<BufWriter<TcpStream>>,
+        retry_at: Instant,
+    },
+    Unix(UnixDatagram),
+}
+
+/// Events as RFC 5424 syslog messages with the JSON event as message. Over TCP the messages
+/// are framed by octet counting (RFC 6587).
+pub struct Syslog {
+    target: SyslogTarget,
+    transport: Transport,
+    hostname: String,
+}
+
+impl Syslog {
+    pub fn connect(target: SyslogTarget) -> std::io::Result<Self> {
+        let transport = match &target {
+            SyslogTarget::Udp(addr) => {
+                let bind: std::net::SocketAddr = if addr.is_ipv4() {
+                    ([0, 0, 0, 0], 0).into()
+                } else {
+                    ([0u16; 8], 0).into()
+                };
+                let socket = UdpSocket::bind(bind)?;
+                socket.connect(addr)?;
+                Transport::Udp(socket)
+            }
+            SyslogTarget::Tcp(_) => Transport::Tcp {
+                stream: None,
+                retry_at: Instant::now(),
+            },
+            SyslogTarget::Unix(_) => Transport::Unix(UnixDatagram::unbound()?),
+        };
+
+        let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|h| h.trim().to_string())
+            .ok()
+            .filter(|h| !h.is_empty())
+            .unwrap_or_else(|| "-".to_string());
+
+        Ok(Self {
+            target,
+            transport,
+            hostname,
+        })
+    }
+
+    fn format(&self, event: &QueryEvent) -> std::io::Result<Vec<u8>> {
+        let header = format!(
+            "<{SYSLOG_PRIORITY}>1 {timestamp} {hostname} bancuh-dns {pid} query - ",
+            timestamp = event.timestamp.to_rfc3339_opts(SecondsFormat::Micros, true),
+            hostname = self.hostname,
+            pid = std::process::id(),
+        );
+
+        let mut message = header.into_bytes();
+        serde_json::to_writer(&mut message, event)?;
+        Ok(message)
+    }
+}
+
+impl EventSink for Syslog {
+    fn name(&self) -> String {
+        format!("syslog {}", self.target)
+    }
+
+    fn write(&mut self, event: &QueryEvent) -> std::io::Result<()> {
+        let message = self.format(event)?;
+        match &mut self.transport {
+            Transport::Udp(socket) => socket.send(&message).map(|_| ()),
+            Transport::Unix(socket) => {
+                let SyslogTarget::Unix(path) = &self.target else {
+                    unreachable!("unix transport for a unix target");
+                };
+                socket.send_to(&message, path).map(|_| ())
+            }
+            Transport::Tcp { stream, retry_at } => {
+                if stream.is_none() {
+                    // drop events until the receiver can be reached again
+                    if Instant::now() < *retry_at {
+                        return Ok(());
+                    }
+                    let SyslogTarget::Tcp(addr) = &self.target else {
+                        unreachable!("tcp transport for a tcp target");
+                    };
+                    *retry_at = Instant::now() + RECONNECT_DELAY;
+                    let connected = TcpStream::connect_timeout(addr, RECONNECT_DELAY)?;
+                    *stream = Some(BufWriter::new(connected));
+                }
+
+                let Some(writer) = stream else {
+                    return Ok(());
+                };
+                let result =
+                    write!(writer, "{} ", message.len()).and_then(|()| writer.write_all(&message));
+                if result.is_err() {
+                    *stream = None;
+                }
+                result
+            }
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let Transport::Tcp { stream, .. } = &mut self.transport else {
+            return Ok(());
+        };
+        let Some(writer) = stream else {
+            return Ok(());
+        };
+
+        let result = writer.flush();
+        if result.is_err() {
+            *stream = None;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use crate::metrics::Outcome;
+
+    use super::*;
+
+    fn event() -> QueryEvent {
+        QueryEvent {
+            timestamp: Utc.with_ymd_and_hms(2024, 1, 31, 12, 0, 0).unwrap(),
+            client: "10.0.0.1".to_string(),
+            transport: "udp",
+            qname: "ads.example.com.".to_string(),
+            qtype: "A".to_string(),
+            rcode: "NOERROR".to_string(),
+            answers: vec!["ads.example.com. 60 IN A 0.0.0.0".to_string()],
+            decision: Outcome::Blocked,
+            list: Some("ads/easylist".to_string()),
+            upstream: None,
+            latency_ms: 0.25,
+        }
+    }
+
+    #[test]
+    fn it_rotates_the_jsonl_file() {
+        let dir = std::env::temp_dir().join(format!("bancuh-events-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("events.jsonl");
+
+        let mut sink = JsonlFile::open(path.clone(), 200, 2).unwrap();
+        for _ in 0..7 {
+            sink.write(&event()).unwrap();
+        }
+        sink.flush().unwrap();
+
+        // each event is over 200 bytes, so every write after the first rotates
+        assert!(path.exists());
+        assert!(dir.join("events.jsonl.1").exists());
+        assert!(dir.join("events.jsonl.2").exists());
+        assert!(!dir.join("events.jsonl.3").exists());
+
+        let line = std::fs::read_to_string(&path).unwrap();
+        let value: serde_json::Value = serde_json::from_str(line.trim()).unwrap();
+        assert_eq!(value["decision"], "blocked");
+        assert_eq!(value["list"], "ads/easylist");
+        assert!(value.get("upstream").is_none());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn it_formats_rfc5424_over_udp() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let target = SyslogTarget::Udp(receiver.local_addr().unwrap());
+        let mut sink = Syslog::connect(target).unwrap();
+        sink.write(&event()).unwrap();
+
+        let mut buf = [0; 2048];
+        let n = receiver.recv(&mut buf).unwrap();
+        let message = std::str::from_utf8(&buf[..n]).unwrap();
+        assert!(message.starts_with("<30>1 2024-01-31T12:00:00.000000Z "));
+        let (header, json) = message.split_once(" - ").unwrap();
+        assert!(header.ends_with(&format!("bancuh-dns {} query", std::process::id())));
+        assert!(json.starts_with("{\"timestamp\""));
+    }
+}
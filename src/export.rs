@@ -0,0 +1,296 @@
+use std::{collections::HashSet, io::Write};
+
+use chrono::Utc;
+use thiserror::Error;
+
+use crate::{
+    config::Category,
+    db::{AdblockDB, DBError},
+    engine::{Lookup, Verdict},
+};
+
+/// Zone name of RPZ exports when none is given
+pub const DEFAULT_RPZ_ZONE: &str = "rpz.bancuh.";
+
+/// TTL of the records in RPZ zones
+pub const RPZ_TTL: u32 = 300;
+
+/// SOA refresh, retry, expire and minimum of RPZ zones
+pub const RPZ_SOA_TIMERS: [u32; 4] = [3600, 600, 86400, 300];
+
+#[derive(Error, Debug)]
+pub enum ExportError {
+    #[error(transparent)]
+    DB(#[from] DBError),
+
+    #[error("IOError: {0}")]
+    IOError(#[from] std::io::Error),
+}
+
+/// Syntax of an exported list
+#[derive(clap::ValueEnum, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// `0.0.0.0 example.com` lines, without wildcards, exceptions or rewrites
+    Hosts,
+    /// One name per line, `*.example.com` for wildcards, without exceptions or rewrites
+    Domains,
+    /// An RPZ zone file, with exceptions as `rpz-passthru.` and rewrites as CNAMEs
+    Rpz,
+    /// AdGuard / ABP style rules, with `@@` exceptions and `$dnsrewrite` rewrites
+    Abp,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Rpz => "text/dns",
+            _ => "text/plain; charset=utf-8",
+        }
+    }
+
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            Self::Hosts => "bancuh.hosts",
+            Self::Domains => "bancuh.domains.txt",
+            Self::Rpz => "bancuh.rpz.zone",
+            Self::Abp => "bancuh.abp.txt",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExportOptions {
+    pub format: ExportFormat,
+    /// Origin of an RPZ zone
+    pub zone: String,
+    /// SOA serial of an RPZ zone
+    pub serial: u32,
+    pub disabled: HashSet<Category>,
+}
+
+/// The effect of a stored name on the answers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Policy {
+    Block,
+    /// Allowed although a less specific rule blocks it
+    Allow,
+    Rewrite(String),
+}
+
+impl Policy {
+    /// `None` for a name that is answered as if it was not stored. A block rule for the name
+    /// itself that is allowed is left out rather than excepted.
+    pub fn of(name: &str, lookup: &Lookup) -> Option<Self> {
+        let blocked_below = lookup
+            .block
+            .as_ref()
+            .is_some_and(|rule| rule.key.trim_end_matches('.') != name.trim_end_matches('.'));
+        match lookup.verdict() {
+            Verdict::Blocked => Some(Self::Block),
+            Verdict::Rewritten(alias) => Some(Self::Rewrite(alias)),
+            Verdict::Allowed if blocked_below => Some(Self::Allow),
+            Verdict::Allowed => None,
+        }
+    }
+}
+
+/// Every name stored in any of the stores, once, with its policy. Names are taken from each
+/// store in turn, skipping those already stored in an earlier one.
+pub fn policies<'a>(
+    db: &'a AdblockDB,
+    disabled: &'a HashSet<Category>,
+) -> impl Iterator<Item = Result<(String, Policy), DBError>> + 'a {
+    let stores = [&db.blacklist, &db.whitelist, &db.rewrites];
+
+    stores.into_iter().enumerate().flat_map(move |(i, store)| {
+        store.iter().filter_map(move |entry| {
+            let name = match entry {
+                Ok((name, _)) => name,
+                Err(err) => return Some(Err(err)),
+            };
+            for earlier in &stores[..i] {
+                match earlier.contains(&name) {
+                    Ok(true) => return None,
+                    Ok(false) => {}
+                    Err(err) => return Some(Err(err)),
+                }
+            }
+
+            let lookup = match Lookup::new(db, &name, disabled) {
+                Ok(lookup) => lookup,
+                Err(err) => return Some(Err(err)),
+            };
+            Policy::of(&name, &lookup).map(|policy| Ok((name, policy)))
+        })
+    })
+}
+
+/// The policy of `name` as `policies` has it, `None` when it is not stored or has no effect
+pub fn policy_of(
+    db: &AdblockDB,
+    name: &str,
+    disabled: &HashSet<Category>,
+) -> Result<Option<Policy>, DBError> {
+    for store in [&db.blacklist, &db.whitelist, &db.rewrites] {
+        if store.contains(name)? {
+            let lookup = Lookup::new(db, name, disabled)?;
+            return Ok(Policy::of(name, &lookup));
+        }
+    }
+    Ok(None)
+}
+
+/// The RPZ action for a policy: NXDOMAIN, passthru or a CNAME to the alias
+pub fn rpz_target(policy: &Policy) -> String {
+    match policy {
+        Policy::Block => ".".to_string(),
+        Policy::Allow => "rpz-passthru.".to_string(),
+        Policy::Rewrite(alias) => format!("{}.", alias.trim_end_matches('.')),
+    }
+}
+
+/// A rule in `format`, `None` when the format cannot express it
+fn rule(format: ExportFormat, name: &str, policy: &Policy) -> Option<String> {
+    let name = name.trim_end_matches('.');
+    let wildcard = name.starts_with("*.");
+    // AdGuard patterns: `|` anchors the start of the name and `^` its end
+    let pattern = match name.strip_prefix("*.") {
+        Some(parent) => format!("||*.{parent}^"),
+        None => format!("|{name}^"),
+    };
+
+    match (format, policy) {
+        (ExportFormat::Hosts, Policy::Block) if !wildcard => Some(format!("0.0.0.0 {name}")),
+        (ExportFormat::Domains, Policy::Block) => Some(name.to_string()),
+        (ExportFormat::Rpz, _) => Some(format!("{name} CNAME {}", rpz_target(policy))),
+        (ExportFormat::Abp, Policy::Block) => Some(pattern),
+        (ExportFormat::Abp, Policy::Allow) => Some(format!("@@{pattern}")),
+        (ExportFormat::Abp, Policy::Rewrite(alias)) => Some(format!(
+            "{pattern}$dnsrewrite=NOERROR;CNAME;{}",
+            alias.trim_end_matches('.')
+        )),
+        _ => None,
+    }
+}
+
+fn header(options: &ExportOptions) -> String {
+    let generated = format!("Generated by bancuh-dns at {}", Utc::now().to_rfc3339());
+    match options.format {
+        ExportFormat::Hosts | ExportFormat::Domains => format!("# {generated}\n"),
+        ExportFormat::Abp => format!("! {generated}\n"),
+        ExportFormat::Rpz => {
+            let zone = format!("{}.", options.zone.trim_end_matches('.'));
+            let [refresh, retry, expire, minimum] = RPZ_SOA_TIMERS;
+            format!(
+                "; {generated}\n\
+                 $ORIGIN {zone}\n\
+                 $TTL {RPZ_TTL}\n\
+                 @ SOA localhost. hostmaster.localhost. {serial} {refresh} {retry} {expire} {minimum}\n\
+                 @ NS localhost.\n",
+                serial = options.serial,
+            )
+        }
+    }
+}
+
+/// Write the effective policy of `db` in the chosen format, returning the number of rules
+/// written and of those the format could not express
+pub fn export(
+    db: &AdblockDB,
+    options: &ExportOptions,
+    out: &mut impl Write,
+) -> Result<(usize, usize), ExportError> {
+    out.write_all(header(options).as_bytes())?;
+
+    let (mut written, mut skipped) = (0, 0);
+    for entry in policies(db, &options.disabled) {
+        let (name, policy) = entry?;
+        match rule(options.format, &name, &policy) {
+            Some(rule) => {
+                writeln!(out, "{rule}")?;
+                written += 1;
+            }
+            None => skipped += 1,
+        }
+    }
+
+    if skipped > 0 {
+        let comment = if options.format == ExportFormat::Rpz {
+            ";"
+        } else if options.format == ExportFormat::Abp {
+            "!"
+        } else {
+            "#"
+        };
+        writeln!(
+            out,
+            "{comment} {skipped} rules cannot be expressed in this format and were left out"
+        )?;
+    }
+    out.flush()?;
+
+    Ok((written, skipped))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::DBBackend;
+
+    use super::*;
+
+    #[test]
+    fn it_exports_the_effective_policy() {
+        let db = AdblockDB::create(DBBackend::Fst).unwrap();
+        db.blacklist
+            .put_batch([
+                ("ads.example.com", "ads/a"),
+                ("*.tracker.net", "trackers/t"),
+                ("adult.example.org", "adult/x"),
+            ])
+            .unwrap();
+        db.whitelist
+            .put_batch([("ok.tracker.net", "allow"), ("ads.example.com", "allow")])
+            .unwrap();
+        db.rewrites
+            .put_alias_batch([("search.example.com", "safe.example.com")])
+            .unwrap();
+        db.finish().unwrap();
+
+        let export = |format| {
+            let options = ExportOptions {
+                format,
+                zone: DEFAULT_RPZ_ZONE.to_string(),
+                serial: 1,
+                disabled: HashSet::from([Category::Adult]),
+            };
+            let mut out = Vec::new();
+            export(&db, &options, &mut out).unwrap();
+            let out = String::from_utf8(out).unwrap();
+            out.lines()
+                .filter(|l| !l.starts_with(['#', '!', ';', '$']) && !l.starts_with("@ "))
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+
+        // the allowed ads.example.com and the disabled adult list are left out
+        assert_eq!(export(ExportFormat::Domains), ["*.tracker.net"]);
+        assert!(export(ExportFormat::Hosts).is_empty());
+        assert_eq!(
+            export(ExportFormat::Rpz),
+            [
+                "*.tracker.net CNAME .",
+                "ok.tracker.net CNAME rpz-passthru.",
+                "search.example.com CNAME safe.example.com."
+            ]
+        );
+        assert_eq!(
+            export(ExportFormat::Abp),
+            [
+                "||*.tracker.net^",
+                "@@|ok.tracker.net^",
+                "|search.example.com^$dnsrewrite=NOERROR;CNAME;safe.example.com"
+            ]
+        );
+    }
+}
@@ -0,0 +1,403 @@
+use std::{path::PathBuf, time::Duration};
+
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+
+use super::{
+    http::{ConditionalResponse, FetchHttp, Validators},
+    Fetch, FetchError,
+};
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct CacheMeta {
+    /// Entries written before sources were keyed by path too call this `url`
+    #[serde(alias = "url")]
+    key: String,
+    validators: Validators,
+    fetched_at: DateTime<Utc>,
+
+    /// Number of entries the source compiled to when it was committed
+    #[serde(default)]
+    entries: Option<usize>,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    meta: CacheMeta,
+    body: Vec<u8>,
+}
+
+/// Where a fetched body came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    /// Newly downloaded or read, not yet checked or committed
+    Fresh,
+    /// The server answered 304, this is the committed copy
+    NotModified,
+    /// The fetch failed, this is the committed copy
+    Cached,
+}
+
+#[derive(Debug)]
+pub struct Fetched {
+    pub raw: Vec<u8>,
+    pub origin: Origin,
+    /// Entry count of the last committed copy, if any
+    pub previous_entries: Option<usize>,
+    validators: Validators,
+}
+
+/// On-disk cache of remote list sources, keyed by url.
+///
+/// Each entry keeps the last good body together with its `ETag`/`Last-Modified`
+/// validators, so updates can be conditional and a failed fetch can fall back
+/// to the last cached copy. Fresh bodies only replace the cached copy once
+/// they are committed, after passing the source's integrity checks.
+#[derive(Debug)]
+pub struct SourceCache {
+    dir: PathBuf,
+}
+
+impl SourceCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn entry_paths(&self, key: &str) -> (PathBuf, PathBuf) {
+        let key = format!("{:x}", Sha256::digest(key));
+        let meta = self.dir.join(format!("{key}.json"));
+        let body = self.dir.join(format!("{key}.body"));
+        (meta, body)
+    }
+
+    async fn read(&self, key: &str) -> Option<CacheEntry> {
+        let (meta_path, body_path) = self.entry_paths(key);
+        let meta = tokio::fs::read_to_string(meta_path).await.ok()?;
+        let meta: CacheMeta = serde_json::from_str(&meta).ok()?;
+        let body = tokio::fs::read(body_path).await.ok()?;
+
+        Some(CacheEntry { meta, body })
+    }
+
+    async fn write(&self, entry: &CacheEntry) -> std::io::Result<()> {
+        let (meta_path, body_path) = self.entry_paths(&entry.meta.key);
+        let meta = serde_json::to_string(&entry.meta)?;
+
+        // write to temp files first, so a crash never leaves a torn entry behind
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let meta_tmp = meta_path.with_extension("json.tmp");
+        let body_tmp = body_path.with_extension("body.tmp");
+        tokio::fs::write(&body_tmp, &entry.body).await?;
+        tokio::fs::write(&meta_tmp, meta).await?;
+        tokio::fs::rename(body_tmp, body_path).await?;
+        tokio::fs::rename(meta_tmp, meta_path).await?;
+
+        Ok(())
+    }
+
+    /// Rewrite the meta of an entry, e.g. to mark it fetched again after a 304
+    async fn write_meta(&self, meta: &CacheMeta) -> std::io::Result<()> {
+        let (meta_path, _) = self.entry_paths(&meta.key);
+        let meta_tmp = meta_path.with_extension("json.tmp");
+        tokio::fs::write(&meta_tmp, serde_json::to_string(meta)?).await?;
+        tokio::fs::rename(meta_tmp, meta_path).await?;
+
+        Ok(())
+    }
+
+    /// The cached copy of a source. Entries of zip members from before the member was part
+    /// of the key are stored under the archive url, and are still found there.
+    /// Local files are read directly and never cached.
+    async fn lookup(&self, fetch: &Fetch) -> Option<CacheEntry> {
+        let Fetch::Http(p) = fetch else {
+            return None;
+        };
+        if let Some(entry) = self.read(&fetch.cache_key()).await {
+            return Some(entry);
+        }
+        match &p.member {
+            Some(_) => self.read(p.url.as_str()).await,
+            None => None,
+        }
+    }
+
+    fn from_cached(cached: CacheEntry, origin: Origin) -> Fetched {
+        Fetched {
+            raw: cached.body,
+            origin,
+            previous_entries: cached.meta.entries,
+            validators: cached.meta.validators,
+        }
+    }
+
+    async fn fetch_http(
+        &self,
+        fetch: &FetchHttp,
+        cached: Option<CacheEntry>,
+    ) -> Result<Fetched, FetchError> {
+        let validators = cached
+            .as_ref()
+            .map(|c| c.meta.validators.clone())
+            .unwrap_or_default();
+        let previous_entries = cached.as_ref().and_then(|c| c.meta.entries);
+
+        match fetch.fetch_conditional(&validators).await {
+            Ok(ConditionalResponse::Modified { body, validators }) => Ok(Fetched {
+                raw: body,
+                origin: Origin::Fresh,
+                previous_entries,
+                validators,
+            }),
+            Ok(ConditionalResponse::NotModified) => match cached {
+                Some(mut cached) => {
+                    tracing::info!("Source not modified, using cached copy: {}", fetch.url);
+                    // the copy is current as of now, so `update_interval` counts from this fetch
+                    cached.meta.fetched_at = Utc::now();
+                    if let Err(err) = self.write_meta(&cached.meta).await {
+                        tracing::warn!("Could not write source cache for {}: {err}", fetch.url);
+                    }
+                    Ok(Self::from_cached(cached, Origin::NotModified))
+                }
+                // a 304 without a cached copy should not happen, refetch unconditionally
+                None => Ok(Fetched {
+                    raw: fetch.fetch().await?,
+                    origin: Origin::Fresh,
+                    previous_entries,
+                    validators: Validators::default(),
+                }),
+            },
+            Err(err) => match cached {
+                Some(cached) => {
+                    tracing::warn!(
+                        "Fetch failed for {}: {err}. Falling back to cached copy from {}",
+                        fetch.url,
+                        cached.meta.fetched_at
+                    );
+                    Ok(Self::from_cached(cached, Origin::Cached))
+                }
+                None => Err(err.into()),
+            },
+        }
+    }
+
+    /// Fetch the raw body of a source. Remote sources are fetched conditionally,
+    /// and fall back to the cached copy when the fetch fails. A remote source
+    /// cached less than `max_age` ago is not fetched at all. Local files are
+    /// always read as they are.
+    pub async fn fetch(
+        &self,
+        fetch: &Fetch,
+        max_age: Option<Duration>,
+    ) -> Result<Fetched, FetchError> {
+        match fetch {
+            Fetch::Http(p) => match (self.lookup(fetch).await, max_age) {
+                (Some(cached), Some(max_age)) if cached.meta.fetched_at + max_age > Utc::now() => {
+                    tracing::info!("Source cached recently, skipping fetch: {}", p.url);
+                    Ok(Self::from_cached(cached, Origin::NotModified))
+                }
+                (cached, _) => self.fetch_http(p, cached).await,
+            },
+            Fetch::File(p) => Ok(Fetched {
+                raw: p.fetch().await?,
+                origin: Origin::Fresh,
+                previous_entries: None,
+                validators: Validators::default(),
+            }),
+        }
+    }
+
+    /// The last committed copy of a source, used when a fresh copy is rejected
+    pub async fn fallback(&self, fetch: &Fetch) -> Option<Fetched> {
+        let cached = self.lookup(fetch).await?;
+        Some(Self::from_cached(cached, Origin::Cached))
+    }
+
+    /// Replace the entry count the next fresh copy of a source is compared against
+    pub async fn lower_baseline(&self, fetch: &Fetch, entries: usize) {
+        let Some(mut cached) = self.lookup(fetch).await else {
+            return;
+        };
+        cached.meta.entries = Some(entries);
+        if let Err(err) = self.write_meta(&cached.meta).await {
+            tracing::warn!(
+                "Could not write source cache for {}: {err}",
+                cached.meta.key
+            );
+        }
+    }
+
+    /// Keep a fresh body as the last good copy of its source
+    pub async fn commit(&self, fetch: &Fetch, fetched: Fetched, entries: usize) {
+        if fetched.origin != Origin::Fresh || !matches!(fetch, Fetch::Http(_)) {
+            return;
+        }
+
+        let entry = CacheEntry {
+            meta: CacheMeta {
+                key: fetch.cache_key(),
+                validators: fetched.validators,
+                fetched_at: Utc::now(),
+                entries: Some(entries),
+            },
+            body: fetched.raw,
+        };
+        if let Err(err) = self.write(&entry).await {
+            tracing::warn!("Could not write source cache for {}: {err}", entry.meta.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        task::JoinHandle,
+    };
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("bancuh-cache-{}-{name}", std::process::id()))
+    }
+
+    /// Answer one request per response on a local port, returning the request heads
+    async fn serve(responses: Vec<&'static str>) -> (url::Url, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hosts.txt", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut head = Vec::new();
+                let mut buf = [0; 1024];
+                while !head.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    head.extend_from_slice(&buf[..n]);
+                }
+                requests.push(String::from_utf8_lossy(&head).to_lowercase());
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+            requests
+        });
+
+        (url::Url::parse(&url).unwrap(), server)
+    }
+
+    async fn seed(cache: &SourceCache, fetch: &Fetch, fetched_at: &str) {
+        let entry = CacheEntry {
+            meta: CacheMeta {
+                key: fetch.cache_key(),
+                validators: Validators {
+                    etag: Some("\"v1\"".to_string()),
+                    last_modified: Some("Mon, 01 Jan 2024 00:00:00 GMT".to_string()),
+                },
+                fetched_at: fetched_at.parse().unwrap(),
+                entries: Some(1),
+            },
+            body: b"0.0.0.0 cached.example.com".to_vec(),
+        };
+        cache.write(&entry).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_sends_validators_and_reuses_the_cached_copy_on_304() {
+        let dir = temp_dir("304");
+        let cache = SourceCache::new(&dir);
+        let (url, server) = serve(vec![
+            "HTTP/1.1 304 Not Modified\r\nconnection: close\r\n\r\n",
+        ])
+        .await;
+        let fetch = Fetch::from(url);
+        seed(&cache, &fetch, "2024-01-01T00:00:00Z").await;
+
+        let fetched = cache.fetch(&fetch, None).await.unwrap();
+        assert_eq!(fetched.origin, Origin::NotModified);
+        assert_eq!(fetched.raw, b"0.0.0.0 cached.example.com");
+        assert_eq!(fetched.previous_entries, Some(1));
+
+        let requests = server.await.unwrap();
+        assert!(requests[0].contains("if-none-match: \"v1\"\r\n"));
+        assert!(requests[0].contains("if-modified-since: mon, 01 jan 2024 00:00:00 gmt\r\n"));
+
+        // the 304 counts as a fetch, so the source is not due again within its interval
+        let fetched = cache
+            .fetch(&fetch, Some(Duration::from_secs(3600)))
+            .await
+            .unwrap();
+        assert_eq!(fetched.origin, Origin::NotModified);
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_falls_back_to_the_cached_copy_when_a_fetch_fails() {
+        let dir = temp_dir("fallback");
+        let cache = SourceCache::new(&dir);
+        let (url, server) = serve(vec![
+            "HTTP/1.1 500 Internal Server Error\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
+            5
+        ])
+        .await;
+        let fetch = Fetch::from(url);
+        seed(&cache, &fetch, "2024-01-01T00:00:00Z").await;
+
+        let fetched = cache.fetch(&fetch, None).await.unwrap();
+        assert_eq!(fetched.origin, Origin::Cached);
+        assert_eq!(fetched.raw, b"0.0.0.0 cached.example.com");
+        assert_eq!(server.await.unwrap().len(), 5);
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_keeps_a_lowered_baseline_for_the_next_fetch() {
+        let dir = temp_dir("baseline");
+        let cache = SourceCache::new(&dir);
+        let fetch = Fetch::from(url::Url::parse("https://example.com/hosts.txt").unwrap());
+        seed(&cache, &fetch, "2024-01-01T00:00:00Z").await;
+
+        cache.lower_baseline(&fetch, 0).await;
+        let fetched = cache.fallback(&fetch).await.unwrap();
+        assert_eq!(fetched.previous_entries, Some(0));
+        assert_eq!(fetched.raw, b"0.0.0.0 cached.example.com");
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_does_not_cache_local_files() {
+        let dir = temp_dir("file");
+        let cache = SourceCache::new(&dir);
+        let path = dir.join("hosts.txt");
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        tokio::fs::write(&path, b"0.0.0.0 file.example.com")
+            .await
+            .unwrap();
+        let fetch = Fetch::from(path.clone());
+
+        let fetched = cache.fetch(&fetch, None).await.unwrap();
+        assert_eq!(fetched.origin, Origin::Fresh);
+        cache.commit(&fetch, fetched, 1).await;
+        assert!(cache.read(&fetch.cache_key()).await.is_none());
+
+        tokio::fs::remove_file(&path).await.unwrap();
+        assert!(cache.fetch(&fetch, None).await.is_err());
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_finds_entries_cached_before_keys_had_members() {
+        let dir = temp_dir("members");
+        let cache = SourceCache::new(&dir);
+        let fetch = Fetch::from(url::Url::parse("https://example.com/lists.zip#hosts").unwrap());
+
+        // the meta of an old entry, keyed by the archive url alone
+        let (meta, body) = cache.entry_paths("https://example.com/lists.zip");
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let old = r#"{"url":"https://example.com/lists.zip","validators":{},"fetched_at":"2024-01-01T00:00:00Z"}"#;
+        tokio::fs::write(meta, old).await.unwrap();
+        tokio::fs::write(body, b"archive").await.unwrap();
+
+        let fetched = cache.fallback(&fetch).await.unwrap();
+        assert_eq!(fetched.raw, b"archive");
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
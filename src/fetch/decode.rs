@@ -0,0 +1,222 @@
+use std::io::{Cursor, Read};
+
+use thiserror::Error;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const XZ_MAGIC: &[u8] = &[0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00];
+const ZIP_MAGIC: &[u8] = &[0x50, 0x4b, 0x03, 0x04];
+
+/// Guards against archives nested inside archives forever
+const MAX_DEPTH: usize = 4;
+
+/// Largest body a source may decompress to, a small bomb would otherwise exhaust memory
+pub(super) const MAX_DECOMPRESSED: u64 = 256 * 1024 * 1024;
+
+/// Bytes looked at to tell text from an unknown binary format
+const SNIFF_LEN: usize = 1024;
+
+#[derive(Error, Debug)]
+pub enum DecodeError {
+    #[error("IOError: {0}")]
+    IOError(#[from] std::io::Error),
+
+    #[error("ZipError: {0}")]
+    ZipError(#[from] zip::result::ZipError),
+
+    #[error("MissingMember: zip archive has {0} files, select one with path#member")]
+    MissingMember(usize),
+
+    #[error("TooDeep")]
+    TooDeep,
+
+    #[error("TooLarge: decompresses to more than {0} bytes")]
+    TooLarge(u64),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Compression {
+    Gzip,
+    Zstd,
+    Xz,
+    Zip,
+    None,
+}
+
+fn detect(bytes: &[u8], name: &str) -> Compression {
+    if bytes.starts_with(GZIP_MAGIC) {
+        return Compression::Gzip;
+    }
+    if bytes.starts_with(ZSTD_MAGIC) {
+        return Compression::Zstd;
+    }
+    if bytes.starts_with(XZ_MAGIC) {
+        return Compression::Xz;
+    }
+    if bytes.starts_with(ZIP_MAGIC) {
+        return Compression::Zip;
+    }
+
+    // text, like a body reqwest already decoded from `Content-Encoding: gzip`, is plain
+    // whatever its name. Only an unknown binary body falls back to the extension.
+    let binary = bytes
+        .iter()
+        .take(SNIFF_LEN)
+        .any(|b| *b < 0x20 && !b"\t\n\r\x0c".contains(b));
+    let name = name.to_lowercase();
+    if !binary {
+        Compression::None
+    } else if name.ends_with(".gz") {
+        Compression::Gzip
+    } else if name.ends_with(".zst") {
+        Compression::Zstd
+    } else if name.ends_with(".xz") {
+        Compression::Xz
+    } else if name.ends_with(".zip") {
+        Compression::Zip
+    } else {
+        Compression::None
+    }
+}
+
+fn read_member(bytes: Vec<u8>, member: Option<&str>) -> Result<Vec<u8>, DecodeError> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(bytes))?;
+    let mut file = match member {
+        Some(member) => archive.by_name(member)?,
+        None if archive.len() == 1 => archive.by_index(0)?,
+        None => return Err(DecodeError::MissingMember(archive.len())),
+    };
+
+    read_limited(&mut file, MAX_DECOMPRESSED)
+}
+
+/// Read a decoder to the end, failing past `limit` bytes
+fn read_limited(reader: impl Read, limit: u64) -> Result<Vec<u8>, DecodeError> {
+    let mut out = Vec::new();
+    reader.take(limit + 1).read_to_end(&mut out)?;
+    if out.len() as u64 > limit {
+        return Err(DecodeError::TooLarge(limit));
+    }
+    Ok(out)
+}
+
+/// Decode a fetched body into text.
+///
+/// Gzip, zstd and xz bodies are decompressed, detected by magic bytes, or by the
+/// extension of `name` for a binary body without any. Zip archives yield `member`,
+/// or their only file.
+pub fn decode(bytes: Vec<u8>, name: &str, member: Option<&str>) -> Result<String, DecodeError> {
+    let mut bytes = bytes;
+    let mut name = name.to_string();
+    let mut member = member;
+
+    for _ in 0..MAX_DEPTH {
+        bytes = match detect(&bytes, &name) {
+            Compression::Gzip => {
+                name = name.trim_end_matches(".gz").to_string();
+                read_limited(
+                    flate2::read::MultiGzDecoder::new(bytes.as_slice()),
+                    MAX_DECOMPRESSED,
+                )?
+            }
+            Compression::Zstd => {
+                name = name.trim_end_matches(".zst").to_string();
+                read_limited(
+                    zstd::stream::read::Decoder::new(bytes.as_slice())?,
+                    MAX_DECOMPRESSED,
+                )?
+            }
+            Compression::Xz => {
+                name = name.trim_end_matches(".xz").to_string();
+                read_limited(
+                    xz2::read::XzDecoder::new(bytes.as_slice()),
+                    MAX_DECOMPRESSED,
+                )?
+            }
+            Compression::Zip => {
+                name = member.unwrap_or_default().to_string();
+                read_member(bytes, member.take())?
+            }
+            Compression::None => {
+                return Ok(String::from_utf8_lossy(&bytes).into_owned());
+            }
+        };
+    }
+
+    Err(DecodeError::TooDeep)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    const BODY: &str = "0.0.0.0 ads.example.com\n0.0.0.0 tracker.example.com\n";
+
+    fn gzip(input: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(input).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, content) in files {
+            writer
+                .start_file(*name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            writer.write_all(content).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn it_decodes_plain_and_compressed() {
+        let plain = decode(BODY.as_bytes().to_vec(), "hosts.txt", None).unwrap();
+        assert_eq!(plain, BODY);
+
+        let gz = decode(gzip(BODY.as_bytes()), "hosts", None).unwrap();
+        assert_eq!(gz, BODY);
+
+        let zst = zstd::encode_all(BODY.as_bytes(), 0).unwrap();
+        assert_eq!(decode(zst, "hosts", None).unwrap(), BODY);
+
+        let mut xz = Vec::new();
+        xz2::read::XzEncoder::new(BODY.as_bytes(), 6)
+            .read_to_end(&mut xz)
+            .unwrap();
+        assert_eq!(decode(xz, "hosts", None).unwrap(), BODY);
+    }
+
+    #[test]
+    fn it_selects_zip_member() {
+        let gz = gzip(BODY.as_bytes());
+        let archive = zip(&[("README", b"readme"), ("lists/hosts.gz", &gz)]);
+
+        let output = decode(archive.clone(), "lists.zip", Some("lists/hosts.gz")).unwrap();
+        assert_eq!(output, BODY);
+
+        let output = decode(archive, "lists.zip", None);
+        assert!(matches!(output, Err(DecodeError::MissingMember(2))));
+
+        let single = zip(&[("hosts", BODY.as_bytes())]);
+        assert_eq!(decode(single, "lists.zip", None).unwrap(), BODY);
+    }
+
+    #[test]
+    fn it_trusts_the_body_over_the_extension() {
+        // reqwest already undid `Content-Encoding: gzip`
+        assert_eq!(
+            decode(BODY.as_bytes().to_vec(), "hosts.gz", None).unwrap(),
+            BODY
+        );
+
+        let bomb = gzip(&[b'a'; 4096]);
+        let decoder = flate2::read::GzDecoder::new(bomb.as_slice());
+        assert!(matches!(
+            read_limited(decoder, 4095),
+            Err(DecodeError::TooLarge(4095))
+        ));
+    }
+}
@@ -1,10 +1,11 @@
-use std::{fs::read_to_string, path::PathBuf};
+use std::path::PathBuf;
 
 use thiserror::Error;
 
 #[derive(Debug)]
 pub struct FetchFile {
     pub path: PathBuf,
+    pub member: Option<String>,
 }
 
 #[derive(Error, Debug)]
@@ -14,8 +15,8 @@ pub enum FetchFileError {
 }
 
 impl FetchFile {
-    pub async fn fetch(&self) -> Result<String, FetchFileError> {
-        let contents = read_to_string(&self.path)?;
+    pub async fn fetch(&self) -> Result<Vec<u8>, FetchFileError> {
+        let contents = tokio::fs::read(&self.path).await?;
         Ok(contents)
     }
 }
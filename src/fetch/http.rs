@@ -1,12 +1,19 @@
 use std::time::Duration;
 
 use lazy_static::lazy_static;
+use reqwest::{
+    header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    StatusCode,
+};
 use thiserror::Error;
 use url::Url;
 
+use super::decode::MAX_DECOMPRESSED;
+
 #[derive(Debug)]
 pub struct FetchHttp {
     pub url: Url,
+    pub member: Option<String>,
 }
 
 #[derive(Error, Debug)]
@@ -14,12 +21,45 @@ pub enum FetchHTTPError {
     #[error("HTTPError: {0}")]
     HTTPError(#[from] reqwest::Error),
 
+    #[error("TooLarge: body is more than {0} bytes")]
+    TooLarge(u64),
+
     #[error("Unknown")]
     Unknown,
 }
 
+/// Cache validators returned by the server, sent back on the next fetch
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ConditionalResponse {
+    NotModified,
+    Modified {
+        body: Vec<u8>,
+        validators: Validators,
+    },
+}
+
+/// Read a response body, failing past `limit` bytes. reqwest already undoes any
+/// `Content-Encoding`, so this bounds the decoded body, not just the transfer.
+async fn read_body(mut response: reqwest::Response, limit: u64) -> Result<Vec<u8>, FetchHTTPError> {
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if (body.len() + chunk.len()) as u64 > limit {
+            return Err(FetchHTTPError::TooLarge(limit));
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(body)
+}
+
 impl FetchHttp {
-    pub async fn fetch(&self) -> Result<String, FetchHTTPError> {
+    async fn send(&self, validators: &Validators) -> Result<reqwest::Response, FetchHTTPError> {
         lazy_static! {
             static ref CLIENT: reqwest::Client = reqwest::Client::builder()
                 .connect_timeout(Duration::from_secs(5))
@@ -30,14 +70,21 @@ impl FetchHttp {
 
         let mut last_error: FetchHTTPError = FetchHTTPError::Unknown;
         for i in 1..=5 {
-            match CLIENT.get(self.url.to_string()).send().await {
+            let mut request = CLIENT.get(self.url.to_string());
+            if let Some(etag) = &validators.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &validators.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+
+            match request.send().await.and_then(|r| r.error_for_status()) {
                 Ok(response) => {
-                    let text = response.text().await?;
-                    println!("Fetch ok: {}, attempt: {}", &self.url, i);
-                    return Ok(text);
+                    tracing::info!("Fetch ok: {}, attempt: {}", &self.url, i);
+                    return Ok(response);
                 }
                 Err(e) => {
-                    println!("Fetch err: {}, attempt: {}", &self.url, i);
+                    tracing::warn!("Fetch err: {}, attempt: {}: {e}", &self.url, i);
                     last_error = e.into();
                 }
             }
@@ -45,4 +92,85 @@ impl FetchHttp {
 
         Err(last_error)
     }
+
+    pub async fn fetch(&self) -> Result<Vec<u8>, FetchHTTPError> {
+        let response = self.send(&Validators::default()).await?;
+        read_body(response, MAX_DECOMPRESSED).await
+    }
+
+    /// Fetch with `If-None-Match`/`If-Modified-Since` set from `validators`
+    pub async fn fetch_conditional(
+        &self,
+        validators: &Validators,
+    ) -> Result<ConditionalResponse, FetchHTTPError> {
+        let response = self.send(validators).await?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(ConditionalResponse::NotModified);
+        }
+
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string())
+        };
+        let validators = Validators {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        };
+        let body = read_body(response, MAX_DECOMPRESSED).await?;
+
+        Ok(ConditionalResponse::Modified { body, validators })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn it_limits_bodies_after_content_decoding() {
+        // 64 KiB of zeros, which gzip shrinks to well under the limit
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+        encoder.write_all(&[0; 64 * 1024]).unwrap();
+        let encoded = encoder.finish().unwrap();
+        assert!(encoded.len() < 1024);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hosts.txt", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut head = Vec::new();
+            let mut buf = [0; 1024];
+            while !head.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                head.extend_from_slice(&buf[..n]);
+            }
+            let mut response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-encoding: gzip\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                encoded.len()
+            )
+            .into_bytes();
+            response.extend_from_slice(&encoded);
+            stream.write_all(&response).await.unwrap();
+        });
+
+        let fetch = FetchHttp {
+            url: Url::parse(&url).unwrap(),
+            member: None,
+        };
+        let response = fetch.send(&Validators::default()).await.unwrap();
+        assert!(matches!(
+            read_body(response, 1024).await,
+            Err(FetchHTTPError::TooLarge(1024))
+        ));
+    }
 }
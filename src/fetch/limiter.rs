@@ -0,0 +1,90 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use super::{Fetch, FetchError, Fetched, SourceCache};
+
+/// Bounds concurrent downloads, overall and per remote host
+#[derive(Debug)]
+pub struct FetchLimiter {
+    global: Arc<Semaphore>,
+    per_host: usize,
+    hosts: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl FetchLimiter {
+    pub fn new(concurrency: usize, per_host: usize) -> Self {
+        Self {
+            global: Arc::new(Semaphore::new(concurrency.max(1))),
+            per_host: per_host.max(1),
+            hosts: Mutex::default(),
+        }
+    }
+
+    fn host_semaphore(&self, host: &str) -> Arc<Semaphore> {
+        let mut hosts = self.hosts.lock().unwrap();
+        hosts
+            .entry(host.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.per_host)))
+            .clone()
+    }
+
+    /// Wait for a free slot for this source. Local files are not limited.
+    async fn acquire(&self, fetch: &Fetch) -> Vec<OwnedSemaphorePermit> {
+        let Fetch::Http(p) = fetch else {
+            return Vec::new();
+        };
+
+        let host = p.url.host_str().unwrap_or_default();
+        let host_semaphore = self.host_semaphore(host);
+
+        // take the host slot first, so queued sources for a busy host don't hold global slots
+        let host_permit = host_semaphore.acquire_owned().await.expect("never closed");
+        let global_permit = self
+            .global
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("never closed");
+
+        vec![host_permit, global_permit]
+    }
+}
+
+/// Fetches list sources through the source cache, within the download limits
+#[derive(Debug)]
+pub struct Fetcher {
+    cache: SourceCache,
+    limiter: FetchLimiter,
+}
+
+impl Fetcher {
+    pub fn new(cache: SourceCache, limiter: FetchLimiter) -> Self {
+        Self { cache, limiter }
+    }
+
+    pub async fn fetch(
+        &self,
+        fetch: &Fetch,
+        max_age: Option<Duration>,
+    ) -> Result<Fetched, FetchError> {
+        let _permits = self.limiter.acquire(fetch).await;
+        self.cache.fetch(fetch, max_age).await
+    }
+
+    pub async fn fallback(&self, fetch: &Fetch) -> Option<Fetched> {
+        self.cache.fallback(fetch).await
+    }
+
+    pub async fn lower_baseline(&self, fetch: &Fetch, entries: usize) {
+        self.cache.lower_baseline(fetch, entries).await
+    }
+
+    pub async fn commit(&self, fetch: &Fetch, fetched: Fetched, entries: usize) {
+        self.cache.commit(fetch, fetched, entries).await
+    }
+}
@@ -1,12 +1,21 @@
+mod cache;
+mod decode;
 mod file;
 mod http;
+mod limiter;
 
 use std::path::PathBuf;
 
 use thiserror::Error;
 use url::Url;
 
+pub use self::{
+    cache::{Fetched, Origin, SourceCache},
+    limiter::{FetchLimiter, Fetcher},
+};
+
 use self::{
+    decode::{decode, DecodeError},
     file::{FetchFile, FetchFileError},
     http::{FetchHTTPError, FetchHttp},
 };
@@ -24,25 +33,110 @@ pub enum FetchError {
 
     #[error("FileError: {0}")]
     FileError(#[from] FetchFileError),
+
+    #[error("DecodeError: {0}")]
+    Decode(#[from] DecodeError),
+
+    #[error("JoinError: {0}")]
+    Join(#[from] tokio::task::JoinError),
+}
+
+/// Split a `path.zip#member` source path into the archive path and the member inside it.
+/// Other paths are left whole, a `#` is a valid character in a file name.
+pub fn split_member(path: &str) -> (&str, Option<&str>) {
+    match path.rsplit_once('#') {
+        Some((archive, member))
+            if !member.is_empty() && archive.to_lowercase().ends_with(".zip") =>
+        {
+            (archive, Some(member))
+        }
+        _ => (path, None),
+    }
 }
 
 impl Fetch {
-    pub async fn fetch(&self) -> Result<String, FetchError> {
+    fn name(&self) -> String {
         match self {
-            Fetch::Http(p) => Ok(p.fetch().await?),
-            Fetch::File(p) => Ok(p.fetch().await?),
+            Fetch::Http(p) => p.url.path().to_string(),
+            Fetch::File(p) => p.path.to_string_lossy().to_string(),
+        }
+    }
+
+    fn member(&self) -> Option<String> {
+        match self {
+            Fetch::Http(p) => p.member.clone(),
+            Fetch::File(p) => p.member.clone(),
+        }
+    }
+
+    /// Identifies this source in the source cache
+    pub fn cache_key(&self) -> String {
+        let (location, member) = match self {
+            Fetch::Http(p) => (p.url.to_string(), &p.member),
+            Fetch::File(p) => (format!("file://{}", p.path.display()), &p.member),
+        };
+
+        match member {
+            Some(member) => format!("{location}#{member}"),
+            None => location,
         }
     }
+
+    /// Decompress and unpack a raw body on a blocking thread
+    pub async fn decode(&self, bytes: Vec<u8>) -> Result<String, FetchError> {
+        let name = self.name();
+        let member = self.member();
+        let text =
+            tokio::task::spawn_blocking(move || decode(bytes, &name, member.as_deref())).await??;
+
+        Ok(text)
+    }
+
+    pub async fn fetch(&self) -> Result<String, FetchError> {
+        let bytes = match self {
+            Fetch::Http(p) => p.fetch().await?,
+            Fetch::File(p) => p.fetch().await?,
+        };
+
+        self.decode(bytes).await
+    }
 }
 
 impl From<Url> for Fetch {
-    fn from(url: Url) -> Self {
-        Self::Http(FetchHttp { url })
+    fn from(mut url: Url) -> Self {
+        let member = url.fragment().map(|f| f.to_string());
+        url.set_fragment(None);
+
+        Self::Http(FetchHttp { url, member })
     }
 }
 
 impl From<PathBuf> for Fetch {
     fn from(path: PathBuf) -> Self {
-        Self::File(FetchFile { path })
+        let path_str = path.to_string_lossy().to_string();
+        let (path, member) = match split_member(&path_str) {
+            (path, Some(member)) => (PathBuf::from(path), Some(member.to_string())),
+            (_, None) => (path, None),
+        };
+
+        Self::File(FetchFile { path, member })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_splits_members_of_zip_paths_only() {
+        assert_eq!(
+            split_member("./lists.ZIP#hosts.txt"),
+            ("./lists.ZIP", Some("hosts.txt"))
+        );
+        assert_eq!(
+            split_member("./lists #1/hosts.txt"),
+            ("./lists #1/hosts.txt", None)
+        );
+        assert_eq!(split_member("./c#.txt"), ("./c#.txt", None));
     }
 }
@@ -1,28 +1,38 @@
 use std::{
-    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     sync::Arc,
+    time::{Instant, SystemTime},
 };
 
+use arc_swap::ArcSwap;
 use chrono::Utc;
 
 use hickory_resolver::{
     proto::rr::{
         rdata::{A, AAAA, CNAME},
-        RData, Record,
+        RData, Record, RecordType,
     },
     Name,
 };
 use hickory_server::{
     authority::MessageResponseBuilder,
-    proto::op::{Header, MessageType, OpCode, ResponseCode},
+    proto::{
+        op::{Header, Message, MessageType, OpCode, ResponseCode},
+        serialize::binary::BinEncodable,
+    },
     server::{Request, RequestHandler, ResponseHandler, ResponseInfo},
 };
 
 use crate::{
+    dnstap::{Dnstap, Exchange},
     engine::AdblockEngine,
+    events::{EventLog, QueryEvent},
+    health::{HEALTHCHECK_ADDR, HEALTHCHECK_NAME},
+    metrics::{rcode_label, transport_label, type_label, Metrics, Outcome},
     query_log::{QueryLog, QueryLogStore},
     rate_limiter::{mask_ip, RateLimiter},
-    resolver::Resolver,
+    resolver::{for_query, Resolver},
+    stats::StatsStore,
 };
 
 #[derive(Debug, thiserror::Error)]
@@ -74,43 +84,65 @@ impl From<crate::engine::EngineError> for HandlerError {
     }
 }
 
+/// Handler settings that can be swapped in live on reload
+#[derive(Debug)]
+pub struct HandlerSettings {
+    pub resolver: Resolver,
+    pub rate_limiter: Option<RateLimiter>,
+    pub rate_limit_ipv4_prefix: u8,
+    pub rate_limit_ipv6_prefix: u8,
+}
+
+/// A query that was answered, and how
+struct Handled {
+    info: ResponseInfo,
+    /// Answer classification, e.g. `blocked by ads/easylist`
+    answer: String,
+    outcome: Outcome,
+    records: Vec<Record>,
+    /// The list that blocked the query
+    list: Option<String>,
+}
+
 /// DNS Request Handler
 pub struct Handler {
     engine: Arc<AdblockEngine>,
-    resolver: Resolver,
     query_log: Arc<QueryLogStore>,
-    rate_limiter: Option<Arc<RateLimiter>>,
-    rate_limit_ipv4_prefix: u8,
-    rate_limit_ipv6_prefix: u8,
+    settings: Arc<ArcSwap<HandlerSettings>>,
+    metrics: Arc<Metrics>,
+    stats: Arc<StatsStore>,
+    dnstap: Option<Arc<Dnstap>>,
+    events: Option<Arc<EventLog>>,
 }
 
 impl Handler {
     pub fn new(
         engine: Arc<AdblockEngine>,
-        resolver: Resolver,
         query_log: Arc<QueryLogStore>,
-        rate_limiter: Option<Arc<RateLimiter>>,
-        rate_limit_ipv4_prefix: u8,
-        rate_limit_ipv6_prefix: u8,
+        settings: Arc<ArcSwap<HandlerSettings>>,
+        metrics: Arc<Metrics>,
+        stats: Arc<StatsStore>,
+        dnstap: Option<Arc<Dnstap>>,
+        events: Option<Arc<EventLog>>,
     ) -> Self {
         Self {
             engine,
-            resolver,
             query_log,
-            rate_limiter,
-            rate_limit_ipv4_prefix,
-            rate_limit_ipv6_prefix,
+            settings,
+            metrics,
+            stats,
+            dnstap,
+            events,
         }
     }
 }
 
 impl Handler {
-    /// Returns (ResponseInfo, question_string, answer_classification)
     async fn do_handle_request<R: ResponseHandler>(
         &self,
         request: &Request,
         responder: &mut R,
-    ) -> Result<(ResponseInfo, String, String), HandlerError> {
+    ) -> Result<Handled, HandlerError> {
         // make sure the request is a query
         if request.op_code() != OpCode::Query {
             return Err(HandlerError::refused("Unsupported OpCode"));
@@ -122,8 +154,8 @@ impl Handler {
         }
 
         let request_info = request.request_info().map_err(HandlerError::serv_fail)?;
+        let settings = self.settings.load_full();
         let name = request_info.query.name();
-        let question = format!("{} {}", name, request_info.query.query_type());
 
         // check engine for domain override redirection
         if let Some(alias) = self.engine.get_redirect(&name.to_string()).await? {
@@ -136,18 +168,24 @@ impl Handler {
             records.push(record);
 
             // fetch records from forward resolver using the alias and return them
-            let alias_records = self
+            let alias_records = settings
                 .resolver
                 .lookup(&alias, request_info.query.query_type())
                 .await?;
             records.extend(alias_records);
 
             let info = self.send_response(request, responder, &records).await?;
-            return Ok((info, question, format!("rewritten: {alias}")));
+            return Ok(Handled {
+                info,
+                answer: format!("rewritten: {alias}"),
+                outcome: Outcome::Rewritten,
+                records,
+                list: None,
+            });
         }
 
         // check engine if domain is blocked
-        if self.engine.is_blocked(&name.to_string()).await? {
+        if let Some(list) = self.engine.is_blocked(&name.to_string()).await? {
             match request_info.query.query_type() {
                 hickory_resolver::proto::rr::RecordType::A => {
                     let ipv4_null_addr = Ipv4Addr::new(0, 0, 0, 0);
@@ -156,7 +194,13 @@ impl Handler {
                     let records = vec![record];
 
                     let info = self.send_response(request, responder, &records).await?;
-                    return Ok((info, question, "blocked".to_string()));
+                    return Ok(Handled {
+                        info,
+                        answer: format!("blocked by {list}"),
+                        outcome: Outcome::Blocked,
+                        records,
+                        list: Some(list),
+                    });
                 }
                 hickory_resolver::proto::rr::RecordType::AAAA => {
                     let ipv6_null_addr = Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0);
@@ -165,19 +209,44 @@ impl Handler {
                     let records = vec![record];
 
                     let info = self.send_response(request, responder, &records).await?;
-                    return Ok((info, question, "blocked".to_string()));
+                    return Ok(Handled {
+                        info,
+                        answer: format!("blocked by {list}"),
+                        outcome: Outcome::Blocked,
+                        records,
+                        list: Some(list),
+                    });
+                }
+                _ => {
+                    let header = Header::response_from_request(request.header());
+                    let response = MessageResponseBuilder::from_message_request(request)
+                        .error_msg(&header, ResponseCode::NXDomain);
+
+                    let info = responder.send_response(response).await?;
+                    return Ok(Handled {
+                        info,
+                        answer: format!("blocked by {list}"),
+                        outcome: Outcome::Blocked,
+                        records: Vec::new(),
+                        list: Some(list),
+                    });
                 }
-                _ => return Err(HandlerError::nx_domain(name.to_string())),
             }
         }
 
         // fetch records from forward resolver and return them
-        let records = self
+        let records = settings
             .resolver
             .lookup(&name.to_string(), request_info.query.query_type())
             .await?;
         let info = self.send_response(request, responder, &records).await?;
-        Ok((info, question, "forwarded".to_string()))
+        Ok(Handled {
+            info,
+            answer: "forwarded".to_string(),
+            outcome: Outcome::Forwarded,
+            records,
+            list: None,
+        })
     }
 
     /// build header and return response
@@ -198,9 +267,85 @@ impl Handler {
 
         Ok(responder.send_response(response).await?)
     }
+
+    /// Answer the synthetic healthcheck name locally
+    async fn answer_healthcheck<R: ResponseHandler>(
+        &self,
+        request: &Request,
+        responder: &mut R,
+        name: Name,
+        query_type: RecordType,
+    ) -> ResponseInfo {
+        let mut records = Vec::new();
+        if query_type == RecordType::A {
+            records.push(Record::from_rdata(name, 0, RData::A(A(HEALTHCHECK_ADDR))));
+        }
+
+        match self.send_response(request, responder, &records).await {
+            Ok(info) => info,
+            Err(_) => {
+                let mut header = Header::new();
+                header.set_response_code(ResponseCode::ServFail);
+                header.into()
+            }
+        }
+    }
+
+    /// The exported event for a handled query, none when the privacy mode leaves out its client
+    fn query_event(
+        &self,
+        request: &Request,
+        client: IpAddr,
+        started: Instant,
+        handled: &Handled,
+        upstream: Option<SocketAddr>,
+    ) -> Option<QueryEvent> {
+        let client = self.query_log.privacy().client_id(client)?;
+        let query = request.queries().first();
+
+        Some(QueryEvent {
+            timestamp: Utc::now(),
+            client,
+            transport: transport_label(request.protocol()),
+            qname: query.map(|q| q.name().to_string()).unwrap_or_default(),
+            qtype: type_label(query.map(|q| q.query_type())),
+            rcode: rcode_label(handled.info.response_code()),
+            answers: handled.records.iter().map(Record::to_string).collect(),
+            decision: handled.outcome,
+            list: handled.list.clone(),
+            upstream: upstream.map(|u| u.to_string()),
+            latency_ms: started.elapsed().as_secs_f64() * 1000.0,
+        })
+    }
+}
+
+/// Emit the response sent to the client to dnstap, rebuilt from what went into it
+fn tap_response(
+    dnstap: &Dnstap,
+    request: &Request,
+    peer: Option<SocketAddr>,
+    query_time: SystemTime,
+    handled: &Handled,
+) {
+    let mut message = Message::new();
+    message
+        .set_header(Header::response_from_request(request.header()))
+        .set_response_code(handled.info.response_code())
+        .add_queries(request.queries().iter().map(|q| q.original().clone()))
+        .add_answers(handled.records.iter().cloned());
+    let message = message.to_vec().unwrap_or_default();
+
+    let exchange = Exchange {
+        peer,
+        protocol: request.protocol(),
+        query_time,
+        response_time: Some(SystemTime::now()),
+        message: &message,
+    };
+    dnstap.client_response(exchange, handled.outcome, &handled.answer);
 }
 
-fn normalize_ip(addr: std::net::SocketAddr) -> IpAddr {
+fn normalize_ip(addr: SocketAddr) -> IpAddr {
     match addr.ip() {
         IpAddr::V6(v6) => {
             // Convert IPv4-mapped IPv6 (::ffff:x.x.x.x) back to IPv4
@@ -221,51 +366,151 @@ impl RequestHandler for Handler {
         request: &Request,
         mut responder: R,
     ) -> ResponseInfo {
+        let started = Instant::now();
         let src_ip = normalize_ip(request.src());
+        let query = request.queries().first();
+        let question = query
+            .map(|q| format!("{} {}", q.name(), q.query_type()))
+            .unwrap_or_default();
+        let query_type = type_label(query.map(|q| q.query_type()));
+        let transport = transport_label(request.protocol());
+
+        // healthchecks stay out of the rate limits, logs and stats
+        if let Some(q) = query.filter(|q| q.name().to_string() == HEALTHCHECK_NAME) {
+            return self
+                .answer_healthcheck(request, &mut responder, q.name().into(), q.query_type())
+                .await;
+        }
 
         // Rate limiting check — silently drop to avoid backscatter from spoofed IPs
-        let rate_key = mask_ip(
-            src_ip,
-            self.rate_limit_ipv4_prefix,
-            self.rate_limit_ipv6_prefix,
-        );
-        if self
-            .rate_limiter
-            .as_ref()
-            .is_some_and(|rl| rl.check_key(&rate_key).is_err())
-        {
+        let rate_limited = {
+            let settings = self.settings.load();
+            let rate_key = mask_ip(
+                src_ip,
+                settings.rate_limit_ipv4_prefix,
+                settings.rate_limit_ipv6_prefix,
+            );
+            settings
+                .rate_limiter
+                .as_ref()
+                .is_some_and(|rl| rl.check_key(&rate_key).is_err())
+        };
+        if rate_limited {
             tracing::warn!("rate limited (dropped): {src_ip}");
+            self.metrics
+                .record_query(query_type, transport, Outcome::RateLimited);
+            self.query_log.insert(
+                src_ip,
+                QueryLog {
+                    query_time: Utc::now(),
+                    question,
+                    answer: "dropped by rate limit".to_string(),
+                    outcome: Outcome::RateLimited,
+                    rcode: None,
+                    latency_ms: started.elapsed().as_secs_f64() * 1000.0,
+                    transport: transport.to_string(),
+                    query_id: request.id(),
+                    upstream: None,
+                },
+            );
             let mut header = Header::new();
             header.set_response_code(ResponseCode::Refused);
             return header.into();
         }
 
-        match self.do_handle_request(request, &mut responder).await {
-            Ok((info, question, answer)) => {
-                self.query_log.insert(
-                    src_ip,
-                    QueryLog {
-                        query_time: Utc::now(),
-                        question,
-                        answer,
-                    },
-                );
-                info
-            }
+        let query_time = SystemTime::now();
+        // opted out clients stay out of dnstap, the others only as far as the privacy mode allows
+        let privacy = self.query_log.privacy();
+        let tap = self
+            .dnstap
+            .as_deref()
+            .filter(|_| !privacy.is_opted_out(src_ip))
+            .map(|dnstap| {
+                let peer = SocketAddr::new(src_ip, request.src().port());
+                (dnstap, privacy.tap_peer(peer))
+            });
+        if let Some((dnstap, peer)) = tap {
+            let message = request.to_bytes().unwrap_or_default();
+            dnstap.client_query(Exchange {
+                peer,
+                protocol: request.protocol(),
+                query_time,
+                response_time: None,
+                message: &message,
+            });
+        }
+
+        // the upstream that answered, none for local answers and failures that never got one
+        let (result, upstream) = for_query(
+            tap.is_some(),
+            self.do_handle_request(request, &mut responder),
+        )
+        .await;
+        let handled = match result {
+            Ok(handled) => handled,
             Err(err) => {
+                // an NXDomain from upstream is still a forwarded answer
+                let outcome = if err.0 == ResponseCode::NXDomain {
+                    Outcome::Forwarded
+                } else {
+                    Outcome::Error
+                };
                 let header = Header::response_from_request(request.header());
                 let response =
                     MessageResponseBuilder::from_message_request(request).error_msg(&header, err.0);
 
-                match responder.send_response(response).await {
+                let info = match responder.send_response(response).await {
                     Ok(ok) => ok,
                     Err(_) => {
                         let mut header = Header::new();
                         header.set_response_code(ResponseCode::ServFail);
                         header.into()
                     }
+                };
+                let answer = match outcome {
+                    Outcome::Forwarded => "forwarded".to_string(),
+                    _ => err.1,
+                };
+                Handled {
+                    info,
+                    answer,
+                    outcome,
+                    records: Vec::new(),
+                    list: None,
                 }
             }
+        };
+
+        if let Some(query) = query {
+            let blocked = handled.outcome == Outcome::Blocked;
+            self.stats
+                .record(src_ip, &query.name().to_string(), blocked);
+        }
+        self.metrics
+            .record_query(query_type, transport, handled.outcome);
+        if let Some((dnstap, peer)) = tap {
+            tap_response(dnstap, request, peer, query_time, &handled);
+        }
+        if let Some(events) = &self.events {
+            if let Some(event) = self.query_event(request, src_ip, started, &handled, upstream) {
+                events.send(event);
+            }
         }
+
+        self.query_log.insert(
+            src_ip,
+            QueryLog {
+                query_time: Utc::now(),
+                question,
+                answer: handled.answer,
+                outcome: handled.outcome,
+                rcode: Some(rcode_label(handled.info.response_code())),
+                latency_ms: started.elapsed().as_secs_f64() * 1000.0,
+                transport: transport.to_string(),
+                query_id: request.id(),
+                upstream: upstream.map(|u| u.to_string()),
+            },
+        );
+        handled.info
     }
 }
@@ -0,0 +1,207 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use arc_swap::ArcSwap;
+use chrono::{DateTime, Utc};
+use hickory_server::proto::{
+    op::{Message, MessageType, OpCode, Query, ResponseCode},
+    rr::{rdata::A, Name, RData, RecordType},
+    ProtoError,
+};
+use thiserror::Error;
+
+use crate::{engine::AdblockEngine, handler::HandlerSettings, metrics::Metrics};
+
+/// Synthetic name the handler answers itself, so a self-query does not depend on upstream DNS
+pub const HEALTHCHECK_NAME: &str = "healthcheck.bancuh-dns.internal.";
+
+/// The address in the answer to `HEALTHCHECK_NAME`
+pub const HEALTHCHECK_ADDR: Ipv4Addr = Ipv4Addr::LOCALHOST;
+
+#[derive(Error, Debug)]
+pub enum HealthError {
+    #[error("IOError: {0}")]
+    IOError(#[from] std::io::Error),
+
+    #[error("ProtoError: {0}")]
+    Proto(#[from] ProtoError),
+
+    #[error("Timeout: no answer from {0}")]
+    Timeout(SocketAddr),
+
+    #[error("UnexpectedAnswer: {0}")]
+    UnexpectedAnswer(String),
+}
+
+/// The outcome of one readiness check
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct Check {
+    pub name: &'static str,
+    pub ok: bool,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str, result: Result<String, String>) -> Self {
+        let (ok, detail) = match result {
+            Ok(detail) => (true, detail),
+            Err(detail) => (false, detail),
+        };
+        Self { name, ok, detail }
+    }
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: Vec<Check>,
+}
+
+/// What the server needs before it can serve queries as configured
+#[derive(Debug)]
+pub struct Health {
+    engine: Arc<AdblockEngine>,
+    settings: Arc<ArcSwap<HandlerSettings>>,
+    metrics: Arc<Metrics>,
+    tls_enabled: bool,
+    listening: AtomicBool,
+}
+
+impl Health {
+    pub fn new(
+        engine: Arc<AdblockEngine>,
+        settings: Arc<ArcSwap<HandlerSettings>>,
+        metrics: Arc<Metrics>,
+        tls_enabled: bool,
+    ) -> Self {
+        Self {
+            engine,
+            settings,
+            metrics,
+            tls_enabled,
+            listening: AtomicBool::new(false),
+        }
+    }
+
+    /// Mark the DNS listeners as bound
+    pub fn set_listening(&self) {
+        self.listening.store(true, Ordering::Relaxed);
+    }
+
+    pub async fn readiness(&self) -> Readiness {
+        let db = match self.engine.last_update() {
+            Some(update) => Ok(format!("updated at {}", update.finished_at)),
+            None if self.engine.is_loaded() => Ok("restored from snapshot".to_string()),
+            None => Err("waiting for the first update".to_string()),
+        };
+
+        let resolver = self.settings.load_full().resolver.probe().await;
+        let resolver = resolver
+            .map(|()| "upstream answers".to_string())
+            .map_err(|err| err.to_string());
+
+        let listeners = if self.listening.load(Ordering::Relaxed) {
+            Ok("bound".to_string())
+        } else {
+            Err("not bound yet".to_string())
+        };
+
+        let mut checks = vec![
+            Check::new("db", db),
+            Check::new("resolver", resolver),
+            Check::new("listeners", listeners),
+        ];
+        if self.tls_enabled {
+            checks.push(Check::new("certificate", self.certificate()));
+        }
+
+        Readiness {
+            ready: checks.iter().all(|c| c.ok),
+            checks,
+        }
+    }
+
+    fn certificate(&self) -> Result<String, String> {
+        let expiry = self
+            .metrics
+            .cert_expiry()
+            .ok_or_else(|| "no certificate yet".to_string())?;
+        let expiry = DateTime::<Utc>::from_timestamp(expiry, 0)
+            .ok_or_else(|| format!("invalid expiry {expiry}"))?;
+
+        if expiry > Utc::now() {
+            Ok(format!("valid until {expiry}"))
+        } else {
+            Err(format!("expired at {expiry}"))
+        }
+    }
+}
+
+/// Where a local healthcheck reaches the DNS listener bound to `ip`
+pub fn local_addr(ip: IpAddr, port: u16) -> SocketAddr {
+    let ip = match ip {
+        IpAddr::V4(v4) if v4.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(v6) if v6.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    SocketAddr::new(ip, port)
+}
+
+/// Query `HEALTHCHECK_NAME` over UDP and check the answer
+pub async fn self_query(addr: SocketAddr, timeout: Duration) -> Result<(), HealthError> {
+    let bind: SocketAddr = if addr.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = tokio::net::UdpSocket::bind(bind).await?;
+    socket.connect(addr).await?;
+
+    let id = rand::random();
+    let mut query = Message::new();
+    query
+        .set_id(id)
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .add_query(Query::query(
+            Name::from_ascii(HEALTHCHECK_NAME)?,
+            RecordType::A,
+        ));
+    socket.send(&query.to_vec()?).await?;
+
+    let mut buf = [0; 512];
+    let response = loop {
+        let n = tokio::time::timeout(timeout, socket.recv(&mut buf))
+            .await
+            .map_err(|_| HealthError::Timeout(addr))??;
+        let response = Message::from_vec(&buf[..n])?;
+        // a stray datagram is not the answer to this query
+        if response.id() == id {
+            break response;
+        }
+    };
+
+    if response.response_code() != ResponseCode::NoError {
+        return Err(HealthError::UnexpectedAnswer(
+            response.response_code().to_string(),
+        ));
+    }
+    let answered = response
+        .answers()
+        .iter()
+        .any(|r| r.data() == &RData::A(A(HEALTHCHECK_ADDR)));
+    if !answered {
+        return Err(HealthError::UnexpectedAnswer(format!(
+            "{:?}",
+            response.answers()
+        )));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,2 @@
+//! The parts of bancuh-dns that are shared with the benches
+pub mod db;
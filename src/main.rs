@@ -1,50 +1,87 @@
 mod admin;
 mod bind;
+mod cli;
 mod compiler;
 mod config;
-mod db;
+mod dnstap;
 mod engine;
+mod events;
+mod export;
 mod fetch;
 mod handler;
+mod health;
+mod metrics;
 mod net;
+mod privacy;
 mod query_log;
 mod rate_limiter;
+mod reload;
 mod resolver;
+mod rpz;
+mod settings;
+mod stats;
 mod tls;
 
 use std::{
     net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
     sync::Arc,
     time::Duration,
 };
 
-use clap::Parser;
+use arc_swap::ArcSwap;
+use bancuh_dns::db;
+use clap::{CommandFactory, FromArgMatches};
 use hickory_server::ServerFuture;
-use itertools::Itertools;
-use tokio::signal::unix::{signal, SignalKind};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::Notify,
+};
 use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
 use crate::{
+    admin::{AdminState, OperatorAuth},
     bind::spawn_bind,
-    config::{Config, FileOrUrl},
+    config::{Category, Config, FileOrUrl},
+    db::DBBackend,
+    dnstap::{Dnstap, DnstapTarget},
     engine::AdblockEngine,
-    handler::Handler,
-    query_log::QueryLogStore,
+    events::{EventLog, EventSink, JsonlFile, Stdout, Syslog, SyslogTarget},
+    fetch::{FetchLimiter, Fetcher, SourceCache},
+    handler::{Handler, HandlerSettings},
+    health::Health,
+    metrics::Metrics,
+    privacy::{Privacy, PrivacyMode},
+    query_log::{QueryLogStore, SegmentLog},
     rate_limiter::new_rate_limiter,
     resolver::Resolver,
+    rpz::{IpPrefix, Rpz, TsigKey},
+    stats::StatsStore,
     tls::setup_tls,
 };
 
-const TCP_TIMEOUT: Duration = Duration::from_secs(10);
 const BIND_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
 const BIND_PORT: u16 = 5353;
 
-#[derive(Parser, Debug)]
+/// First and longest wait before retrying a failed update
+const UPDATE_RETRY_MIN: Duration = Duration::from_secs(30);
+const UPDATE_RETRY_MAX: Duration = Duration::from_secs(900);
+
+#[derive(clap::Parser, serde::Serialize, Clone, Debug)]
 #[command(name = "Bancuh DNS")]
 #[command(version)]
 #[command(about)]
 struct Args {
-    /// Sets a custom config file
+    #[command(subcommand)]
+    #[serde(skip)]
+    command: Option<cli::Command>,
+
+    /// YAML file with server settings, keyed by argument name. Command line and env take precedence
+    #[arg(long, env, value_name = "SETTINGS_FILE")]
+    #[serde(skip)]
+    settings_file: Option<PathBuf>,
+
+    /// Sets a custom config file, repeat the flag for more; later configs override earlier ones
     #[arg(
         short,
         long,
@@ -52,12 +89,61 @@ struct Args {
         value_name = "CONFIG_URL",
         default_value = "https://raw.githubusercontent.com/ragibkl/adblock-dns-server/master/data/configuration.yaml"
     )]
-    config_url: FileOrUrl,
+    #[serde(serialize_with = "settings::serialize_config_urls")]
+    config_url: Vec<FileOrUrl>,
+
+    /// Directory for caching downloaded list sources, used as fallback when a fetch fails
+    #[arg(
+        long,
+        env,
+        value_name = "SOURCE_CACHE_DIR",
+        default_value = "/var/cache/bancuh-dns/sources"
+    )]
+    source_cache_dir: String,
+
+    /// Directory for the last known good compiled db, loaded at startup until the first update completes
+    #[arg(
+        long,
+        env,
+        value_name = "DB_SNAPSHOT_DIR",
+        default_value = "/var/cache/bancuh-dns/snapshot"
+    )]
+    db_snapshot_dir: PathBuf,
+
+    /// Storage for the compiled lists
+    #[arg(long, env, value_name = "DB_BACKEND", value_enum, default_value_t)]
+    db_backend: DBBackend,
+
+    /// Maximum number of list sources downloaded at the same time
+    #[arg(long, env, value_name = "FETCH_CONCURRENCY", default_value = "8")]
+    fetch_concurrency: usize,
+
+    /// Maximum number of list sources downloaded at the same time from a single host
+    #[arg(long, env, value_name = "FETCH_PER_HOST", default_value = "2")]
+    fetch_per_host: usize,
+
+    /// Source categories to start with blocking disabled, can be toggled from the admin UI
+    #[arg(long, env, value_name = "DISABLED_CATEGORIES", value_delimiter = ',')]
+    disabled_categories: Vec<Category>,
 
     /// Sets a custom listener port
     #[arg(short, long, env, value_name = "PORT", default_value = "53")]
     port: u16,
 
+    /// Addresses the DNS, DoT and DoH listeners bind to
+    #[arg(
+        long,
+        env,
+        value_name = "LISTEN_ADDRS",
+        value_delimiter = ',',
+        default_value = "0.0.0.0,::"
+    )]
+    listen_addrs: Vec<IpAddr>,
+
+    /// Idle timeout in seconds for TCP, DoT and DoH connections
+    #[arg(long, env, value_name = "TCP_TIMEOUT", default_value = "10")]
+    tcp_timeout: u64,
+
     /// Sets custom forward resolvers
     #[arg(short, long, env, value_name = "FORWARDERS", value_delimiter = ',')]
     forwarders: Vec<IpAddr>,
@@ -66,10 +152,18 @@ struct Args {
     #[arg(long, env, value_name = "FORWARDERS_PORT", default_value = "53")]
     forwarders_port: u16,
 
+    /// Timeout in seconds for each query to an upstream resolver
+    #[arg(long, env, value_name = "UPSTREAM_TIMEOUT", default_value = "5")]
+    upstream_timeout: u64,
+
     /// Sets the blocklist update interval in seconds
     #[arg(long, env, value_name = "UPDATE_INTERVAL", default_value = "86400")]
     update_interval: u64,
 
+    /// Watch local config and list files, and update as soon as they change
+    #[arg(long, env, value_name = "WATCH_CONFIG")]
+    watch_config: bool,
+
     /// Enable DoT (port 853) and DoH (port 443) via ACME/Let's Encrypt
     #[arg(long, env, value_name = "TLS_ENABLED")]
     tls_enabled: bool,
@@ -84,6 +178,7 @@ struct Args {
 
     /// Custom ACME directory URL (defaults to Let's Encrypt production)
     #[arg(long, env, value_name = "ACME_URL")]
+    #[serde(serialize_with = "settings::serialize_opt_url")]
     acme_url: Option<String>,
 
     /// Directory for caching ACME account key and certificates
@@ -103,6 +198,111 @@ struct Args {
     #[arg(long, env, value_name = "ADMIN_PORT", default_value = "8080")]
     admin_port: u16,
 
+    /// Seconds of queries kept in memory for each client's log view
+    #[arg(long, env, value_name = "QUERY_LOG_MAX_AGE", default_value = "600")]
+    query_log_max_age: u64,
+
+    /// Most queries kept in memory for each client's log view
+    #[arg(long, env, value_name = "QUERY_LOG_MAX_PER_IP", default_value = "1000")]
+    query_log_max_per_ip: usize,
+
+    /// Directory for an on-disk query log in hourly segments, searched by the operator view
+    #[arg(long, env, value_name = "QUERY_LOG_DIR")]
+    query_log_dir: Option<PathBuf>,
+
+    /// Seconds the on-disk query log is kept (0 = no age limit)
+    #[arg(
+        long,
+        env,
+        value_name = "QUERY_LOG_RETENTION",
+        default_value = "604800"
+    )]
+    query_log_retention: u64,
+
+    /// Maximum size of the on-disk query log in MiB, the oldest hours are removed first (0 = no limit)
+    #[arg(long, env, value_name = "QUERY_LOG_MAX_SIZE", default_value = "1024")]
+    query_log_max_size: u64,
+
+    /// How clients are identified in the query log and stats: full, anonymized, hashed, domains or off
+    #[arg(
+        long,
+        env,
+        value_name = "QUERY_LOG_PRIVACY",
+        value_enum,
+        default_value_t
+    )]
+    query_log_privacy: PrivacyMode,
+
+    /// Key for the client hashes of the hashed privacy mode, random on every start when unset
+    #[arg(long, env, value_name = "QUERY_LOG_HASH_KEY")]
+    #[serde(serialize_with = "settings::serialize_secret")]
+    query_log_hash_key: Option<String>,
+
+    /// dnstap collector for query and response events, as unix:<path> or tcp:<ip>:<port>
+    #[arg(long, env, value_name = "DNSTAP_SOCKET")]
+    dnstap_socket: Option<String>,
+
+    /// Identity of this server in dnstap frames
+    #[arg(long, env, value_name = "DNSTAP_IDENTITY")]
+    dnstap_identity: Option<String>,
+
+    /// File to export query events to as JSON lines
+    #[arg(long, env, value_name = "QUERY_EVENTS_FILE")]
+    query_events_file: Option<PathBuf>,
+
+    /// Size in MiB at which the query events file is rotated (0 = never)
+    #[arg(
+        long,
+        env,
+        value_name = "QUERY_EVENTS_FILE_MAX_SIZE",
+        default_value = "100"
+    )]
+    query_events_file_max_size: u64,
+
+    /// Rotated query events files kept
+    #[arg(long, env, value_name = "QUERY_EVENTS_FILE_KEEP", default_value = "5")]
+    query_events_file_keep: usize,
+
+    /// Syslog receiver for query events, as udp:<ip>:<port>, tcp:<ip>:<port> or unix:<path>
+    #[arg(long, env, value_name = "QUERY_EVENTS_SYSLOG")]
+    query_events_syslog: Option<String>,
+
+    /// Export query events to stdout as JSON lines
+    #[arg(long, env, value_name = "QUERY_EVENTS_STDOUT")]
+    query_events_stdout: bool,
+
+    /// Hex sha256 of the operator token, enables the all-clients log view and guards category toggles
+    #[arg(long, env, value_name = "ADMIN_TOKEN_SHA256")]
+    #[serde(serialize_with = "settings::serialize_secret")]
+    admin_token_sha256: Option<String>,
+
+    /// Port serving the compiled policy as an RPZ zone to secondaries over AXFR/IXFR, off when unset
+    #[arg(long, env, value_name = "RPZ_PORT")]
+    rpz_port: Option<u16>,
+
+    /// Name of the RPZ zone
+    #[arg(long, env, value_name = "RPZ_ZONE", default_value = export::DEFAULT_RPZ_ZONE)]
+    rpz_zone: String,
+
+    /// Addresses or prefixes allowed to query and transfer the RPZ zone
+    #[arg(
+        long,
+        env,
+        value_name = "RPZ_ALLOW_TRANSFER",
+        value_delimiter = ',',
+        default_value = "127.0.0.1,::1"
+    )]
+    rpz_allow_transfer: Vec<String>,
+
+    /// TSIG key required on RPZ requests and used to sign NOTIFYs, as [algorithm:]name:base64-secret
+    #[arg(long, env, value_name = "RPZ_TSIG_KEY")]
+    #[serde(serialize_with = "settings::serialize_secret")]
+    rpz_tsig_key: Option<String>,
+
+    /// Secondaries sent a NOTIFY when the RPZ zone changes, as <ip>:<port>
+    #[arg(long, env, value_name = "RPZ_NOTIFY", value_delimiter = ',')]
+    rpz_notify: Vec<SocketAddr>,
+
     /// Maximum DNS requests per second per IP (0 = unlimited)
     #[arg(long, env, value_name = "RATE_LIMIT", default_value = "100")]
     rate_limit: u32,
@@ -116,6 +316,91 @@ struct Args {
     rate_limit_ipv6_prefix: u8,
 }
 
+/// Parse the arguments, filling in from the settings file what the command line and env leave out
+fn parse_args() -> anyhow::Result<Args> {
+    let command = Args::command();
+    let matches = command.clone().get_matches();
+    let args = Args::from_arg_matches(&matches)?;
+
+    let Some(path) = &args.settings_file else {
+        return Ok(args);
+    };
+    let extra = settings::settings_args(path, &command, &matches)?;
+    // settings go before any subcommand, which has arguments of its own
+    let mut argv = std::env::args_os();
+    let argv = argv.next().into_iter().chain(extra).chain(argv);
+    let matches = command.try_get_matches_from(argv)?;
+
+    Ok(Args::from_arg_matches(&matches)?)
+}
+
+/// Settings applied live on SIGHUP; changes to any other need a restart
+const RELOADABLE: &[&str] = &[
+    "forwarders",
+    "forwarders_port",
+    "upstream_timeout",
+    "rate_limit",
+    "rate_limit_ipv4_prefix",
+    "rate_limit_ipv6_prefix",
+];
+
+fn handler_settings(
+    args: &Args,
+    metrics: &Arc<Metrics>,
+    dnstap: &Option<Arc<Dnstap>>,
+) -> HandlerSettings {
+    let timeout = Duration::from_secs(args.upstream_timeout);
+    let resolver = if args.forwarders.is_empty() {
+        Resolver::new(
+            &[BIND_IP],
+            &BIND_PORT,
+            timeout,
+            metrics.clone(),
+            dnstap.clone(),
+        )
+    } else {
+        Resolver::new(
+            &args.forwarders,
+            &args.forwarders_port,
+            timeout,
+            metrics.clone(),
+            dnstap.clone(),
+        )
+    };
+
+    HandlerSettings {
+        resolver,
+        rate_limiter: new_rate_limiter(args.rate_limit),
+        rate_limit_ipv4_prefix: args.rate_limit_ipv4_prefix,
+        rate_limit_ipv6_prefix: args.rate_limit_ipv6_prefix,
+    }
+}
+
+/// Re-read the settings, warning about changes that only apply after a restart
+fn reload_args(current: &Args, bind_running: bool) -> anyhow::Result<Args> {
+    let mut args = parse_args()?;
+
+    if args.forwarders.is_empty() && !bind_running {
+        tracing::warn!(
+            "Switching to the local BIND resolver requires a restart, keeping forwarders"
+        );
+        args.forwarders = current.forwarders.clone();
+    }
+
+    let before = serde_yaml::to_value(current)?;
+    let after = serde_yaml::to_value(&args)?;
+    if let (Some(before), Some(after)) = (before.as_mapping(), after.as_mapping()) {
+        for (key, value) in after {
+            let key = key.as_str().unwrap_or_default();
+            if before.get(key) != Some(value) && !RELOADABLE.contains(&key) {
+                tracing::warn!("Setting {key} changed, restart to apply it");
+            }
+        }
+    }
+
+    Ok(args)
+}
+
 async fn sigint() -> std::io::Result<()> {
     signal(SignalKind::interrupt())?.recv().await;
     Ok(())
@@ -128,14 +413,60 @@ async fn sigterm() -> std::io::Result<()> {
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
+    let args = parse_args()?;
+    if let Some(command) = args.command.clone() {
+        // subcommands print their results to stdout, so logs go to stderr
+        tracing_subscriber::fmt()
+            .with_writer(std::io::stderr)
+            .init();
+        return Ok(cli::run(command, &args).await?);
+    }
+
+    if args.query_events_stdout {
+        // query events take stdout, logs go to stderr so they stay apart
+        tracing_subscriber::fmt()
+            .with_writer(std::io::stderr)
+            .init();
+    } else {
+        tracing_subscriber::fmt::init();
+    }
+    tracing::info!("Effective settings:\n{}", serde_yaml::to_string(&args)?);
+
+    let metrics = Arc::new(Metrics::new());
+    let dnstap = match &args.dnstap_socket {
+        Some(target) => {
+            let target: DnstapTarget = target.parse()?;
+            tracing::info!("Sending dnstap to {target}");
+            Some(Arc::new(Dnstap::spawn(
+                target,
+                args.dnstap_identity.clone(),
+            )))
+        }
+        None => None,
+    };
+    let settings = Arc::new(ArcSwap::from_pointee(handler_settings(
+        &args, &metrics, &dnstap,
+    )));
+    let reload = Arc::new(Notify::new());
 
     let Args {
+        command: _,
+        settings_file: _,
         config_url,
+        source_cache_dir,
+        db_snapshot_dir,
+        db_backend,
+        fetch_concurrency,
+        fetch_per_host,
+        disabled_categories,
         port,
+        listen_addrs,
+        tcp_timeout,
         forwarders,
-        forwarders_port,
+        forwarders_port: _,
+        upstream_timeout: _,
         update_interval,
+        watch_config,
         tls_enabled,
         tls_email,
         tls_domain,
@@ -143,63 +474,115 @@ async fn main() -> anyhow::Result<()> {
         acme_cache_dir,
         acme_insecure,
         admin_port,
-        rate_limit,
-        rate_limit_ipv4_prefix,
-        rate_limit_ipv6_prefix,
-    } = Args::parse();
+        query_log_max_age,
+        query_log_max_per_ip,
+        query_log_dir,
+        query_log_retention,
+        query_log_max_size,
+        query_log_privacy,
+        query_log_hash_key,
+        dnstap_socket: _,
+        dnstap_identity: _,
+        query_events_file,
+        query_events_file_max_size,
+        query_events_file_keep,
+        query_events_syslog,
+        query_events_stdout,
+        admin_token_sha256,
+        rpz_port,
+        rpz_zone,
+        rpz_allow_transfer,
+        rpz_tsig_key,
+        rpz_notify,
+        rate_limit: _,
+        rate_limit_ipv4_prefix: _,
+        rate_limit_ipv6_prefix: _,
+    } = args.clone();
+
+    let rpz_origin = hickory_server::proto::rr::Name::from_ascii(&rpz_zone)?;
+    let operator_auth = admin_token_sha256
+        .as_deref()
+        .map(OperatorAuth::from_hex)
+        .transpose()?;
 
     let update_interval = Duration::from_secs(update_interval);
+    let tcp_timeout = Duration::from_secs(tcp_timeout);
 
-    tracing::info!("config_url: {config_url}");
-    tracing::info!("port: {port}");
-    tracing::info!("forwarders: [{}]", forwarders.iter().join(", "));
-    tracing::info!("forwarders_port: {forwarders_port}");
-    tracing::info!("update_interval: {update_interval:?}");
-
-    tracing::info!("Validating adblock config. config_url: {config_url}");
-    let mut delay = Duration::from_secs(5);
-    for attempt in 1u32.. {
-        match Config::load(&config_url).await {
-            Ok(_) => break,
-            Err(err) if attempt >= 5 => {
-                tracing::error!("Validating adblock config failed after {attempt} attempts: {err}");
-                return Err(err.into());
-            }
-            Err(err) => {
-                tracing::warn!(
-                    "Validating adblock config failed (attempt {attempt}): {err}. Retrying in {delay:?}"
-                );
-                tokio::time::sleep(delay).await;
-                delay = (delay * 2).min(Duration::from_secs(60));
+    let fetcher = Fetcher::new(
+        SourceCache::new(source_cache_dir),
+        FetchLimiter::new(fetch_concurrency, fetch_per_host),
+    );
+    let engine = Arc::new(AdblockEngine::new(
+        config_url.clone(),
+        fetcher,
+        disabled_categories.into_iter().collect(),
+        db_snapshot_dir,
+        db_backend,
+    )?);
+
+    // a restored snapshot already blocks, a config that cannot be loaded yet is left to the
+    // update loop to retry rather than keeping the server down
+    if engine.is_loaded() {
+        tracing::info!(
+            "Serving the restored db snapshot, the config is checked by the first update"
+        );
+    } else {
+        tracing::info!("Validating adblock config");
+        let mut delay = Duration::from_secs(5);
+        for attempt in 1u32.. {
+            match Config::load(&config_url).await {
+                Ok(_) => break,
+                Err(err) if attempt >= 5 => {
+                    tracing::error!(
+                        "Validating adblock config failed after {attempt} attempts: {err}"
+                    );
+                    return Err(err.into());
+                }
+                Err(err) => {
+                    tracing::warn!(
+                        "Validating adblock config failed (attempt {attempt}): {err}. Retrying in {delay:?}"
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(Duration::from_secs(60));
+                }
             }
         }
+        tracing::info!("Validating adblock config. DONE");
     }
-    tracing::info!("Validating adblock config. config_url: {config_url}. DONE");
-
-    let engine = Arc::new(AdblockEngine::new(config_url)?);
 
     let tracker = TaskTracker::new();
     let token = CancellationToken::new();
 
     tracing::info!("Starting engine-update task");
     let cloned_engine = engine.clone();
+    let cloned_reload = reload.clone();
     let cloned_token = token.clone();
     tracker.spawn(async move {
+        // a failed update is retried sooner than the next interval, backing off up to it
+        let mut retry = UPDATE_RETRY_MIN;
         loop {
             tracing::info!("engine-update running db update");
-            if let Err(err) = cloned_engine.run_update().await {
+            let wait = if let Err(err) = cloned_engine.run_update().await {
+                let wait = retry.min(update_interval);
+                retry = (retry * 2).min(UPDATE_RETRY_MAX);
                 tracing::warn!(
-                    "engine-update running db update. ERROR: {err}. Keeping existing db, will retry next interval."
+                    "engine-update running db update. ERROR: {err}. Keeping existing db, will retry in {wait:?}."
                 );
+                wait
             } else {
+                retry = UPDATE_RETRY_MIN;
                 tracing::info!("engine-update running db update. DONE");
-            }
+                update_interval
+            };
 
-            tracing::info!("engine-update sleeping for {update_interval:?}");
+            tracing::info!("engine-update sleeping for {wait:?}");
             tokio::select! {
-                _ = tokio::time::sleep(update_interval) => {
+                _ = tokio::time::sleep(wait) => {
                     tracing::info!("engine-update waking up");
                 }
+                _ = cloned_reload.notified() => {
+                    tracing::info!("engine-update triggered by reload or admin");
+                }
                 _ = cloned_token.cancelled() => {
                     tracing::info!("engine-update received cancel signal");
                     return;
@@ -209,7 +592,17 @@ async fn main() -> anyhow::Result<()> {
     });
     tracing::info!("Starting engine-update task. DONE");
 
-    let resolver = if forwarders.is_empty() {
+    if watch_config {
+        tracing::info!("Starting file-watch task");
+        tracker.spawn(reload::watch_files(
+            engine.clone(),
+            reload.clone(),
+            token.clone(),
+        ));
+    }
+
+    let bind_running = forwarders.is_empty();
+    if bind_running {
         tracing::info!("Starting bind");
         let cloned_token = token.clone();
         tracker.spawn(async move {
@@ -234,31 +627,151 @@ async fn main() -> anyhow::Result<()> {
                 },
             }
         });
+    }
 
-        Resolver::new(&[BIND_IP], &BIND_PORT)
+    tracing::info!("Starting reload task");
+    let cloned_settings = settings.clone();
+    let cloned_metrics = metrics.clone();
+    let cloned_dnstap = dnstap.clone();
+    let cloned_reload = reload.clone();
+    let cloned_token = token.clone();
+    tracker.spawn(async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(err) => {
+                tracing::error!("Unable to listen for sighup signal: {err}");
+                return;
+            }
+        };
+
+        let mut current = args;
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => {
+                    tracing::info!("Received sighup signal, reloading");
+                    match reload_args(&current, bind_running) {
+                        Ok(args) => {
+                            cloned_settings.store(Arc::new(handler_settings(&args, &cloned_metrics, &cloned_dnstap)));
+                            current = args;
+                            tracing::info!("Reloaded settings");
+                        }
+                        Err(err) => {
+                            tracing::warn!("Reloading settings failed: {err}. Keeping current settings");
+                        }
+                    }
+                    cloned_reload.notify_one();
+                }
+                _ = cloned_token.cancelled() => {
+                    tracing::info!("reload received cancel signal");
+                    return;
+                }
+            }
+        }
+    });
+
+    // opt-outs are kept next to the on-disk log, if there is one
+    let opt_out_path = query_log_dir.as_ref().map(|dir| dir.join("opt-out.json"));
+    let segments = match query_log_dir {
+        Some(dir) => {
+            tracing::info!("Opening query log in {}", dir.display());
+            let retention = Duration::from_secs(query_log_retention);
+            Some(SegmentLog::open(
+                dir,
+                retention,
+                query_log_max_size * 1024 * 1024,
+            )?)
+        }
+        None => None,
+    };
+    let privacy = Arc::new(Privacy::new(
+        query_log_privacy,
+        query_log_hash_key.as_deref(),
+        opt_out_path,
+    )?);
+    let query_log = Arc::new(QueryLogStore::new(
+        Duration::from_secs(query_log_max_age),
+        query_log_max_per_ip,
+        segments,
+        privacy.clone(),
+    ));
+    let stats = Arc::new(StatsStore::new(privacy));
+
+    let mut sinks: Vec<Box<dyn EventSink>> = Vec::new();
+    if let Some(path) = query_events_file {
+        tracing::info!("Exporting query events to {}", path.display());
+        sinks.push(Box::new(JsonlFile::open(
+            path,
+            query_events_file_max_size * 1024 * 1024,
+            query_events_file_keep,
+        )?));
+    }
+    if let Some(target) = query_events_syslog {
+        let target: SyslogTarget = target.parse()?;
+        tracing::info!("Exporting query events to syslog {target}");
+        sinks.push(Box::new(Syslog::connect(target)?));
+    }
+    if query_events_stdout {
+        sinks.push(Box::new(Stdout));
+    }
+    let events = if sinks.is_empty() {
+        None
     } else {
-        Resolver::new(&forwarders, &forwarders_port)
+        Some(Arc::new(EventLog::spawn(sinks)?))
     };
 
-    let query_log = Arc::new(QueryLogStore::new());
-    let rate_limiter = new_rate_limiter(rate_limit).map(Arc::new);
+    let health = Arc::new(Health::new(
+        engine.clone(),
+        settings.clone(),
+        metrics.clone(),
+        tls_enabled,
+    ));
     let handler = Handler::new(
-        engine,
-        resolver,
+        engine.clone(),
         query_log.clone(),
-        rate_limiter,
-        rate_limit_ipv4_prefix,
-        rate_limit_ipv6_prefix,
+        settings,
+        metrics.clone(),
+        stats.clone(),
+        dnstap,
+        events,
     );
 
     tracing::info!("Starting dns server");
     let mut server = ServerFuture::new(handler);
-    let v4_addr = SocketAddr::from(([0, 0, 0, 0], port));
-    let v6_addr = SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 0], port));
-    server.register_listener(net::bind_tcp(v4_addr)?, TCP_TIMEOUT);
-    server.register_listener(net::bind_tcp(v6_addr)?, TCP_TIMEOUT);
-    server.register_socket(net::bind_udp(v4_addr)?);
-    server.register_socket(net::bind_udp(v6_addr)?);
+    for ip in &listen_addrs {
+        let addr = SocketAddr::new(*ip, port);
+        server.register_listener(net::bind_tcp(addr)?, tcp_timeout);
+        server.register_socket(net::bind_udp(addr)?);
+    }
+
+    if let Some(rpz_port) = rpz_port {
+        let allow = rpz_allow_transfer
+            .iter()
+            .map(|prefix| prefix.parse::<IpPrefix>())
+            .collect::<Result<Vec<_>, _>>()?;
+        let key = rpz_tsig_key
+            .as_deref()
+            .map(str::parse::<TsigKey>)
+            .transpose()?;
+        let origin = rpz_origin.clone();
+
+        match &key {
+            Some(key) => tracing::info!(
+                "Serving RPZ zone {origin} on port {rpz_port}, TSIG key {}",
+                key.name()
+            ),
+            None => tracing::info!("Serving RPZ zone {origin} on port {rpz_port}, without TSIG"),
+        }
+        let rpz = Arc::new(Rpz::new(origin, allow, key, rpz_notify));
+        tracker.spawn(rpz.clone().follow(engine.clone(), token.clone()));
+        for ip in &listen_addrs {
+            let addr = SocketAddr::new(*ip, rpz_port);
+            tracker.spawn(rpz.clone().serve_udp(net::bind_udp(addr)?, token.clone()));
+            tracker.spawn(
+                rpz.clone()
+                    .serve_tcp(net::bind_tcp(addr)?, tcp_timeout, token.clone()),
+            );
+        }
+    }
 
     let tls_resolver = if tls_enabled {
         let domain = tls_domain.expect("TLS_DOMAIN is required when TLS_ENABLED=true");
@@ -271,34 +784,33 @@ async fn main() -> anyhow::Result<()> {
             acme_url,
             acme_cache_dir,
             acme_insecure,
+            metrics.clone(),
             &tracker,
             token.clone(),
         )
         .await;
 
         tracing::info!("Registering DoT listener on port 853");
-        let dot_v4 = SocketAddr::from(([0, 0, 0, 0], 853));
-        let dot_v6 = SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 0], 853));
-        server.register_tls_listener(net::bind_tcp(dot_v4)?, TCP_TIMEOUT, resolver.clone())?;
-        server.register_tls_listener(net::bind_tcp(dot_v6)?, TCP_TIMEOUT, resolver.clone())?;
+        for ip in &listen_addrs {
+            let dot_addr = SocketAddr::new(*ip, 853);
+            server.register_tls_listener(
+                net::bind_tcp(dot_addr)?,
+                tcp_timeout,
+                resolver.clone(),
+            )?;
+        }
 
         tracing::info!("Registering DoH listener on port 443");
-        let doh_v4 = SocketAddr::from(([0, 0, 0, 0], 443));
-        let doh_v6 = SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 0], 443));
-        server.register_https_listener(
-            net::bind_tcp(doh_v4)?,
-            TCP_TIMEOUT,
-            resolver.clone(),
-            Some(domain.clone()),
-            "/dns-query".to_string(),
-        )?;
-        server.register_https_listener(
-            net::bind_tcp(doh_v6)?,
-            TCP_TIMEOUT,
-            resolver.clone(),
-            Some(domain),
-            "/dns-query".to_string(),
-        )?;
+        for ip in &listen_addrs {
+            let doh_addr = SocketAddr::new(*ip, 443);
+            server.register_https_listener(
+                net::bind_tcp(doh_addr)?,
+                tcp_timeout,
+                resolver.clone(),
+                Some(domain.clone()),
+                "/dns-query".to_string(),
+            )?;
+        }
         tracing::info!("TLS/ACME setup done");
 
         Some(resolver)
@@ -307,17 +819,29 @@ async fn main() -> anyhow::Result<()> {
     };
 
     tracing::info!("Starting admin HTTP server on port {admin_port}");
-    let cloned_query_log = query_log.clone();
+    let admin_state = AdminState {
+        engine,
+        query_log: query_log.clone(),
+        metrics,
+        stats,
+        auth: operator_auth,
+        shutdown: token.clone(),
+        update: reload.clone(),
+        health: health.clone(),
+        rpz_zone: rpz_origin.to_string(),
+        exports: Arc::default(),
+    };
     let cloned_token = token.clone();
     tracker.spawn(admin::serve(
         admin_port,
-        cloned_query_log,
+        admin_state,
         tls_resolver,
         cloned_token,
     ));
 
     tracker.close();
 
+    health.set_listening();
     tracing::info!("Starting dns server. DONE");
 
     tokio::select! {
@@ -0,0 +1,279 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use hickory_server::proto::{op::ResponseCode, rr::RecordType, xfer::Protocol};
+
+use crate::engine::AdblockEngine;
+
+/// Upper bounds in seconds of the upstream latency buckets
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// How a query was answered
+#[derive(
+    serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Forwarded,
+    Blocked,
+    Rewritten,
+    Error,
+    RateLimited,
+}
+
+impl Outcome {
+    pub const ALL: [Outcome; 5] = [
+        Outcome::Forwarded,
+        Outcome::Blocked,
+        Outcome::Rewritten,
+        Outcome::Error,
+        Outcome::RateLimited,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Forwarded => "forwarded",
+            Self::Blocked => "blocked",
+            Self::Rewritten => "rewritten",
+            Self::Error => "error",
+            Self::RateLimited => "rate_limited",
+        }
+    }
+}
+
+impl std::str::FromStr for Outcome {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|o| o.as_str() == s)
+            .ok_or_else(|| format!("unknown outcome: {s}"))
+    }
+}
+
+pub fn transport_label(protocol: Protocol) -> &'static str {
+    match protocol {
+        Protocol::Udp => "udp",
+        Protocol::Tcp => "tcp",
+        Protocol::Tls => "tls",
+        Protocol::Https => "https",
+        _ => "other",
+    }
+}
+
+/// Query type label, unassigned types are grouped so they cannot blow up the label set
+pub fn type_label(query_type: Option<RecordType>) -> String {
+    match query_type {
+        Some(RecordType::Unknown(_)) | None => "OTHER".to_string(),
+        Some(t) => t.to_string(),
+    }
+}
+
+/// Response code mnemonic, e.g. `NXDOMAIN`
+pub fn rcode_label(code: ResponseCode) -> String {
+    match code {
+        ResponseCode::NoError => "NOERROR".to_string(),
+        ResponseCode::FormErr => "FORMERR".to_string(),
+        ResponseCode::ServFail => "SERVFAIL".to_string(),
+        ResponseCode::NXDomain => "NXDOMAIN".to_string(),
+        ResponseCode::NotImp => "NOTIMP".to_string(),
+        ResponseCode::Refused => "REFUSED".to_string(),
+        code => format!("RCODE{}", u16::from(code)),
+    }
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        for (bucket, le) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            if secs <= le {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        for (bucket, le) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            let n = bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{le}\"}} {n}");
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {count}");
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {sum}");
+        let _ = writeln!(out, "{name}_count{{{labels}}} {count}");
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// Server metrics, rendered in the Prometheus text format at `/metrics`
+#[derive(Debug, Default)]
+pub struct Metrics {
+    queries: Mutex<BTreeMap<(String, &'static str, Outcome), u64>>,
+    upstream_ok: Histogram,
+    upstream_error: Histogram,
+    cache_hits: AtomicU64,
+    cert_expiry: AtomicI64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_query(&self, query_type: String, transport: &'static str, outcome: Outcome) {
+        let mut queries = self.queries.lock().unwrap();
+        *queries.entry((query_type, transport, outcome)).or_default() += 1;
+    }
+
+    /// Record a query answered by an upstream resolver
+    pub fn record_upstream(&self, elapsed: Duration, ok: bool) {
+        if ok {
+            self.upstream_ok.observe(elapsed);
+        } else {
+            self.upstream_error.observe(elapsed);
+        }
+    }
+
+    /// Record a query answered from the resolver cache
+    pub fn record_cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record the expiry, as a unix timestamp, of the certificate currently in use
+    pub fn set_cert_expiry(&self, timestamp: i64) {
+        self.cert_expiry.store(timestamp, Ordering::Relaxed);
+    }
+
+    /// Expiry of the certificate currently in use, if one was loaded
+    pub fn cert_expiry(&self) -> Option<i64> {
+        Some(self.cert_expiry.load(Ordering::Relaxed)).filter(|expiry| *expiry > 0)
+    }
+
+    pub fn render(&self, engine: &AdblockEngine) -> String {
+        let mut out = String::new();
+
+        let name = "bancuh_dns_queries_total";
+        header(
+            &mut out,
+            name,
+            "counter",
+            "DNS queries by type, transport and outcome",
+        );
+        for ((query_type, transport, outcome), n) in self.queries.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "{name}{{type=\"{query_type}\",transport=\"{transport}\",outcome=\"{}\"}} {n}",
+                outcome.as_str()
+            );
+        }
+
+        let name = "bancuh_dns_upstream_duration_seconds";
+        header(
+            &mut out,
+            name,
+            "histogram",
+            "Latency of queries sent upstream",
+        );
+        self.upstream_ok.render(&mut out, name, "result=\"ok\"");
+        self.upstream_error
+            .render(&mut out, name, "result=\"error\"");
+
+        let name = "bancuh_dns_upstream_cache_hits_total";
+        header(
+            &mut out,
+            name,
+            "counter",
+            "Upstream lookups answered from the resolver cache",
+        );
+        let _ = writeln!(out, "{name} {}", self.cache_hits.load(Ordering::Relaxed));
+
+        let name = "bancuh_dns_db_entries";
+        header(&mut out, name, "gauge", "Entries in each domain store");
+        for (store, entries) in engine.db_entries() {
+            let _ = writeln!(out, "{name}{{store=\"{store}\"}} {entries}");
+        }
+
+        if let Some(update) = engine.last_update() {
+            let name = "bancuh_dns_last_update_timestamp_seconds";
+            header(
+                &mut out,
+                name,
+                "gauge",
+                "Completion time of the last successful update",
+            );
+            let _ = writeln!(out, "{name} {}", update.finished_at.timestamp());
+
+            let name = "bancuh_dns_last_update_duration_seconds";
+            header(
+                &mut out,
+                name,
+                "gauge",
+                "Duration of the last successful update",
+            );
+            let _ = writeln!(out, "{name} {}", update.duration.as_secs_f64());
+        }
+
+        let cert_expiry = self.cert_expiry.load(Ordering::Relaxed);
+        if cert_expiry > 0 {
+            let name = "bancuh_dns_tls_cert_expiry_timestamp_seconds";
+            header(
+                &mut out,
+                name,
+                "gauge",
+                "Expiry time of the ACME certificate",
+            );
+            let _ = writeln!(out, "{name} {cert_expiry}");
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_renders_cumulative_buckets() {
+        let histogram = Histogram::default();
+        histogram.observe(Duration::from_millis(3));
+        histogram.observe(Duration::from_millis(40));
+        histogram.observe(Duration::from_secs(10));
+
+        let mut out = String::new();
+        histogram.render(&mut out, "latency", "result=\"ok\"");
+
+        assert!(out.contains("latency_bucket{result=\"ok\",le=\"0.001\"} 0\n"));
+        assert!(out.contains("latency_bucket{result=\"ok\",le=\"0.005\"} 1\n"));
+        assert!(out.contains("latency_bucket{result=\"ok\",le=\"0.05\"} 2\n"));
+        assert!(out.contains("latency_bucket{result=\"ok\",le=\"5\"} 2\n"));
+        assert!(out.contains("latency_bucket{result=\"ok\",le=\"+Inf\"} 3\n"));
+        assert!(out.contains("latency_sum{result=\"ok\"} 10.043\n"));
+        assert!(out.contains("latency_count{result=\"ok\"} 3\n"));
+    }
+}
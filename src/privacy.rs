@@ -0,0 +1,285 @@
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use arc_swap::ArcSwap;
+use itertools::Itertools;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::rate_limiter::mask_ip;
+
+/// Most opt-outs kept, as anyone who can reach the admin port can add one
+const MAX_OPT_OUTS: usize = 10_000;
+
+/// Opt-outs cover an IPv6 client's whole /64, which it can pick addresses from at will
+fn opt_out_key(ip: IpAddr) -> IpAddr {
+    mask_ip(ip, 32, 64)
+}
+
+#[derive(Debug, Error)]
+pub enum OptOutError {
+    #[error("at most {MAX_OPT_OUTS} clients can opt out")]
+    Full,
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+/// How much of each query is kept by the query log and stats
+#[derive(clap::ValueEnum, serde::Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PrivacyMode {
+    /// Client IPs and names
+    #[default]
+    Full,
+    /// Client IPs truncated to their /24 or /48
+    Anonymized,
+    /// Client IPs replaced by a keyed hash
+    Hashed,
+    /// No query log, only per domain stats
+    Domains,
+    /// No query log, only query counts
+    Off,
+}
+
+/// Decides how clients are identified in the query log and stats, if at all
+#[derive(Debug)]
+pub struct Privacy {
+    mode: PrivacyMode,
+    key: [u8; 32],
+    /// Read on every query, so it is swapped whole rather than locked
+    opted_out: ArcSwap<HashSet<IpAddr>>,
+    /// Held by changes to the opt-outs, so they are saved one at a time
+    opt_out_write: Mutex<()>,
+    opt_out_path: Option<PathBuf>,
+}
+
+impl Privacy {
+    /// `key` seeds the client hashes, a random one makes them change on every restart.
+    /// Opt-outs are kept in `opt_out_path` when given, so they survive restarts.
+    pub fn new(
+        mode: PrivacyMode,
+        key: Option<&str>,
+        opt_out_path: Option<PathBuf>,
+    ) -> std::io::Result<Self> {
+        let key = match key {
+            Some(key) => Sha256::digest(key.as_bytes()).into(),
+            None => rand::random(),
+        };
+
+        let opted_out: HashSet<IpAddr> = match &opt_out_path {
+            Some(path) => match std::fs::read(path) {
+                Ok(content) => serde_json::from_slice(&content)?,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashSet::new(),
+                Err(err) => return Err(err),
+            },
+            None => HashSet::new(),
+        };
+        // files from before opt-outs were kept per /64 may hold full IPv6 addresses
+        let opted_out = opted_out.into_iter().map(opt_out_key).collect();
+
+        Ok(Self {
+            mode,
+            key,
+            opted_out: ArcSwap::from_pointee(opted_out),
+            opt_out_write: Mutex::default(),
+            opt_out_path,
+        })
+    }
+
+    pub fn mode(&self) -> PrivacyMode {
+        self.mode
+    }
+
+    fn id(&self, ip: IpAddr) -> Option<String> {
+        match self.mode {
+            PrivacyMode::Full => Some(ip.to_string()),
+            PrivacyMode::Anonymized => Some(mask_ip(ip, 24, 48).to_string()),
+            PrivacyMode::Hashed => {
+                let mut hasher = Sha256::new();
+                hasher.update(self.key);
+                hasher.update(ip.to_string());
+                let digest = hasher.finalize();
+                Some(digest[..8].iter().map(|b| format!("{b:02x}")).join(""))
+            }
+            PrivacyMode::Domains | PrivacyMode::Off => None,
+        }
+    }
+
+    /// Client id recorded for queries from `ip`, none when clients are not recorded
+    pub fn client_id(&self, ip: IpAddr) -> Option<String> {
+        if self.is_opted_out(ip) {
+            return None;
+        }
+
+        self.id(ip)
+    }
+
+    /// Client id whose logs `ip` may see in the self-service view. Anonymized ids are
+    /// shared by a whole subnet, so they are not shown there.
+    pub fn own_id(&self, ip: IpAddr) -> Option<String> {
+        match self.mode {
+            PrivacyMode::Full | PrivacyMode::Hashed => self.id(ip),
+            _ => None,
+        }
+    }
+
+    /// Client id an operator search for `client` refers to, which may be given as an IP
+    pub fn search_id(&self, client: &str) -> String {
+        match client.parse() {
+            Ok(ip) => self.id(ip).unwrap_or_default(),
+            Err(_) => client.to_string(),
+        }
+    }
+
+    /// Client address sent to dnstap, anonymized like the client ids, and left out when those
+    /// are hashed or not recorded
+    pub fn tap_peer(&self, peer: SocketAddr) -> Option<SocketAddr> {
+        match self.mode {
+            PrivacyMode::Full => Some(peer),
+            PrivacyMode::Anonymized => Some(SocketAddr::new(mask_ip(peer.ip(), 24, 48), 0)),
+            _ => None,
+        }
+    }
+
+    /// Whether stats keep the names queried
+    pub fn keeps_domains(&self) -> bool {
+        self.mode != PrivacyMode::Off
+    }
+
+    pub fn is_opted_out(&self, ip: IpAddr) -> bool {
+        self.opted_out.load().contains(&opt_out_key(ip))
+    }
+
+    /// Stop or resume recording queries from `ip`, and the rest of its /64 for IPv6
+    pub fn set_opted_out(&self, ip: IpAddr, opted_out: bool) -> Result<(), OptOutError> {
+        let _write = self.opt_out_write.lock().unwrap();
+        let key = opt_out_key(ip);
+        let current = self.opted_out.load_full();
+        if current.contains(&key) == opted_out {
+            return Ok(());
+        }
+        if opted_out && current.len() >= MAX_OPT_OUTS {
+            return Err(OptOutError::Full);
+        }
+
+        let mut set = HashSet::clone(&current);
+        if opted_out {
+            set.insert(key);
+        } else {
+            set.remove(&key);
+        }
+        let set = Arc::new(set);
+        self.opted_out.store(set.clone());
+        tracing::info!("query log opt-out for {key}: {opted_out}");
+
+        if let Some(path) = &self.opt_out_path {
+            let tmp = path.with_extension("tmp");
+            std::fs::write(&tmp, serde_json::to_vec(&*set)?)?;
+            std::fs::rename(tmp, path)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Privacy {
+    fn default() -> Self {
+        Self {
+            mode: PrivacyMode::Full,
+            key: rand::random(),
+            opted_out: ArcSwap::default(),
+            opt_out_write: Mutex::default(),
+            opt_out_path: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_identifies_clients_per_mode() {
+        let ip: IpAddr = "192.168.1.100".parse().unwrap();
+
+        let full = Privacy::new(PrivacyMode::Full, None, None).unwrap();
+        assert_eq!(full.client_id(ip).as_deref(), Some("192.168.1.100"));
+
+        let anonymized = Privacy::new(PrivacyMode::Anonymized, None, None).unwrap();
+        assert_eq!(anonymized.client_id(ip).as_deref(), Some("192.168.1.0"));
+        assert_eq!(anonymized.own_id(ip), None);
+        assert_eq!(anonymized.search_id("192.168.1.7"), "192.168.1.0");
+        assert_eq!(
+            anonymized.tap_peer("192.168.1.100:5353".parse().unwrap()),
+            Some("192.168.1.0:0".parse().unwrap())
+        );
+
+        let hashed = Privacy::new(PrivacyMode::Hashed, Some("key"), None).unwrap();
+        let id = hashed.client_id(ip).unwrap();
+        assert_eq!(id.len(), 16);
+        assert_ne!(id, "192.168.1.100");
+        assert_eq!(hashed.own_id(ip), Some(id.clone()));
+        assert_eq!(hashed.search_id("192.168.1.100"), id);
+        assert_eq!(hashed.tap_peer("192.168.1.100:5353".parse().unwrap()), None);
+        let other_key = Privacy::new(PrivacyMode::Hashed, Some("other"), None).unwrap();
+        assert_ne!(other_key.client_id(ip), Some(id));
+
+        let domains = Privacy::new(PrivacyMode::Domains, None, None).unwrap();
+        assert_eq!(domains.client_id(ip), None);
+        assert!(domains.keeps_domains());
+        assert!(!Privacy::new(PrivacyMode::Off, None, None)
+            .unwrap()
+            .keeps_domains());
+    }
+
+    #[test]
+    fn it_persists_opt_outs() {
+        let path = std::env::temp_dir().join(format!("bancuh-opt-out-{}.json", std::process::id()));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        let privacy = Privacy::new(PrivacyMode::Full, None, Some(path.clone())).unwrap();
+        privacy.set_opted_out(ip, true).unwrap();
+        assert_eq!(privacy.client_id(ip), None);
+
+        let reopened = Privacy::new(PrivacyMode::Full, None, Some(path.clone())).unwrap();
+        assert!(reopened.is_opted_out(ip));
+        reopened.set_opted_out(ip, false).unwrap();
+        assert_eq!(reopened.client_id(ip).as_deref(), Some("10.0.0.1"));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn it_bounds_opt_outs() {
+        let privacy = Privacy::default();
+
+        // any address of an IPv6 /64 opts out all of it
+        let ip: IpAddr = "2001:db8:0:1::1".parse().unwrap();
+        privacy.set_opted_out(ip, true).unwrap();
+        assert!(privacy.is_opted_out("2001:db8:0:1:ffff::2".parse().unwrap()));
+        assert!(!privacy.is_opted_out("2001:db8:0:2::1".parse().unwrap()));
+        assert_eq!(privacy.opted_out.load().len(), 1);
+
+        for i in 1..MAX_OPT_OUTS as u32 {
+            privacy
+                .set_opted_out(IpAddr::from(i.to_be_bytes()), true)
+                .unwrap();
+        }
+        let more: IpAddr = "192.0.2.1".parse().unwrap();
+        assert!(matches!(
+            privacy.set_opted_out(more, true),
+            Err(OptOutError::Full)
+        ));
+        // opting back in still works once full
+        privacy.set_opted_out(ip, false).unwrap();
+        privacy.set_opted_out(more, true).unwrap();
+    }
+}
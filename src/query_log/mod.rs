@@ -0,0 +1,388 @@
+mod segments;
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use tokio::sync::broadcast;
+
+use crate::{metrics::Outcome, privacy::Privacy};
+
+pub use self::segments::SegmentLog;
+
+/// Entries buffered for each live subscriber, a slower one skips ahead
+const LIVE_CAPACITY: usize = 1024;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct QueryLog {
+    pub query_time: DateTime<Utc>,
+    pub question: String,
+    pub answer: String,
+    pub outcome: Outcome,
+    /// Response code sent, none when the query was dropped
+    #[serde(default)]
+    pub rcode: Option<String>,
+    #[serde(default)]
+    pub latency_ms: f64,
+    #[serde(default)]
+    pub transport: String,
+    #[serde(default)]
+    pub query_id: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream: Option<String>,
+}
+
+/// Deserialize an optional value from its string form, treating an empty one as unset,
+/// as sent for blank fields of an HTML form
+pub fn non_empty<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    let s: Option<String> = serde::Deserialize::deserialize(deserializer)?;
+    match s.as_deref().map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(s) => s.parse().map(Some).map_err(serde::de::Error::custom),
+    }
+}
+
+/// Like `non_empty`, also accepting the `2024-01-31T12:00` form of a datetime-local input, as UTC
+fn non_empty_time<'de, D>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s: Option<String> = serde::Deserialize::deserialize(deserializer)?;
+    let s = match s.as_deref().map(str::trim) {
+        None | Some("") => return Ok(None),
+        Some(s) => s,
+    };
+
+    DateTime::parse_from_rfc3339(s)
+        .map(|t| t.with_timezone(&Utc))
+        .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M").map(|t| t.and_utc()))
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+/// Criteria for searching the logs of all clients, unset fields match anything
+#[derive(serde::Deserialize, Debug, Default, Clone)]
+pub struct LogFilter {
+    #[serde(default, deserialize_with = "non_empty")]
+    pub client: Option<String>,
+    #[serde(default, deserialize_with = "non_empty")]
+    pub domain: Option<String>,
+    #[serde(default, deserialize_with = "non_empty")]
+    pub outcome: Option<Outcome>,
+    #[serde(default, deserialize_with = "non_empty_time")]
+    pub from: Option<DateTime<Utc>>,
+    #[serde(default, deserialize_with = "non_empty_time")]
+    pub to: Option<DateTime<Utc>>,
+    #[serde(default, deserialize_with = "non_empty")]
+    pub rcode: Option<String>,
+    #[serde(default, deserialize_with = "non_empty")]
+    pub transport: Option<String>,
+    #[serde(default, deserialize_with = "non_empty")]
+    pub query_id: Option<u16>,
+    #[serde(default, deserialize_with = "non_empty")]
+    pub upstream: Option<String>,
+    /// Only queries that took at least this long
+    #[serde(default, deserialize_with = "non_empty")]
+    pub min_latency_ms: Option<f64>,
+}
+
+impl LogFilter {
+    pub fn matches(&self, client: &str, log: &QueryLog) -> bool {
+        self.client.as_deref().is_none_or(|c| c == client)
+            && self.domain.as_deref().is_none_or(|d| {
+                let name = log.question.split(' ').next().unwrap_or_default();
+                name.to_lowercase().contains(&d.to_lowercase())
+            })
+            && self.outcome.is_none_or(|o| o == log.outcome)
+            && self.from.is_none_or(|from| log.query_time >= from)
+            && self.to.is_none_or(|to| log.query_time <= to)
+            && self.rcode.as_deref().is_none_or(|r| {
+                log.rcode
+                    .as_deref()
+                    .is_some_and(|c| c.eq_ignore_ascii_case(r))
+            })
+            && self
+                .transport
+                .as_deref()
+                .is_none_or(|t| log.transport.eq_ignore_ascii_case(t))
+            && self.query_id.is_none_or(|id| id == log.query_id)
+            && self
+                .upstream
+                .as_deref()
+                .is_none_or(|u| log.upstream.as_deref().is_some_and(|l| l.contains(u)))
+            && self.min_latency_ms.is_none_or(|ms| log.latency_ms >= ms)
+    }
+}
+
+/// A log entry with the client it came from, identified as the privacy mode allows
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct ClientQueryLog {
+    pub client: String,
+    #[serde(flatten)]
+    pub log: QueryLog,
+}
+
+/// Recent queries of each client in memory, and optionally every query in an on-disk log
+#[derive(Debug)]
+pub struct QueryLogStore {
+    logs: Mutex<HashMap<String, VecDeque<QueryLog>>>,
+    max_age: Duration,
+    max_per_ip: usize,
+    segments: Option<SegmentLog>,
+    privacy: Arc<Privacy>,
+    live: broadcast::Sender<ClientQueryLog>,
+}
+
+impl QueryLogStore {
+    pub fn new(
+        max_age: Duration,
+        max_per_ip: usize,
+        segments: Option<SegmentLog>,
+        privacy: Arc<Privacy>,
+    ) -> Self {
+        Self {
+            logs: Mutex::default(),
+            max_age,
+            max_per_ip,
+            segments,
+            privacy,
+            live: broadcast::channel(LIVE_CAPACITY).0,
+        }
+    }
+
+    pub fn privacy(&self) -> &Privacy {
+        &self.privacy
+    }
+
+    /// How long queries are kept in memory
+    pub fn max_age(&self) -> Duration {
+        self.max_age
+    }
+
+    /// Record a query from `ip`, unless the privacy mode or an opt-out rules it out
+    pub fn insert(&self, ip: IpAddr, log: QueryLog) {
+        let Some(client) = self.privacy.client_id(ip) else {
+            return;
+        };
+
+        if self.segments.is_some() || self.live.receiver_count() > 0 {
+            let entry = ClientQueryLog {
+                client: client.clone(),
+                log: log.clone(),
+            };
+            let _ = self.live.send(entry.clone());
+            if let Some(segments) = &self.segments {
+                segments.append(entry);
+            }
+        }
+
+        let mut store = self.logs.lock().unwrap();
+        let entries = store.entry(client).or_default();
+
+        // Evict expired entries
+        let cutoff = Utc::now() - self.max_age;
+        while entries.front().is_some_and(|e| e.query_time < cutoff) {
+            entries.pop_front();
+        }
+
+        // Cap per-IP entries
+        while entries.len() >= self.max_per_ip.max(1) {
+            entries.pop_front();
+        }
+
+        entries.push_back(log);
+    }
+
+    /// Recent queries `ip` may see of its own
+    pub fn get_logs(&self, ip: IpAddr) -> Vec<QueryLog> {
+        let Some(client) = self.privacy.own_id(ip) else {
+            return Vec::new();
+        };
+        let store = self.logs.lock().unwrap();
+        let cutoff = Utc::now() - self.max_age;
+
+        store
+            .get(&client)
+            .map(|entries| {
+                entries
+                    .iter()
+                    .filter(|e| e.query_time >= cutoff)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Logs of all clients matching `filter`, newest first, at most `limit` of them.
+    /// Searches the on-disk log when there is one, so this may block on IO.
+    pub fn search(&self, filter: &LogFilter, limit: usize) -> Vec<ClientQueryLog> {
+        let filter = &self.client_filter(filter);
+        if let Some(segments) = &self.segments {
+            return segments.search(filter, limit);
+        }
+
+        let store = self.logs.lock().unwrap();
+        let cutoff = Utc::now() - self.max_age;
+
+        let mut found: Vec<_> = store
+            .iter()
+            .flat_map(|(client, entries)| entries.iter().map(move |log| (client, log)))
+            .filter(|(client, log)| log.query_time >= cutoff && filter.matches(client, log))
+            .map(|(client, log)| ClientQueryLog {
+                client: client.clone(),
+                log: log.clone(),
+            })
+            .collect();
+        found.sort_by_key(|q| std::cmp::Reverse(q.log.query_time));
+        found.truncate(limit);
+        found
+    }
+
+    /// `filter` with its client, which may be given as an IP, as the privacy mode records it
+    pub fn client_filter(&self, filter: &LogFilter) -> LogFilter {
+        LogFilter {
+            client: filter.client.as_deref().map(|c| self.privacy.search_id(c)),
+            ..filter.clone()
+        }
+    }
+
+    /// Entries of all clients as they are inserted
+    pub fn subscribe(&self) -> broadcast::Receiver<ClientQueryLog> {
+        self.live.subscribe()
+    }
+
+    /// Drop the in-memory logs of `ip`, after it opted out
+    pub fn forget(&self, ip: IpAddr) {
+        if let Some(client) = self.privacy.own_id(ip) {
+            self.logs.lock().unwrap().remove(&client);
+        }
+    }
+
+    pub fn active_ips(&self) -> usize {
+        let store = self.logs.lock().unwrap();
+        let cutoff = Utc::now() - self.max_age;
+
+        store
+            .values()
+            .filter(|entries| entries.back().is_some_and(|e| e.query_time >= cutoff))
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::privacy::PrivacyMode;
+
+    use super::*;
+
+    fn log(question: &str, outcome: Outcome) -> QueryLog {
+        QueryLog {
+            query_time: Utc::now(),
+            question: question.to_string(),
+            answer: String::new(),
+            outcome,
+            rcode: Some("NOERROR".to_string()),
+            latency_ms: 1.0,
+            transport: "udp".to_string(),
+            query_id: 1,
+            upstream: None,
+        }
+    }
+
+    #[test]
+    fn it_searches_across_clients() {
+        let store = QueryLogStore::new(
+            Duration::from_secs(600),
+            1000,
+            None,
+            Arc::new(Privacy::default()),
+        );
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        store.insert(a, log("ads.example.com. A", Outcome::Blocked));
+        store.insert(a, log("example.org. A", Outcome::Forwarded));
+        store.insert(b, log("cdn.example.com. AAAA", Outcome::Forwarded));
+
+        assert_eq!(store.search(&LogFilter::default(), 10).len(), 3);
+        assert_eq!(store.search(&LogFilter::default(), 2).len(), 2);
+
+        let filter = LogFilter {
+            domain: Some("EXAMPLE.com".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(store.search(&filter, 10).len(), 2);
+
+        let filter = LogFilter {
+            client: Some("10.0.0.1".to_string()),
+            outcome: Some(Outcome::Forwarded),
+            ..Default::default()
+        };
+        let found = store.search(&filter, 10);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].log.question, "example.org. A");
+
+        let filter = LogFilter {
+            from: Some(Utc::now() + chrono::Duration::minutes(1)),
+            ..Default::default()
+        };
+        assert!(store.search(&filter, 10).is_empty());
+
+        store.insert(
+            b,
+            QueryLog {
+                rcode: Some("SERVFAIL".to_string()),
+                latency_ms: 5000.0,
+                transport: "tls".to_string(),
+                query_id: 4242,
+                upstream: Some("1.1.1.1:53".to_string()),
+                ..log("slow.example.net. A", Outcome::Error)
+            },
+        );
+        let filter = LogFilter {
+            rcode: Some("servfail".to_string()),
+            transport: Some("TLS".to_string()),
+            upstream: Some("1.1.1.1".to_string()),
+            min_latency_ms: Some(1000.0),
+            ..Default::default()
+        };
+        let found = store.search(&filter, 10);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].log.query_id, 4242);
+        let filter = LogFilter {
+            query_id: Some(4242),
+            rcode: Some("NOERROR".to_string()),
+            ..Default::default()
+        };
+        assert!(store.search(&filter, 10).is_empty());
+    }
+
+    #[test]
+    fn it_streams_inserts_to_subscribers() {
+        let privacy = Privacy::new(PrivacyMode::Anonymized, None, None).unwrap();
+        let store = QueryLogStore::new(Duration::from_secs(600), 1000, None, Arc::new(privacy));
+        let mut live = store.subscribe();
+
+        store.insert(
+            "10.0.0.1".parse().unwrap(),
+            log("ads.example.com. A", Outcome::Blocked),
+        );
+        let entry = live.try_recv().unwrap();
+        assert_eq!(entry.client, "10.0.0.0");
+
+        let filter = store.client_filter(&LogFilter {
+            client: Some("10.0.0.99".to_string()),
+            ..Default::default()
+        });
+        assert!(filter.matches(&entry.client, &entry.log));
+    }
+}
@@ -0,0 +1,368 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+
+use super::{ClientQueryLog, LogFilter};
+
+const PREFIX: &str = "queries-";
+const SUFFIX: &str = ".jsonl";
+const HOUR_FORMAT: &str = "%Y%m%d%H";
+
+/// Entries waiting for the writer, more are dropped rather than slowing down queries
+const QUEUE_SIZE: usize = 10_000;
+
+/// How long written entries may sit in the buffer before they are flushed
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often retention is applied, besides whenever a new segment is started
+const RETENTION_INTERVAL: Duration = Duration::from_secs(60);
+
+fn segment_name(time: DateTime<Utc>) -> String {
+    format!("{PREFIX}{}{SUFFIX}", time.format(HOUR_FORMAT))
+}
+
+fn segment_start(name: &str) -> Option<DateTime<Utc>> {
+    let hour = name.strip_prefix(PREFIX)?.strip_suffix(SUFFIX)?;
+    let start = NaiveDateTime::parse_from_str(&format!("{hour}00"), "%Y%m%d%H%M").ok()?;
+
+    Some(start.and_utc())
+}
+
+#[derive(Debug)]
+struct Segment {
+    start: DateTime<Utc>,
+    path: PathBuf,
+    size: u64,
+}
+
+impl Segment {
+    fn end(&self) -> DateTime<Utc> {
+        self.start + chrono::Duration::hours(1)
+    }
+}
+
+/// Segments in `dir`, oldest first
+fn list_segments(dir: &Path) -> std::io::Result<Vec<Segment>> {
+    let mut segments = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(start) = segment_start(&name.to_string_lossy()) else {
+            continue;
+        };
+
+        segments.push(Segment {
+            start,
+            path: entry.path(),
+            size: entry.metadata()?.len(),
+        });
+    }
+    segments.sort_by_key(|s| s.start);
+
+    Ok(segments)
+}
+
+/// Appends entries to the segment of their hour, and applies retention
+struct Writer {
+    dir: PathBuf,
+    retention: Duration,
+    max_bytes: u64,
+    current: Option<(String, BufWriter<File>)>,
+}
+
+impl Writer {
+    fn run(mut self, receiver: Receiver<ClientQueryLog>) {
+        self.apply_retention();
+
+        let mut last_flush = Instant::now();
+        let mut last_retention = Instant::now();
+        loop {
+            match receiver.recv_timeout(FLUSH_INTERVAL) {
+                Ok(entry) => {
+                    if let Err(err) = self.write(&entry) {
+                        tracing::warn!("query log write failed: {err}");
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.flush();
+                    return;
+                }
+            }
+
+            if last_flush.elapsed() >= FLUSH_INTERVAL {
+                self.flush();
+                last_flush = Instant::now();
+            }
+            if last_retention.elapsed() >= RETENTION_INTERVAL {
+                self.apply_retention();
+                last_retention = Instant::now();
+            }
+        }
+    }
+
+    fn write(&mut self, entry: &ClientQueryLog) -> std::io::Result<()> {
+        let name = segment_name(entry.log.query_time);
+        if self
+            .current
+            .as_ref()
+            .is_none_or(|(current, _)| *current != name)
+        {
+            self.flush();
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.dir.join(&name))?;
+            self.current = Some((name, BufWriter::new(file)));
+            self.apply_retention();
+        }
+
+        let Some((_, writer)) = &mut self.current else {
+            return Ok(());
+        };
+        serde_json::to_writer(&mut *writer, entry)?;
+        writer.write_all(b"\n")
+    }
+
+    fn flush(&mut self) {
+        if let Some((_, writer)) = &mut self.current {
+            if let Err(err) = writer.flush() {
+                tracing::warn!("query log flush failed: {err}");
+            }
+        }
+    }
+
+    /// Remove segments older than the retention, then the oldest ones until the
+    /// log fits in `max_bytes`. The segment being written is always kept.
+    fn apply_retention(&mut self) {
+        let mut segments = match list_segments(&self.dir) {
+            Ok(segments) => segments,
+            Err(err) => {
+                tracing::warn!("query log retention failed: {err}");
+                return;
+            }
+        };
+        let current = self.current.as_ref().map(|(name, _)| self.dir.join(name));
+        segments.retain(|s| Some(&s.path) != current.as_ref());
+
+        let cutoff = Utc::now() - self.retention;
+        let mut total: u64 = segments.iter().map(|s| s.size).sum();
+        for segment in segments {
+            let expired = !self.retention.is_zero() && segment.end() <= cutoff;
+            let oversized = self.max_bytes > 0 && total > self.max_bytes;
+            if !expired && !oversized {
+                continue;
+            }
+
+            match std::fs::remove_file(&segment.path) {
+                Ok(()) => {
+                    tracing::info!("query log removed segment {}", segment.path.display());
+                    total -= segment.size;
+                }
+                Err(err) => tracing::warn!(
+                    "query log could not remove segment {}: {err}",
+                    segment.path.display()
+                ),
+            }
+        }
+    }
+}
+
+/// Query log on disk as JSON lines, in one segment file per hour
+#[derive(Debug)]
+pub struct SegmentLog {
+    dir: PathBuf,
+    sender: SyncSender<ClientQueryLog>,
+}
+
+impl SegmentLog {
+    /// Open the log in `dir`, keeping segments for `retention` and up to `max_bytes` in total,
+    /// zero for no limit. Entries are written by a background thread.
+    pub fn open(dir: PathBuf, retention: Duration, max_bytes: u64) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        let writer = Writer {
+            dir: dir.clone(),
+            retention,
+            max_bytes,
+            current: None,
+        };
+        std::thread::Builder::new()
+            .name("query-log-writer".to_string())
+            .spawn(move || writer.run(receiver))?;
+
+        Ok(Self { dir, sender })
+    }
+
+    pub fn append(&self, entry: ClientQueryLog) {
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(entry) {
+            tracing::warn!("query log writer is behind, dropping entry");
+        }
+    }
+
+    /// Entries matching `filter`, newest first, reading only the segments in its time range
+    /// and no older ones than needed for `limit` entries
+    pub fn search(&self, filter: &LogFilter, limit: usize) -> Vec<ClientQueryLog> {
+        let segments = match list_segments(&self.dir) {
+            Ok(segments) => segments,
+            Err(err) => {
+                tracing::warn!("query log search failed: {err}");
+                return Vec::new();
+            }
+        };
+
+        // a min-heap of the newest `limit` matches, so a busy hour is not held in memory whole
+        let mut found: BinaryHeap<Reverse<ByTime>> = BinaryHeap::new();
+        for segment in segments.iter().rev() {
+            if found.len() >= limit || filter.from.is_some_and(|from| segment.end() <= from) {
+                break;
+            }
+            if filter.to.is_some_and(|to| segment.start > to) {
+                continue;
+            }
+
+            let file = match File::open(&segment.path) {
+                Ok(file) => file,
+                Err(err) => {
+                    tracing::warn!("query log cannot read {}: {err}", segment.path.display());
+                    continue;
+                }
+            };
+            let entries = BufReader::new(file)
+                .lines()
+                .map_while(Result::ok)
+                .filter_map(|line| serde_json::from_str::<ClientQueryLog>(&line).ok())
+                .filter(|e| filter.matches(&e.client, &e.log));
+            for entry in entries {
+                found.push(Reverse(ByTime(entry)));
+                if found.len() > limit {
+                    found.pop();
+                }
+            }
+        }
+
+        // ascending by `Reverse`, so newest first
+        found
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse(ByTime(entry))| entry)
+            .collect()
+    }
+}
+
+/// Orders entries by query time only
+struct ByTime(ClientQueryLog);
+
+impl PartialEq for ByTime {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.log.query_time == other.0.log.query_time
+    }
+}
+
+impl Eq for ByTime {}
+
+impl PartialOrd for ByTime {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ByTime {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.log.query_time.cmp(&other.0.log.query_time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use crate::{metrics::Outcome, query_log::QueryLog};
+
+    use super::*;
+
+    fn entry(client: &str, question: &str, query_time: DateTime<Utc>) -> ClientQueryLog {
+        ClientQueryLog {
+            client: client.to_string(),
+            log: QueryLog {
+                query_time,
+                question: question.to_string(),
+                answer: "forwarded".to_string(),
+                outcome: Outcome::Forwarded,
+                rcode: Some("NOERROR".to_string()),
+                latency_ms: 1.0,
+                transport: "udp".to_string(),
+                query_id: 1,
+                upstream: None,
+            },
+        }
+    }
+
+    #[test]
+    fn it_writes_hourly_segments_and_searches_them() {
+        let dir = std::env::temp_dir().join(format!("bancuh-segments-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // an expired segment, removed by retention when the writer starts
+        let old = Utc::now() - chrono::Duration::days(10);
+        std::fs::write(dir.join(segment_name(old)), "").unwrap();
+
+        let log = SegmentLog::open(dir.clone(), Duration::from_secs(86400), 0).unwrap();
+        let now = Utc::now();
+        let hour_ago = now - chrono::Duration::hours(1);
+        log.append(entry("10.0.0.1", "a.example.com. A", hour_ago));
+        log.append(entry("10.0.0.2", "b.example.com. A", now));
+        log.append(entry("10.0.0.1", "c.example.org. A", now));
+        std::thread::sleep(FLUSH_INTERVAL * 2);
+
+        let segments = list_segments(&dir).unwrap();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(
+            segments[0].path.file_name(),
+            Some(segment_name(hour_ago).as_ref())
+        );
+
+        let found = log.search(&LogFilter::default(), 10);
+        let questions: Vec<_> = found.iter().map(|e| e.log.question.as_str()).collect();
+        assert_eq!(questions.len(), 3);
+        assert_eq!(questions[2], "a.example.com. A");
+
+        // the newest segment alone satisfies the limit, keeping its newest entries
+        let found = log.search(&LogFilter::default(), 2);
+        assert_eq!(found.len(), 2);
+        assert!(found.iter().all(|e| e.log.query_time == now));
+
+        let filter = LogFilter {
+            client: Some("10.0.0.1".to_string()),
+            domain: Some("example.com".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(log.search(&filter, 10).len(), 1);
+
+        let filter = LogFilter {
+            to: Some(hour_ago),
+            ..Default::default()
+        };
+        assert_eq!(log.search(&filter, 10).len(), 1);
+
+        assert_eq!(
+            segment_start(&segment_name(
+                Utc.with_ymd_and_hms(2024, 1, 31, 13, 5, 0).unwrap()
+            )),
+            Some(Utc.with_ymd_and_hms(2024, 1, 31, 13, 0, 0).unwrap())
+        );
+
+        drop(log);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,97 @@
+use std::{collections::HashSet, path::PathBuf, sync::Arc, time::Duration};
+
+use notify::{EventKind, RecursiveMode, Watcher};
+use tokio::{
+    sync::{mpsc, Notify},
+    time::Instant,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::engine::AdblockEngine;
+
+/// Wait after the first change before an update is triggered, so a burst of writes makes one
+const DEBOUNCE: Duration = Duration::from_secs(1);
+
+/// How often the watched files are synced with the ones the engine last used
+const RESYNC_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Watch the local configs and list files used by the engine, and notify
+/// `reload` when any of them changes.
+///
+/// Parent directories are watched rather than the files themselves, so
+/// editors that save by replacing the file are picked up too.
+pub async fn watch_files(
+    engine: Arc<AdblockEngine>,
+    reload: Arc<Notify>,
+    token: CancellationToken,
+) {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event {
+            let _ = tx.send(event);
+        }
+    });
+    let mut watcher = match watcher {
+        Ok(watcher) => watcher,
+        Err(err) => {
+            tracing::error!("file-watch could not start: {err}");
+            return;
+        }
+    };
+
+    let mut files: HashSet<PathBuf> = HashSet::new();
+    let mut dirs: HashSet<PathBuf> = HashSet::new();
+    // both are deadlines rather than timeouts, so unrelated events in a watched directory
+    // cannot keep putting them off
+    let mut resync = Instant::now();
+    let mut pending: Option<Instant> = None;
+    loop {
+        if resync <= Instant::now() {
+            resync = Instant::now() + RESYNC_INTERVAL;
+            let current: HashSet<_> = engine
+                .local_paths()
+                .iter()
+                .map(|p| std::fs::canonicalize(p).unwrap_or_else(|_| p.clone()))
+                .collect();
+            if current != files {
+                files = current;
+                for dir in files.iter().filter_map(|f| f.parent()) {
+                    if dirs.contains(dir) {
+                        continue;
+                    }
+                    match watcher.watch(dir, RecursiveMode::NonRecursive) {
+                        Ok(()) => {
+                            tracing::info!("file-watch watching {}", dir.display());
+                            dirs.insert(dir.to_path_buf());
+                        }
+                        Err(err) => {
+                            tracing::warn!("file-watch cannot watch {}: {err}", dir.display())
+                        }
+                    }
+                }
+            }
+        }
+
+        let wake = pending.map_or(resync, |deadline| deadline.min(resync));
+        tokio::select! {
+            Some(event) = rx.recv() => {
+                let relevant = !matches!(event.kind, EventKind::Access(_))
+                    && event.paths.iter().any(|p| files.contains(p));
+                if relevant && pending.is_none() {
+                    pending = Some(Instant::now() + DEBOUNCE);
+                }
+            }
+            _ = tokio::time::sleep_until(wake) => {
+                if pending.is_some_and(|deadline| deadline <= Instant::now()) {
+                    pending = None;
+                    tracing::info!("file-watch detected changes, triggering update");
+                    reload.notify_one();
+                }
+            }
+            _ = token.cancelled() => {
+                tracing::info!("file-watch received cancel signal");
+                return;
+            }
+        }
+    }
+}
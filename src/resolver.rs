@@ -1,20 +1,180 @@
-use std::net::{IpAddr, SocketAddr};
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
 
+use futures::{future::BoxFuture, stream::BoxStream, StreamExt};
 use hickory_resolver::{
     config::{NameServerConfig, ResolverConfig, ResolverOpts},
-    name_server::TokioConnectionProvider,
+    name_server::{ConnectionProvider, GenericConnection, TokioConnectionProvider},
     proto::{
         rr::{Record, RecordType},
-        xfer::Protocol,
+        runtime::TokioRuntimeProvider,
+        xfer::{DnsHandle, DnsRequest, DnsResponse, Protocol},
+        ProtoError,
     },
     ResolveError, Resolver as HickoryResolver,
 };
 use itertools::Itertools;
 
+use crate::{
+    dnstap::{Dnstap, Exchange},
+    metrics::Metrics,
+};
+
+/// Lookups remembered to tell cache hits apart, cleared of expired ones when full
+const DEADLINES_CAPACITY: usize = 4096;
+
+/// The upstream lookups made for one client query
+#[derive(Debug, Default)]
+struct QueryLookups {
+    /// Whether what goes upstream is sent to dnstap
+    tap: bool,
+    /// The upstream that sent the latest response
+    answered_by: Arc<Mutex<Option<SocketAddr>>>,
+}
+
+tokio::task_local! {
+    static LOOKUPS: QueryLookups;
+}
+
+/// Run the lookups for one client query, returning the upstream that answered the last one
+/// sent upstream. That is none when they were all answered from the cache, or when no upstream
+/// answered, e.g. on a timeout.
+///
+/// What goes upstream is sent to dnstap only if `tap`, so clients left out of dnstap stay out
+/// of the forwarder frames too. Lookups made outside of this, like the health probe, are not sent.
+pub async fn for_query<F: Future>(tap: bool, future: F) -> (F::Output, Option<SocketAddr>) {
+    let lookups = QueryLookups {
+        tap,
+        ..Default::default()
+    };
+    let answered_by = lookups.answered_by.clone();
+    let output = LOOKUPS.scope(lookups, future).await;
+    let answered_by = *answered_by.lock().unwrap();
+
+    (output, answered_by)
+}
+
+/// Connects to the upstreams, noting which one answered and copying the messages
+/// exchanged with them to dnstap
+#[derive(Clone)]
+pub struct UpstreamConnector {
+    inner: TokioConnectionProvider,
+    dnstap: Option<Arc<Dnstap>>,
+}
+
+impl ConnectionProvider for UpstreamConnector {
+    type Conn = UpstreamConnection;
+    type FutureConn = BoxFuture<'static, Result<UpstreamConnection, ProtoError>>;
+    type RuntimeProvider = TokioRuntimeProvider;
+
+    fn new_connection(
+        &self,
+        config: &NameServerConfig,
+        options: &ResolverOpts,
+    ) -> Result<Self::FutureConn, io::Error> {
+        let connect = self.inner.new_connection(config, options)?;
+        let upstream = config.socket_addr;
+        let protocol = config.protocol;
+        let dnstap = self.dnstap.clone();
+
+        Ok(Box::pin(async move {
+            Ok(UpstreamConnection {
+                inner: connect.await?,
+                upstream,
+                protocol,
+                dnstap,
+            })
+        }))
+    }
+}
+
+/// A connection to one upstream
+#[derive(Clone)]
+pub struct UpstreamConnection {
+    inner: GenericConnection,
+    upstream: SocketAddr,
+    protocol: Protocol,
+    dnstap: Option<Arc<Dnstap>>,
+}
+
+impl DnsHandle for UpstreamConnection {
+    type Response = BoxStream<'static, Result<DnsResponse, ProtoError>>;
+
+    fn is_verifying_dnssec(&self) -> bool {
+        self.inner.is_verifying_dnssec()
+    }
+
+    fn is_using_edns(&self) -> bool {
+        self.inner.is_using_edns()
+    }
+
+    fn send<R: Into<DnsRequest> + Unpin + Send + 'static>(&self, request: R) -> Self::Response {
+        let request = request.into();
+        let (tap, answered_by) = LOOKUPS
+            .try_with(|lookups| (lookups.tap, Some(lookups.answered_by.clone())))
+            .unwrap_or_default();
+        let dnstap = self.dnstap.clone().filter(|_| tap);
+        let upstream = self.upstream;
+        if dnstap.is_none() && answered_by.is_none() {
+            return self.inner.send(request).boxed();
+        }
+
+        let mut query = dnstap
+            .is_some()
+            .then(|| request.to_vec().unwrap_or_default());
+        let query_time = SystemTime::now();
+        let (peer, protocol) = (Some(upstream), self.protocol);
+
+        self.inner
+            .send(request)
+            .inspect(move |result| {
+                let response = result.as_ref().ok();
+                if let (Some(answered_by), Some(_)) = (&answered_by, response) {
+                    *answered_by.lock().unwrap() = Some(upstream);
+                }
+                let Some(dnstap) = &dnstap else {
+                    return;
+                };
+
+                // the query goes out with a new id, so it is tapped once the response tells which
+                if let Some(mut query) = query.take() {
+                    if let (Some(response), Some(id)) = (response, query.get_mut(..2)) {
+                        id.copy_from_slice(&response.id().to_be_bytes());
+                    }
+                    dnstap.forwarder_query(Exchange {
+                        peer,
+                        protocol,
+                        query_time,
+                        response_time: None,
+                        message: &query,
+                    });
+                }
+                if let Some(response) = response {
+                    dnstap.forwarder_response(Exchange {
+                        peer,
+                        protocol,
+                        query_time,
+                        response_time: Some(SystemTime::now()),
+                        message: response.as_buffer(),
+                    });
+                }
+            })
+            .boxed()
+    }
+}
+
 pub fn create_resolver(
     forwarders: &[IpAddr],
     port: &u16,
-) -> HickoryResolver<TokioConnectionProvider> {
+    timeout: Duration,
+    dnstap: Option<Arc<Dnstap>>,
+) -> HickoryResolver<UpstreamConnector> {
     tracing::info!(
         "Setting up forwarders: [{}] on port: {port}",
         forwarders.iter().join(", ")
@@ -28,22 +188,65 @@ pub fn create_resolver(
         config.add_name_server(name_server);
     });
 
-    let options = ResolverOpts::default();
+    let mut options = ResolverOpts::default();
+    options.timeout = timeout;
 
-    HickoryResolver::builder_with_config(config, TokioConnectionProvider::default())
+    let connector = UpstreamConnector {
+        inner: TokioConnectionProvider::default(),
+        dnstap,
+    };
+    HickoryResolver::builder_with_config(config, connector)
         .with_options(options)
         .build()
 }
 
 #[derive(Debug)]
 pub struct Resolver {
-    resolver: HickoryResolver<TokioConnectionProvider>,
+    resolver: HickoryResolver<UpstreamConnector>,
+    metrics: Arc<Metrics>,
+    deadlines: Mutex<HashMap<(String, RecordType), Instant>>,
 }
 
 impl Resolver {
-    pub fn new(forwarders: &[IpAddr], port: &u16) -> Self {
-        let resolver = create_resolver(forwarders, port);
-        Self { resolver }
+    pub fn new(
+        forwarders: &[IpAddr],
+        port: &u16,
+        timeout: Duration,
+        metrics: Arc<Metrics>,
+        dnstap: Option<Arc<Dnstap>>,
+    ) -> Self {
+        let resolver = create_resolver(forwarders, port, timeout, dnstap);
+        Self {
+            resolver,
+            metrics,
+            deadlines: Mutex::default(),
+        }
+    }
+
+    /// A cached lookup keeps the deadline it was stored with, while a fresh
+    /// one gets a new deadline, so seeing the same deadline again is a cache hit
+    fn is_cache_hit(&self, name: &str, query_type: RecordType, valid_until: Instant) -> bool {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        if deadlines.len() >= DEADLINES_CAPACITY {
+            let now = Instant::now();
+            deadlines.retain(|_, deadline| *deadline > now);
+            if deadlines.len() >= DEADLINES_CAPACITY {
+                deadlines.clear();
+            }
+        }
+
+        let previous = deadlines.insert((name.to_string(), query_type), valid_until);
+        previous == Some(valid_until)
+    }
+
+    /// Check that the upstream answers, by looking up the root name servers. Unlike `lookup`,
+    /// this is not counted in the metrics.
+    pub async fn probe(&self) -> Result<(), ResolveError> {
+        match self.resolver.lookup(".", RecordType::NS).await {
+            Ok(_) => Ok(()),
+            Err(err) if err.is_no_records_found() => Ok(()),
+            Err(err) => Err(err),
+        }
     }
 
     /// Lookup records from forward resolver
@@ -53,10 +256,26 @@ impl Resolver {
         name: &str,
         query_type: RecordType,
     ) -> Result<Vec<Record>, ResolveError> {
+        let start = Instant::now();
         match self.resolver.lookup(name, query_type).await {
-            Ok(lookup) => Ok(lookup.records().to_owned()),
-            Err(err) if err.is_no_records_found() && !err.is_nx_domain() => Ok(Vec::new()),
-            Err(err) => Err(err),
+            Ok(lookup) => {
+                if self.is_cache_hit(name, query_type, lookup.valid_until()) {
+                    self.metrics.record_cache_hit();
+                } else {
+                    self.metrics.record_upstream(start.elapsed(), true);
+                }
+                Ok(lookup.records().to_owned())
+            }
+            Err(err) if err.is_no_records_found() && !err.is_nx_domain() => {
+                self.metrics.record_upstream(start.elapsed(), true);
+                Ok(Vec::new())
+            }
+            Err(err) => {
+                // an NXDomain is still an answer, only failures count as errors
+                self.metrics
+                    .record_upstream(start.elapsed(), err.is_nx_domain());
+                Err(err)
+            }
         }
     }
 }
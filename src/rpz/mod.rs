@@ -0,0 +1,384 @@
+mod server;
+mod tsig;
+
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use arc_swap::ArcSwapOption;
+use hickory_proto::{rr::Name, ProtoError};
+use thiserror::Error;
+use tokio_util::sync::CancellationToken;
+
+pub use self::tsig::TsigKey;
+use crate::{
+    config::Category,
+    db::{AdblockDB, DBError},
+    engine::AdblockEngine,
+    export::{policies, policy_of, rpz_target},
+    rate_limiter::mask_ip,
+};
+
+/// Changes kept for IXFR, older serials get the whole zone
+const JOURNAL_SIZE: usize = 10;
+
+#[derive(Error, Debug)]
+pub enum RpzError {
+    #[error("IOError: {0}")]
+    IOError(#[from] std::io::Error),
+
+    #[error("ProtoError: {0}")]
+    Proto(#[from] ProtoError),
+
+    #[error(transparent)]
+    DB(#[from] DBError),
+
+    #[error("InvalidPrefix: expected an address or <address>/<length>, got {0}")]
+    InvalidPrefix(String),
+
+    #[error("InvalidTsigKey: expected [algorithm:]name:base64-secret for key {0}")]
+    InvalidTsigKey(String),
+
+    #[error("Tsig: {0}")]
+    Tsig(String),
+
+    #[error("Notify: {0}")]
+    Notify(String),
+
+    #[error("MessageTooLarge: {0} bytes do not fit in a TCP message")]
+    MessageTooLarge(usize),
+}
+
+/// An address range of the transfer ACL, e.g. `192.0.2.0/24`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpPrefix {
+    ip: IpAddr,
+    len: u8,
+}
+
+impl FromStr for IpPrefix {
+    type Err = RpzError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || RpzError::InvalidPrefix(s.to_string());
+        let (ip, len) = match s.split_once('/') {
+            Some((ip, len)) => (ip, Some(len)),
+            None => (s, None),
+        };
+        let ip: IpAddr = ip.parse().map_err(|_| invalid())?;
+        let max = if ip.is_ipv4() { 32 } else { 128 };
+        let len = match len {
+            Some(len) => len.parse().map_err(|_| invalid())?,
+            None => max,
+        };
+        if len > max {
+            return Err(invalid());
+        }
+
+        // a shift by the full width would overflow, and /0 matches any address anyway
+        let ip = if len == 0 { ip } else { mask_ip(ip, len, len) };
+        Ok(Self { ip, len })
+    }
+}
+
+impl IpPrefix {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        if ip.is_ipv4() != self.ip.is_ipv4() {
+            return false;
+        }
+        self.len == 0 || mask_ip(ip, self.len, self.len) == self.ip
+    }
+}
+
+/// CNAME records of the zone by owner, relative to the origin, and target
+type Records = Vec<(String, String)>;
+
+/// The records of a zone version, read from where they are stored rather than copied
+pub trait ZoneSource: Send + Sync {
+    /// Every record, in no particular order
+    fn records(&self) -> Box<dyn Iterator<Item = Result<(String, String), DBError>> + '_>;
+
+    /// The target of the record for `owner`, if there is one
+    fn target(&self, owner: &str) -> Result<Option<String>, DBError>;
+}
+
+/// The effective policy of a db, with the categories disabled at the time
+struct PolicySource {
+    db: Arc<AdblockDB>,
+    disabled: Arc<HashSet<Category>>,
+}
+
+impl ZoneSource for PolicySource {
+    fn records(&self) -> Box<dyn Iterator<Item = Result<(String, String), DBError>> + '_> {
+        Box::new(policies(&self.db, &self.disabled).map(|entry| {
+            let (name, policy) = entry?;
+            Ok((name.trim_end_matches('.').to_string(), rpz_target(&policy)))
+        }))
+    }
+
+    fn target(&self, owner: &str) -> Result<Option<String>, DBError> {
+        let policy = policy_of(&self.db, &format!("{owner}."), &self.disabled)?;
+        Ok(policy.as_ref().map(rpz_target))
+    }
+}
+
+/// The zone at one serial. Its records stay in the db they are read from, which is kept
+/// open for as long as the version is current or being transferred.
+pub struct ZoneVersion {
+    pub serial: u32,
+    source: Box<dyn ZoneSource>,
+}
+
+impl fmt::Debug for ZoneVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ZoneVersion")
+            .field("serial", &self.serial)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The changes from one serial to the next
+#[derive(Debug)]
+struct Delta {
+    from: u32,
+    to: u32,
+    removed: Records,
+    added: Records,
+}
+
+/// The RPZ zone, following the effective policy of the engine
+#[derive(Debug)]
+pub struct Zone {
+    origin: Name,
+    current: ArcSwapOption<ZoneVersion>,
+    /// Newest last
+    journal: Mutex<VecDeque<Arc<Delta>>>,
+}
+
+/// Records of `from` that `to` lacks or has another target for, sorted. Only the changes are
+/// collected, each side is streamed and looked up in the other.
+fn changed(from: &dyn ZoneSource, to: &dyn ZoneSource) -> Result<Records, DBError> {
+    let mut changed = Vec::new();
+    for record in from.records() {
+        let (owner, target) = record?;
+        if to.target(&owner)?.as_ref() != Some(&target) {
+            changed.push((owner, target));
+        }
+    }
+    changed.sort_unstable();
+    Ok(changed)
+}
+
+impl Zone {
+    pub fn new(origin: Name) -> Self {
+        Self {
+            origin,
+            current: ArcSwapOption::empty(),
+            journal: Mutex::default(),
+        }
+    }
+
+    pub fn origin(&self) -> &Name {
+        &self.origin
+    }
+
+    /// `None` until the first update
+    pub fn current(&self) -> Option<Arc<ZoneVersion>> {
+        self.current.load_full()
+    }
+
+    /// Replace the records, keeping the changes for IXFR. The serial is raised past the
+    /// current one if needed, and nothing changes when the records are the same. Returns the
+    /// new serial. Both versions are read in full, so this blocks.
+    pub fn update(&self, serial: u32, source: Box<dyn ZoneSource>) -> Result<Option<u32>, DBError> {
+        let Some(current) = self.current() else {
+            self.current
+                .store(Some(Arc::new(ZoneVersion { serial, source })));
+            return Ok(Some(serial));
+        };
+
+        let removed = changed(current.source.as_ref(), source.as_ref())?;
+        let added = changed(source.as_ref(), current.source.as_ref())?;
+        if removed.is_empty() && added.is_empty() {
+            return Ok(None);
+        }
+        let serial = if serial_gt(serial, current.serial) {
+            serial
+        } else {
+            current.serial.wrapping_add(1)
+        };
+
+        let mut journal = self.journal.lock().unwrap();
+        if journal.len() == JOURNAL_SIZE {
+            journal.pop_front();
+        }
+        journal.push_back(Arc::new(Delta {
+            from: current.serial,
+            to: serial,
+            removed,
+            added,
+        }));
+        self.current
+            .store(Some(Arc::new(ZoneVersion { serial, source })));
+        Ok(Some(serial))
+    }
+
+    /// The changes from `serial` to the current version, `None` when some are no longer kept
+    fn deltas_since(&self, serial: u32) -> Option<Vec<Arc<Delta>>> {
+        let journal = self.journal.lock().unwrap();
+        let start = journal.iter().position(|d| d.from == serial)?;
+        Some(journal.iter().skip(start).cloned().collect())
+    }
+}
+
+/// Serial number order (RFC 1982)
+fn serial_gt(a: u32, b: u32) -> bool {
+    a != b && a.wrapping_sub(b) < 1 << 31
+}
+
+/// Serves the zone to secondaries and tells them about new serials
+#[derive(Debug)]
+pub struct Rpz {
+    zone: Zone,
+    allow: Vec<IpPrefix>,
+    key: Option<TsigKey>,
+    notify: Vec<SocketAddr>,
+}
+
+impl Rpz {
+    pub fn new(
+        origin: Name,
+        allow: Vec<IpPrefix>,
+        key: Option<TsigKey>,
+        notify: Vec<SocketAddr>,
+    ) -> Self {
+        Self {
+            zone: Zone::new(origin),
+            allow,
+            key,
+            notify,
+        }
+    }
+
+    /// Rebuild the zone whenever the effective policy changes, until cancelled
+    pub async fn follow(self: Arc<Self>, engine: Arc<AdblockEngine>, token: CancellationToken) {
+        let mut changes = engine.watch_serial();
+        loop {
+            // an empty db before the first update is no policy to hand out
+            if engine.is_loaded() {
+                if let Err(err) = self.rebuild(&engine).await {
+                    tracing::warn!("Rebuilding the RPZ zone failed: {err}");
+                }
+            }
+
+            tokio::select! {
+                changed = changes.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                }
+                _ = token.cancelled() => {
+                    tracing::info!("rpz received cancel signal");
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Compare the effective policy with the current version, in a blocking task as both are
+    /// read in full. Only the changes are held in memory, for the journal.
+    async fn rebuild(self: &Arc<Self>, engine: &AdblockEngine) -> Result<(), RpzError> {
+        let serial = engine.serial();
+        let source = PolicySource {
+            db: engine.db(),
+            disabled: engine.disabled_categories(),
+        };
+        let rpz = self.clone();
+        let updated =
+            tokio::task::spawn_blocking(move || rpz.zone.update(serial, Box::new(source)))
+                .await
+                .map_err(std::io::Error::other)??;
+
+        if let Some(serial) = updated {
+            tracing::info!("RPZ zone {} at serial {serial}", self.zone.origin());
+            self.send_notify(serial).await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    impl ZoneSource for BTreeMap<String, String> {
+        fn records(&self) -> Box<dyn Iterator<Item = Result<(String, String), DBError>> + '_> {
+            Box::new(
+                self.iter()
+                    .map(|(owner, target)| Ok((owner.clone(), target.clone()))),
+            )
+        }
+
+        fn target(&self, owner: &str) -> Result<Option<String>, DBError> {
+            Ok(self.get(owner).cloned())
+        }
+    }
+
+    pub(super) fn source(names: &[(&str, &str)]) -> Box<dyn ZoneSource> {
+        let records: BTreeMap<_, _> = names
+            .iter()
+            .map(|(name, target)| (name.to_string(), target.to_string()))
+            .collect();
+        Box::new(records)
+    }
+
+    fn records(names: &[(&str, &str)]) -> Records {
+        names
+            .iter()
+            .map(|(name, target)| (name.to_string(), target.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn it_keeps_the_changes_between_versions() {
+        let zone = Zone::new(Name::from_ascii("rpz.bancuh.").unwrap());
+        let v1 = [("a.com", "."), ("b.com", ".")];
+        assert_eq!(zone.update(100, source(&v1)).unwrap(), Some(100));
+        assert_eq!(zone.update(101, source(&v1)).unwrap(), None);
+
+        // an older serial still makes a newer version
+        let v2 = source(&[("b.com", "rpz-passthru."), ("c.com", ".")]);
+        assert_eq!(zone.update(90, v2).unwrap(), Some(101));
+
+        let deltas = zone.deltas_since(100).unwrap();
+        assert_eq!(deltas.len(), 1);
+        assert_eq!((deltas[0].from, deltas[0].to), (100, 101));
+        assert_eq!(
+            deltas[0].removed,
+            records(&[("a.com", "."), ("b.com", ".")])
+        );
+        assert_eq!(
+            deltas[0].added,
+            records(&[("b.com", "rpz-passthru."), ("c.com", ".")])
+        );
+        assert!(zone.deltas_since(99).is_none());
+    }
+
+    #[test]
+    fn it_matches_addresses_in_prefixes() {
+        let prefix: IpPrefix = "10.1.0.0/16".parse().unwrap();
+        assert!(prefix.contains("10.1.2.3".parse().unwrap()));
+        assert!(!prefix.contains("10.2.0.1".parse().unwrap()));
+        assert!("0.0.0.0/0"
+            .parse::<IpPrefix>()
+            .unwrap()
+            .contains("192.0.2.1".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<IpPrefix>().is_err());
+    }
+}
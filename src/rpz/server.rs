@@ -0,0 +1,621 @@
+use std::{
+    iter::Peekable,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use hickory_proto::{
+    op::{Message, MessageType, OpCode, Query, ResponseCode},
+    rr::{
+        rdata::{CNAME, NS, SOA},
+        Name, RData, Record, RecordType,
+    },
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::{mpsc, Semaphore},
+};
+use tokio_util::sync::CancellationToken;
+
+use super::{tsig::ResponseSigner, Delta, Records, Rpz, RpzError, ZoneVersion};
+use crate::export::{RPZ_SOA_TIMERS, RPZ_TTL};
+
+/// Records in each message of a transfer, small enough for the longest names to fit in 64 KiB
+const RECORDS_PER_MESSAGE: usize = 100;
+
+/// Open TCP connections, each may be sending the whole zone
+const MAX_CONNECTIONS: usize = 16;
+
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(2);
+const NOTIFY_ATTEMPTS: usize = 3;
+
+fn soa(origin: &Name, serial: u32) -> Record {
+    let [refresh, retry, expire, minimum] = RPZ_SOA_TIMERS;
+    let localhost = Name::from_ascii("localhost.").unwrap();
+    let hostmaster = Name::from_ascii("hostmaster.localhost.").unwrap();
+    let soa = SOA::new(
+        localhost,
+        hostmaster,
+        serial,
+        refresh as i32,
+        retry as i32,
+        expire as i32,
+        minimum,
+    );
+    Record::from_rdata(origin.clone(), RPZ_TTL, RData::SOA(soa))
+}
+
+fn ns(origin: &Name) -> Record {
+    let localhost = Name::from_ascii("localhost.").unwrap();
+    Record::from_rdata(origin.clone(), RPZ_TTL, RData::NS(NS(localhost)))
+}
+
+/// `None` for a name that is not valid in DNS, which a secondary could not load anyway
+fn cname(origin: &Name, (owner, target): &(String, String)) -> Option<Record> {
+    let name = Name::from_ascii(owner).and_then(|name| name.append_domain(origin));
+    match (name, Name::from_ascii(target)) {
+        (Ok(name), Ok(alias)) => Some(Record::from_rdata(
+            name,
+            RPZ_TTL,
+            RData::CNAME(CNAME(alias)),
+        )),
+        _ => {
+            tracing::debug!("Leaving {owner} CNAME {target} out of the RPZ zone");
+            None
+        }
+    }
+}
+
+/// Records as a transfer takes them, reading one from the db can fail
+type RecordStream = Box<dyn Iterator<Item = Result<Record, RpzError>> + Send>;
+
+/// The records of a shared delta, without copying them up front
+fn cnames(
+    origin: &Name,
+    delta: Arc<Delta>,
+    records: fn(&Delta) -> &Records,
+) -> impl Iterator<Item = Result<Record, RpzError>> + Send {
+    let origin = origin.clone();
+    (0..records(&delta).len()).filter_map(move |i| cname(&origin, &records(&delta)[i]).map(Ok))
+}
+
+/// The records of a version, read from its db by a thread of their own as the transfer
+/// takes them, so the zone is never held in memory whole
+fn version_cnames(
+    origin: &Name,
+    version: Arc<ZoneVersion>,
+) -> impl Iterator<Item = Result<Record, RpzError>> + Send {
+    let (sender, receiver) = std::sync::mpsc::sync_channel(RECORDS_PER_MESSAGE);
+    let spawned = std::thread::Builder::new()
+        .name("rpz-transfer".to_string())
+        .spawn(move || {
+            for record in version.source.records() {
+                let failed = record.is_err();
+                // a closed channel is a transfer that was given up
+                if sender.send(record).is_err() || failed {
+                    return;
+                }
+            }
+        });
+
+    let origin = origin.clone();
+    spawned
+        .err()
+        .map(|err| Err(RpzError::from(err)))
+        .into_iter()
+        .chain(receiver.into_iter().filter_map(move |record| match record {
+            Ok(record) => cname(&origin, &record).map(Ok),
+            Err(err) => Some(Err(err.into())),
+        }))
+}
+
+/// The zone as an AXFR sends it, between two copies of the SOA
+fn axfr(
+    origin: &Name,
+    version: Arc<ZoneVersion>,
+) -> impl Iterator<Item = Result<Record, RpzError>> + Send {
+    let soa = soa(origin, version.serial);
+    [Ok(soa.clone()), Ok(ns(origin))]
+        .into_iter()
+        .chain(version_cnames(origin, version))
+        .chain([Ok(soa)])
+}
+
+/// The changes as an IXFR sends them: for each, the old SOA with the removed records and the
+/// new SOA with the added ones (RFC 1995)
+fn ixfr(
+    origin: &Name,
+    serial: u32,
+    deltas: Vec<Arc<Delta>>,
+) -> impl Iterator<Item = Result<Record, RpzError>> + Send {
+    let current = soa(origin, serial);
+    let origin = origin.clone();
+    let changes = deltas.into_iter().flat_map(move |delta| {
+        [Ok(soa(&origin, delta.from))]
+            .into_iter()
+            .chain(cnames(&origin, delta.clone(), |d| &d.removed))
+            .chain([Ok(soa(&origin, delta.to))])
+            .chain(cnames(&origin, delta, |d| &d.added))
+    });
+    [Ok(current.clone())]
+        .into_iter()
+        .chain(changes)
+        .chain([Ok(current)])
+}
+
+/// The messages answering a request, the records split over as many as needed
+struct Reply {
+    message: Message,
+    records: Peekable<RecordStream>,
+    signer: Option<ResponseSigner>,
+    done: bool,
+}
+
+impl Reply {
+    fn new(request: &Message, code: ResponseCode) -> Self {
+        let mut message = Message::new();
+        message
+            .set_id(request.id())
+            .set_message_type(MessageType::Response)
+            .set_op_code(request.op_code())
+            .set_authoritative(code == ResponseCode::NoError)
+            .set_recursion_desired(request.recursion_desired())
+            .set_response_code(code)
+            .add_queries(request.queries().iter().cloned());
+        let records: RecordStream = Box::new(std::iter::empty());
+        Self {
+            message,
+            records: records.peekable(),
+            signer: None,
+            done: false,
+        }
+    }
+
+    fn with_records(
+        mut self,
+        records: impl Iterator<Item = Result<Record, RpzError>> + Send + 'static,
+    ) -> Self {
+        let records: RecordStream = Box::new(records);
+        self.records = records.peekable();
+        self
+    }
+}
+
+impl Iterator for Reply {
+    type Item = Result<Vec<u8>, RpzError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let mut message = self.message.clone();
+        for record in self.records.by_ref().take(RECORDS_PER_MESSAGE) {
+            match record {
+                Ok(record) => {
+                    message.add_answer(record);
+                }
+                Err(err) => {
+                    // the rest would be taken for the whole zone
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+        self.done = self.records.peek().is_none();
+
+        Some(match &mut self.signer {
+            Some(signer) => signer.sign(&mut message),
+            None => message.to_vec().map_err(RpzError::from),
+        })
+    }
+}
+
+impl Rpz {
+    /// The answer to a request from `src`, `None` for one that gets no answer
+    fn reply(&self, request: &[u8], src: IpAddr, tcp: bool) -> Option<Reply> {
+        let message = match Message::from_vec(request) {
+            Ok(message) => message,
+            Err(err) => {
+                tracing::debug!("Dropping a malformed RPZ request from {src}: {err}");
+                return None;
+            }
+        };
+        if message.message_type() != MessageType::Query {
+            return None;
+        }
+
+        if !self.allow.iter().any(|prefix| prefix.contains(src)) {
+            tracing::warn!("Refusing an RPZ request from {src}, not in RPZ_ALLOW_TRANSFER");
+            return Some(Reply::new(&message, ResponseCode::Refused));
+        }
+
+        let signed = !message.signature().is_empty();
+        let signer = match (&self.key, signed) {
+            (Some(key), true) => match key.verify(request) {
+                Ok(mac) => Some(key.response_signer(mac)),
+                Err(err) => {
+                    tracing::warn!("Rejecting an RPZ request from {src}: {err}");
+                    return Some(Reply::new(&message, ResponseCode::NotAuth));
+                }
+            },
+            (Some(_), false) => {
+                tracing::warn!("Refusing an unsigned RPZ request from {src}");
+                return Some(Reply::new(&message, ResponseCode::Refused));
+            }
+            (None, true) => {
+                tracing::warn!("Rejecting a signed RPZ request from {src}, no RPZ_TSIG_KEY set");
+                return Some(Reply::new(&message, ResponseCode::NotAuth));
+            }
+            (None, false) => None,
+        };
+
+        let mut reply = self.answer(&message, src, tcp);
+        reply.signer = signer;
+        Some(reply)
+    }
+
+    fn answer(&self, request: &Message, src: IpAddr, tcp: bool) -> Reply {
+        let origin = self.zone.origin();
+        if request.op_code() != OpCode::Query {
+            return Reply::new(request, ResponseCode::NotImp);
+        }
+        let [query] = request.queries() else {
+            return Reply::new(request, ResponseCode::FormErr);
+        };
+        if query.name() != origin {
+            return Reply::new(request, ResponseCode::Refused);
+        }
+        let Some(version) = self.zone.current() else {
+            return Reply::new(request, ResponseCode::ServFail);
+        };
+        let serial = version.serial;
+        let reply = Reply::new(request, ResponseCode::NoError);
+
+        match query.query_type() {
+            RecordType::SOA => reply.with_records([Ok(soa(origin, serial))].into_iter()),
+            RecordType::NS => reply.with_records([Ok(ns(origin))].into_iter()),
+            RecordType::AXFR if !tcp => Reply::new(request, ResponseCode::FormErr),
+            RecordType::AXFR => {
+                tracing::info!("RPZ AXFR of {origin} at serial {serial} to {src}");
+                reply.with_records(axfr(origin, version))
+            }
+            RecordType::IXFR => {
+                let known = request.name_servers().iter().find_map(|r| match r.data() {
+                    RData::SOA(soa) => Some(soa.serial()),
+                    _ => None,
+                });
+                let Some(known) = known else {
+                    return Reply::new(request, ResponseCode::FormErr);
+                };
+
+                // over UDP, the current SOA tells the secondary to come back over TCP
+                if known == serial || !tcp {
+                    return reply.with_records([Ok(soa(origin, serial))].into_iter());
+                }
+                match self.zone.deltas_since(known) {
+                    Some(deltas) => {
+                        tracing::info!(
+                            "RPZ IXFR of {origin} from serial {known} to {serial} to {src}"
+                        );
+                        reply.with_records(ixfr(origin, serial, deltas))
+                    }
+                    None => {
+                        tracing::info!(
+                            "RPZ IXFR of {origin} from unknown serial {known}, sending serial {serial} in full to {src}"
+                        );
+                        reply.with_records(axfr(origin, version))
+                    }
+                }
+            }
+            _ => reply,
+        }
+    }
+
+    /// Answer SOA queries and IXFR probes over UDP until cancelled
+    pub async fn serve_udp(self: Arc<Self>, socket: UdpSocket, token: CancellationToken) {
+        let mut buf = vec![0; 4096];
+        loop {
+            let received = tokio::select! {
+                received = socket.recv_from(&mut buf) => received,
+                _ = token.cancelled() => return,
+            };
+            let (n, src) = match received {
+                Ok(received) => received,
+                Err(err) => {
+                    tracing::warn!("RPZ UDP receive failed: {err}");
+                    continue;
+                }
+            };
+
+            let Some(mut reply) = self.reply(&buf[..n], src.ip(), false) else {
+                continue;
+            };
+            match reply.next() {
+                Some(Ok(response)) => {
+                    if let Err(err) = socket.send_to(&response, src).await {
+                        tracing::warn!("RPZ UDP send to {src} failed: {err}");
+                    }
+                }
+                Some(Err(err)) => tracing::warn!("RPZ answer to {src} failed: {err}"),
+                None => {}
+            }
+        }
+    }
+
+    /// Serve transfers over TCP until cancelled, to at most `MAX_CONNECTIONS` clients at a time
+    pub async fn serve_tcp(
+        self: Arc<Self>,
+        listener: TcpListener,
+        timeout: Duration,
+        token: CancellationToken,
+    ) {
+        let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = token.cancelled() => return,
+            };
+            let (stream, src) = match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    tracing::warn!("RPZ TCP accept failed: {err}");
+                    continue;
+                }
+            };
+            let Ok(permit) = connections.clone().try_acquire_owned() else {
+                tracing::warn!("Closing an RPZ connection from {src}, {MAX_CONNECTIONS} are open");
+                continue;
+            };
+
+            let rpz = self.clone();
+            let token = token.clone();
+            tokio::spawn(async move {
+                let _permit = permit;
+                tokio::select! {
+                    result = rpz.connection(stream, src, timeout) => {
+                        if let Err(err) = result {
+                            tracing::warn!("RPZ transfer to {src} failed: {err}");
+                        }
+                    }
+                    _ = token.cancelled() => {}
+                }
+            });
+        }
+    }
+
+    async fn connection(
+        self: Arc<Self>,
+        mut stream: TcpStream,
+        src: SocketAddr,
+        timeout: Duration,
+    ) -> Result<(), RpzError> {
+        loop {
+            let len = match tokio::time::timeout(timeout, stream.read_u16()).await {
+                Err(_) => return Ok(()),
+                Ok(Err(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+                Ok(len) => len?,
+            };
+            let mut request = vec![0; len as usize];
+            tokio::time::timeout(timeout, stream.read_exact(&mut request))
+                .await
+                .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+
+            let Some(reply) = self.reply(&request, src.ip(), true) else {
+                continue;
+            };
+
+            // a whole zone takes a while to encode and sign, so it runs off the async threads
+            let (sender, mut receiver) = mpsc::channel(8);
+            tokio::task::spawn_blocking(move || {
+                for message in reply {
+                    let failed = message.is_err();
+                    if sender.blocking_send(message).is_err() || failed {
+                        return;
+                    }
+                }
+            });
+            while let Some(message) = receiver.recv().await {
+                let message = message?;
+                let len = u16::try_from(message.len())
+                    .map_err(|_| RpzError::MessageTooLarge(message.len()))?;
+                stream.write_u16(len).await?;
+                stream.write_all(&message).await?;
+            }
+        }
+    }
+
+    /// Tell the secondaries about a new serial
+    pub(super) async fn send_notify(&self, serial: u32) {
+        let sent = self
+            .notify
+            .iter()
+            .map(|target| self.notify_one(*target, serial));
+        let results = futures::future::join_all(sent).await;
+        for (target, result) in self.notify.iter().zip(results) {
+            match result {
+                Ok(()) => tracing::info!("Sent RPZ NOTIFY for serial {serial} to {target}"),
+                Err(err) => tracing::warn!("RPZ NOTIFY to {target} failed: {err}"),
+            }
+        }
+    }
+
+    async fn notify_one(&self, target: SocketAddr, serial: u32) -> Result<(), RpzError> {
+        let bind: SocketAddr = if target.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = UdpSocket::bind(bind).await?;
+        socket.connect(target).await?;
+
+        let origin = self.zone.origin();
+        let id = rand::random();
+        let mut message = Message::new();
+        message
+            .set_id(id)
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Notify)
+            .set_authoritative(true)
+            .add_query(Query::query(origin.clone(), RecordType::SOA))
+            .add_answer(soa(origin, serial));
+        if let Some(key) = &self.key {
+            key.sign_request(&mut message)?;
+        }
+        let message = message.to_vec()?;
+
+        let mut buf = [0; 4096];
+        for _ in 0..NOTIFY_ATTEMPTS {
+            socket.send(&message).await?;
+            let response = tokio::time::timeout(NOTIFY_TIMEOUT, async {
+                loop {
+                    let n = socket.recv(&mut buf).await?;
+                    // a stray datagram is not the answer to this notify
+                    match Message::from_vec(&buf[..n]) {
+                        Ok(response) if response.id() == id => {
+                            return Ok::<_, RpzError>(response);
+                        }
+                        _ => continue,
+                    }
+                }
+            })
+            .await;
+
+            match response {
+                Ok(response) => {
+                    let code = response?.response_code();
+                    return match code {
+                        ResponseCode::NoError => Ok(()),
+                        code => Err(RpzError::Notify(code.to_string())),
+                    };
+                }
+                Err(_) => continue,
+            }
+        }
+        Err(RpzError::Notify(format!(
+            "no answer after {NOTIFY_ATTEMPTS} attempts"
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rpz::{tests::source, IpPrefix, TsigKey};
+
+    use super::*;
+
+    fn request(query_type: RecordType, known: Option<u32>, key: Option<&TsigKey>) -> Vec<u8> {
+        let origin = Name::from_ascii("rpz.bancuh.").unwrap();
+        let mut message = Message::new();
+        message
+            .set_id(1)
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .add_query(Query::query(origin.clone(), query_type));
+        if let Some(known) = known {
+            message.add_name_server(soa(&origin, known));
+        }
+        if let Some(key) = key {
+            key.sign_request(&mut message).unwrap();
+        }
+        message.to_vec().unwrap()
+    }
+
+    /// The answers of all messages, and the response code of the first
+    fn answers(reply: Option<Reply>) -> (ResponseCode, Vec<String>) {
+        let messages: Vec<_> = reply
+            .unwrap()
+            .map(|m| Message::from_vec(&m.unwrap()).unwrap())
+            .collect();
+        let records = messages
+            .iter()
+            .flat_map(|m| m.answers())
+            .map(|r| match r.data() {
+                RData::SOA(soa) => format!("SOA {}", soa.serial()),
+                RData::CNAME(target) => format!("{} {}", r.name(), target.0),
+                data => format!("{} {data}", r.record_type()),
+            })
+            .collect();
+        (messages[0].response_code(), records)
+    }
+
+    #[test]
+    fn it_transfers_the_zone() {
+        let key: TsigKey = "transfer.:c2VjcmV0c2VjcmV0c2VjcmV0".parse().unwrap();
+        let rpz = Rpz::new(
+            Name::from_ascii("rpz.bancuh.").unwrap(),
+            vec!["10.0.0.0/8".parse::<IpPrefix>().unwrap()],
+            Some(key.clone()),
+            Vec::new(),
+        );
+        let client: IpAddr = "10.0.0.2".parse().unwrap();
+        let signed = |query_type, known| request(query_type, known, Some(&key));
+
+        let (code, _) = answers(rpz.reply(&signed(RecordType::SOA, None), client, false));
+        assert_eq!(code, ResponseCode::ServFail);
+
+        rpz.zone.update(10, source(&[("a.com", ".")])).unwrap();
+        let v2 = source(&[("b.com", "rpz-passthru.")]);
+        rpz.zone.update(11, v2).unwrap();
+
+        let (code, records) = answers(rpz.reply(&signed(RecordType::AXFR, None), client, true));
+        assert_eq!(code, ResponseCode::NoError);
+        assert_eq!(
+            records,
+            [
+                "SOA 11",
+                "NS localhost.",
+                "b.com.rpz.bancuh. rpz-passthru.",
+                "SOA 11"
+            ]
+        );
+
+        let (_, records) = answers(rpz.reply(&signed(RecordType::IXFR, Some(10)), client, true));
+        assert_eq!(
+            records,
+            [
+                "SOA 11",
+                "SOA 10",
+                "a.com.rpz.bancuh. .",
+                "SOA 11",
+                "b.com.rpz.bancuh. rpz-passthru.",
+                "SOA 11"
+            ]
+        );
+        let (_, records) = answers(rpz.reply(&signed(RecordType::IXFR, Some(11)), client, true));
+        assert_eq!(records, ["SOA 11"]);
+
+        // outside the ACL, unsigned or over UDP
+        let (code, _) =
+            answers(rpz.reply(&signed(RecordType::AXFR, None), [192, 0, 2, 1].into(), true));
+        assert_eq!(code, ResponseCode::Refused);
+        let unsigned = request(RecordType::AXFR, None, None);
+        let (code, _) = answers(rpz.reply(&unsigned, client, true));
+        assert_eq!(code, ResponseCode::Refused);
+        let (code, _) = answers(rpz.reply(&signed(RecordType::AXFR, None), client, false));
+        assert_eq!(code, ResponseCode::FormErr);
+    }
+
+    #[test]
+    fn it_splits_large_transfers() {
+        let rpz = Rpz::new(
+            Name::from_ascii("rpz.bancuh.").unwrap(),
+            vec!["127.0.0.1".parse().unwrap()],
+            None,
+            Vec::new(),
+        );
+        let names: Vec<_> = (0..250).map(|i| format!("{i:03}.example.com")).collect();
+        let records: Vec<_> = names.iter().map(|name| (name.as_str(), ".")).collect();
+        rpz.zone.update(1, source(&records)).unwrap();
+
+        let reply = rpz.reply(
+            &request(RecordType::AXFR, None, None),
+            Ipv4Addr::LOCALHOST.into(),
+            true,
+        );
+        let messages: Vec<_> = reply.unwrap().map(Result::unwrap).collect();
+        // 250 names between the SOAs and the NS
+        assert_eq!(messages.len(), 3);
+    }
+}
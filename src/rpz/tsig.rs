@@ -0,0 +1,198 @@
+use std::{fmt, str::FromStr};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use chrono::Utc;
+use hickory_proto::{
+    dnssec::{
+        rdata::tsig::{make_tsig_record, TsigAlgorithm, TSIG},
+        tsig::TSigner,
+    },
+    op::Message,
+    rr::Name,
+    serialize::binary::BinEncoder,
+};
+
+use super::RpzError;
+
+/// Seconds a signed message may be off from the clock
+const FUDGE: u16 = 300;
+
+/// A TSIG key, written like `dig -y`: `[algorithm:]name:base64-secret`, hmac-sha256 by default
+#[derive(Clone)]
+pub struct TsigKey(TSigner);
+
+impl FromStr for TsigKey {
+    type Err = RpzError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || RpzError::InvalidTsigKey(s.split(':').next().unwrap_or_default().into());
+        let parts: Vec<_> = s.split(':').collect();
+        let (algorithm, name, secret) = match parts[..] {
+            [name, secret] => ("hmac-sha256", name, secret),
+            [algorithm, name, secret] => (algorithm, name, secret),
+            _ => return Err(invalid()),
+        };
+
+        let algorithm = TsigAlgorithm::from_name(
+            Name::from_ascii(algorithm.to_ascii_lowercase()).map_err(|_| invalid())?,
+        );
+        let name = Name::from_ascii(name).map_err(|_| invalid())?;
+        let secret = BASE64_STANDARD.decode(secret).map_err(|_| invalid())?;
+        let signer = TSigner::new(secret, algorithm, name, FUDGE).map_err(|_| invalid())?;
+        Ok(Self(signer))
+    }
+}
+
+/// Only the key name, the secret stays out of logs
+impl fmt::Debug for TsigKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TsigKey({})", self.0.signer_name())
+    }
+}
+
+impl TsigKey {
+    pub fn name(&self) -> &Name {
+        self.0.signer_name()
+    }
+
+    /// Check the TSIG of a request, returning its MAC, which the signed responses chain on
+    pub fn verify(&self, request: &[u8]) -> Result<Vec<u8>, RpzError> {
+        let (mac, valid, _) = self
+            .0
+            .verify_message_byte(None, request, true)
+            .map_err(|err| RpzError::Tsig(err.to_string()))?;
+        let now = Utc::now().timestamp() as u64;
+        if !valid.contains(&now) {
+            return Err(RpzError::Tsig(
+                "signed at a time out of the fudge".to_string(),
+            ));
+        }
+        Ok(mac)
+    }
+
+    /// Sign a request, such as a NOTIFY
+    pub fn sign_request(&self, message: &mut Message) -> Result<(), RpzError> {
+        message.finalize(&self.0, Utc::now().timestamp() as u32)?;
+        Ok(())
+    }
+
+    /// Signs the messages of a response to a request with `request_mac`
+    pub fn response_signer(&self, request_mac: Vec<u8>) -> ResponseSigner {
+        ResponseSigner {
+            key: self.clone(),
+            previous_mac: request_mac,
+            first: true,
+        }
+    }
+}
+
+/// Signs each message of a response, every MAC covering the one before it (RFC 8945 5.3.1)
+#[derive(Debug)]
+pub struct ResponseSigner {
+    key: TsigKey,
+    previous_mac: Vec<u8>,
+    first: bool,
+}
+
+impl ResponseSigner {
+    /// Encode `message` with a TSIG record appended
+    pub fn sign(&mut self, message: &mut Message) -> Result<Vec<u8>, RpzError> {
+        let signer = &self.key.0;
+        let time = Utc::now().timestamp() as u64;
+        let tsig = TSIG::new(
+            signer.algorithm().clone(),
+            time,
+            signer.fudge(),
+            Vec::new(),
+            message.id(),
+            0,
+            Vec::new(),
+        );
+
+        // the message is encoded on its own, its compression pointers are offsets into it
+        let unsigned = message.to_vec()?;
+        let mut tbs = Vec::new();
+        let mut encoder = BinEncoder::new(&mut tbs);
+        encoder.emit_u16(self.previous_mac.len() as u16)?;
+        encoder.emit_vec(&self.previous_mac)?;
+        encoder.emit_vec(&unsigned)?;
+        if self.first {
+            tsig.emit_tsig_for_mac(&mut encoder, signer.signer_name())?;
+        } else {
+            // later messages only cover the timers
+            encoder.emit_u16((time >> 32) as u16)?;
+            encoder.emit_u32(time as u32)?;
+            encoder.emit_u16(signer.fudge())?;
+        }
+
+        let mac = signer
+            .sign(&tbs)
+            .map_err(|err| RpzError::Tsig(err.to_string()))?;
+        message.add_tsig(make_tsig_record(
+            signer.signer_name().clone(),
+            tsig.set_mac(mac.clone()),
+        ));
+        self.previous_mac = mac;
+        self.first = false;
+
+        Ok(message.to_vec()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hickory_proto::{
+        op::{MessageType, OpCode, Query},
+        rr::{rdata::CNAME, RData, Record, RecordType},
+    };
+
+    use super::*;
+
+    #[test]
+    fn it_signs_and_verifies_messages() {
+        let key: TsigKey = "hmac-sha256:transfer.:c2VjcmV0c2VjcmV0c2VjcmV0"
+            .parse()
+            .unwrap();
+        assert_eq!(key.name().to_ascii(), "transfer.");
+
+        let zone = Name::from_ascii("rpz.bancuh.").unwrap();
+        let query = Query::query(zone.clone(), RecordType::AXFR);
+        let mut request = Message::new();
+        request
+            .set_id(7)
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .add_query(query.clone());
+        key.sign_request(&mut request).unwrap();
+        let request = request.to_vec().unwrap();
+        let request_mac = key.verify(&request).unwrap();
+
+        let other: TsigKey = "transfer.:b3RoZXJvdGhlcm90aGVy".parse().unwrap();
+        assert!(other.verify(&request).is_err());
+
+        // a client checks the responses the same way, chaining on the MAC before. The names
+        // below the zone are compressed, so the MAC has to cover the message as sent.
+        let mut signer = key.response_signer(request_mac.clone());
+        let mut previous = request_mac;
+        for first in [true, false, false] {
+            let name = Name::from_ascii("ads.example.com").unwrap();
+            let record = Record::from_rdata(
+                name.append_domain(&zone).unwrap(),
+                300,
+                RData::CNAME(CNAME(Name::root())),
+            );
+            let mut response = Message::new();
+            response
+                .set_id(7)
+                .set_message_type(MessageType::Response)
+                .add_query(query.clone())
+                .add_answer(record);
+            let response = signer.sign(&mut response).unwrap();
+            let (mac, _, _) = key
+                .0
+                .verify_message_byte(Some(&previous), &response, first)
+                .unwrap();
+            previous = mac;
+        }
+    }
+}
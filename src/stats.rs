@@ -0,0 +1,384 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Utc};
+
+use crate::privacy::Privacy;
+
+/// Bucket size of an aggregate
+#[derive(serde::Deserialize, serde::Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    #[default]
+    Minute,
+    Hour,
+    Day,
+}
+
+impl Period {
+    pub const ALL: [Period; 3] = [Period::Minute, Period::Hour, Period::Day];
+
+    fn seconds(&self) -> i64 {
+        match self {
+            Self::Minute => 60,
+            Self::Hour => 3600,
+            Self::Day => 86400,
+        }
+    }
+
+    /// Number of buckets kept, covering the last hour, two days or month
+    fn buckets(&self) -> usize {
+        match self {
+            Self::Minute => 60,
+            Self::Hour => 48,
+            Self::Day => 30,
+        }
+    }
+
+    /// Keys kept by each top counter in a bucket
+    fn capacity(&self) -> usize {
+        match self {
+            Self::Minute => 200,
+            Self::Hour => 500,
+            Self::Day => 1000,
+        }
+    }
+}
+
+impl std::fmt::Display for Period {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Minute => "minute",
+            Self::Hour => "hour",
+            Self::Day => "day",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// Approximate counts of the most frequent keys, in bounded memory
+#[derive(Debug)]
+struct TopCounter {
+    counts: HashMap<String, u64>,
+    capacity: usize,
+}
+
+impl TopCounter {
+    fn new(capacity: usize) -> Self {
+        Self {
+            counts: HashMap::new(),
+            capacity,
+        }
+    }
+
+    fn add(&mut self, key: &str) {
+        if let Some(count) = self.counts.get_mut(key) {
+            *count += 1;
+            return;
+        }
+
+        if self.counts.len() >= self.capacity {
+            self.prune();
+        }
+        self.counts.insert(key.to_string(), 1);
+    }
+
+    /// Drop the less frequent half of the keys, heavy hitters survive
+    fn prune(&mut self) {
+        let mut counts: Vec<u64> = self.counts.values().copied().collect();
+        let mid = counts.len() / 2;
+        let (_, cutoff, _) = counts.select_nth_unstable(mid);
+        let cutoff = *cutoff;
+
+        self.counts.retain(|_, count| *count > cutoff);
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    start: i64,
+    queries: u64,
+    blocked: u64,
+    domains: TopCounter,
+    blocked_domains: TopCounter,
+    clients: TopCounter,
+}
+
+impl Bucket {
+    fn new(start: i64, capacity: usize) -> Self {
+        Self {
+            start,
+            queries: 0,
+            blocked: 0,
+            domains: TopCounter::new(capacity),
+            blocked_domains: TopCounter::new(capacity),
+            clients: TopCounter::new(capacity),
+        }
+    }
+}
+
+/// The most recent buckets of one period, oldest first
+#[derive(Debug)]
+struct Ring {
+    period: Period,
+    buckets: VecDeque<Bucket>,
+}
+
+impl Ring {
+    fn new(period: Period) -> Self {
+        Self {
+            period,
+            buckets: VecDeque::new(),
+        }
+    }
+
+    fn align(&self, now: i64) -> i64 {
+        now - now.rem_euclid(self.period.seconds())
+    }
+
+    /// Start of the oldest bucket still in the window ending at `now`
+    fn window_start(&self, now: i64) -> i64 {
+        self.align(now) - (self.period.buckets() as i64 - 1) * self.period.seconds()
+    }
+
+    fn evict(&mut self, now: i64) {
+        let window_start = self.window_start(now);
+        while self.buckets.front().is_some_and(|b| b.start < window_start) {
+            self.buckets.pop_front();
+        }
+    }
+
+    fn current(&mut self, now: i64) -> &mut Bucket {
+        self.evict(now);
+
+        let start = self.align(now);
+        if self.buckets.back().is_none_or(|b| b.start < start) {
+            self.buckets
+                .push_back(Bucket::new(start, self.period.capacity()));
+        }
+
+        self.buckets.back_mut().unwrap()
+    }
+}
+
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct TopEntry {
+    pub name: String,
+    pub count: u64,
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct BucketReport {
+    pub start: DateTime<Utc>,
+    pub queries: u64,
+    pub blocked: u64,
+    pub blocked_percent: f64,
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct StatsReport {
+    pub period: Period,
+    pub queries: u64,
+    pub blocked: u64,
+    pub blocked_percent: f64,
+    pub buckets: Vec<BucketReport>,
+    pub top_domains: Vec<TopEntry>,
+    pub top_blocked: Vec<TopEntry>,
+    pub top_clients: Vec<TopEntry>,
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / total as f64
+    }
+}
+
+fn top<'a>(counters: impl Iterator<Item = &'a TopCounter>, n: usize) -> Vec<TopEntry> {
+    let mut totals: HashMap<&str, u64> = HashMap::new();
+    for counter in counters {
+        for (key, count) in &counter.counts {
+            *totals.entry(key).or_default() += count;
+        }
+    }
+
+    let mut entries: Vec<_> = totals
+        .into_iter()
+        .map(|(name, count)| TopEntry {
+            name: name.to_string(),
+            count,
+        })
+        .collect();
+    entries.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(&b.name)));
+    entries.truncate(n);
+    entries
+}
+
+/// Query counters aggregated into minute, hour and day buckets.
+/// Clients and names are only counted as far as the privacy mode allows.
+#[derive(Debug)]
+pub struct StatsStore {
+    rings: Mutex<Vec<Ring>>,
+    privacy: Arc<Privacy>,
+}
+
+impl StatsStore {
+    pub fn new(privacy: Arc<Privacy>) -> Self {
+        Self {
+            rings: Mutex::new(Period::ALL.into_iter().map(Ring::new).collect()),
+            privacy,
+        }
+    }
+
+    pub fn record(&self, client: IpAddr, domain: &str, blocked: bool) {
+        self.record_at(Utc::now().timestamp(), client, domain, blocked);
+    }
+
+    fn record_at(&self, now: i64, client: IpAddr, domain: &str, blocked: bool) {
+        let domain = self
+            .privacy
+            .keeps_domains()
+            .then(|| domain.trim_end_matches('.').to_lowercase());
+        let client = self.privacy.client_id(client);
+
+        let mut rings = self.rings.lock().unwrap();
+        for ring in rings.iter_mut() {
+            let bucket = ring.current(now);
+            bucket.queries += 1;
+            if blocked {
+                bucket.blocked += 1;
+            }
+            if let Some(domain) = &domain {
+                bucket.domains.add(domain);
+                if blocked {
+                    bucket.blocked_domains.add(domain);
+                }
+            }
+            if let Some(client) = &client {
+                bucket.clients.add(client);
+            }
+        }
+    }
+
+    /// Aggregates over the window of `period` buckets, with the `n` most frequent of each top list
+    pub fn report(&self, period: Period, n: usize) -> StatsReport {
+        self.report_at(Utc::now().timestamp(), period, n)
+    }
+
+    fn report_at(&self, now: i64, period: Period, n: usize) -> StatsReport {
+        let mut rings = self.rings.lock().unwrap();
+        let ring = rings
+            .iter_mut()
+            .find(|r| r.period == period)
+            .expect("a ring for every period");
+        ring.evict(now);
+
+        // one entry per bucket in the window, including the empty ones
+        let counts: HashMap<i64, (u64, u64)> = ring
+            .buckets
+            .iter()
+            .map(|b| (b.start, (b.queries, b.blocked)))
+            .collect();
+        let buckets: Vec<_> = (0..period.buckets() as i64)
+            .map(|i| ring.window_start(now) + i * period.seconds())
+            .map(|start| {
+                let (queries, blocked) = counts.get(&start).copied().unwrap_or_default();
+                BucketReport {
+                    start: DateTime::from_timestamp(start, 0).unwrap_or_default(),
+                    queries,
+                    blocked,
+                    blocked_percent: percent(blocked, queries),
+                }
+            })
+            .collect();
+
+        let queries = buckets.iter().map(|b| b.queries).sum();
+        let blocked = buckets.iter().map(|b| b.blocked).sum();
+
+        StatsReport {
+            period,
+            queries,
+            blocked,
+            blocked_percent: percent(blocked, queries),
+            buckets,
+            top_domains: top(ring.buckets.iter().map(|b| &b.domains), n),
+            top_blocked: top(ring.buckets.iter().map(|b| &b.blocked_domains), n),
+            top_clients: top(ring.buckets.iter().map(|b| &b.clients), n),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::privacy::PrivacyMode;
+
+    use super::*;
+
+    #[test]
+    fn it_aggregates_into_buckets_and_expires_them() {
+        let stats = StatsStore::new(Arc::new(Privacy::default()));
+        let client: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let t0 = 1_700_000_000 - 1_700_000_000 % 86400;
+        stats.record_at(t0, client, "ads.example.com.", true);
+        stats.record_at(t0 + 5, client, "ADS.example.com.", true);
+        stats.record_at(t0 + 70, other, "example.com.", false);
+        stats.record_at(t0 + 130, client, "example.com.", false);
+
+        let report = stats.report_at(t0 + 130, Period::Minute, 10);
+        assert_eq!(report.buckets.len(), 60);
+        assert_eq!((report.queries, report.blocked), (4, 2));
+        assert_eq!(report.blocked_percent, 50.0);
+        let last: Vec<_> = report.buckets[57..].iter().map(|b| b.queries).collect();
+        assert_eq!(last, vec![2, 1, 1]);
+        assert_eq!(
+            report.top_blocked,
+            vec![TopEntry {
+                name: "ads.example.com".to_string(),
+                count: 2
+            }]
+        );
+        assert_eq!(report.top_clients[0].name, "10.0.0.1");
+        assert_eq!(report.top_clients[0].count, 3);
+
+        // an hour later the minute buckets are gone, the hour and day ones remain
+        let report = stats.report_at(t0 + 3600 + 130, Period::Minute, 10);
+        assert_eq!(report.queries, 0);
+        assert!(report.top_domains.is_empty());
+        assert_eq!(stats.report_at(t0 + 3600, Period::Hour, 10).queries, 4);
+        assert_eq!(stats.report_at(t0 + 3600, Period::Day, 10).queries, 4);
+    }
+
+    #[test]
+    fn it_leaves_out_what_the_privacy_mode_hides() {
+        let privacy = Privacy::new(PrivacyMode::Off, None, None).unwrap();
+        let stats = StatsStore::new(Arc::new(privacy));
+        let client: IpAddr = "10.0.0.1".parse().unwrap();
+        stats.record_at(1_700_000_000, client, "ads.example.com.", true);
+
+        let report = stats.report_at(1_700_000_000, Period::Minute, 10);
+        assert_eq!((report.queries, report.blocked), (1, 1));
+        assert!(report.top_domains.is_empty());
+        assert!(report.top_blocked.is_empty());
+        assert!(report.top_clients.is_empty());
+    }
+
+    #[test]
+    fn it_keeps_heavy_hitters_within_capacity() {
+        let mut counter = TopCounter::new(10);
+        for _ in 0..5 {
+            counter.add("heavy");
+        }
+        for i in 0..100 {
+            counter.add(&format!("once{i}"));
+        }
+
+        assert!(counter.counts.len() <= 10);
+        assert_eq!(counter.counts.get("heavy"), Some(&5));
+    }
+}
@@ -6,9 +6,13 @@ use futures::StreamExt;
 use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
 use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
 use rustls::DigitallySignedStruct;
-use rustls_acme::{caches::DirCache, AcmeConfig, AcmeState, ResolvesServerCertAcme, UseChallenge};
+use rustls_acme::{
+    caches::DirCache, AcmeConfig, AcmeState, CertCache, ResolvesServerCertAcme, UseChallenge,
+};
 use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
+use crate::metrics::Metrics;
+
 /// Skips all TLS certificate verification. Only for use with local test ACME servers (Pebble).
 #[derive(Debug)]
 struct NoVerifier;
@@ -50,12 +54,66 @@ impl ServerCertVerifier for NoVerifier {
     }
 }
 
+/// Expiry of the first certificate in a cached PEM bundle, as a unix timestamp
+fn cert_expiry(pem: &[u8]) -> Option<i64> {
+    let pems = pem::parse_many(pem).ok()?;
+    let cert = pems.iter().find(|p| p.tag() == "CERTIFICATE")?;
+    let (_, cert) = x509_parser::parse_x509_certificate(cert.contents()).ok()?;
+
+    Some(cert.validity().not_after.timestamp())
+}
+
+/// Certificate cache that records the expiry of each certificate it loads or stores
+struct ExpiryCache {
+    cache: DirCache<String>,
+    metrics: Arc<Metrics>,
+}
+
+impl ExpiryCache {
+    fn record_expiry(&self, pem: &[u8]) {
+        match cert_expiry(pem) {
+            Some(expiry) => self.metrics.set_cert_expiry(expiry),
+            None => tracing::warn!("acme certificate expiry could not be read"),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl CertCache for ExpiryCache {
+    type EC = std::io::Error;
+
+    async fn load_cert(
+        &self,
+        domains: &[String],
+        directory_url: &str,
+    ) -> Result<Option<Vec<u8>>, Self::EC> {
+        let cert = self.cache.load_cert(domains, directory_url).await?;
+        if let Some(pem) = &cert {
+            self.record_expiry(pem);
+        }
+
+        Ok(cert)
+    }
+
+    async fn store_cert(
+        &self,
+        domains: &[String],
+        directory_url: &str,
+        cert: &[u8],
+    ) -> Result<(), Self::EC> {
+        self.record_expiry(cert);
+        self.cache.store_cert(domains, directory_url, cert).await
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn setup_tls(
     domain: String,
     email: String,
     acme_url: Option<String>,
     acme_cache_dir: String,
     acme_insecure: bool,
+    metrics: Arc<Metrics>,
     tracker: &TaskTracker,
     token: CancellationToken,
 ) -> Arc<ResolvesServerCertAcme> {
@@ -77,7 +135,13 @@ pub async fn setup_tls(
 
     let mut config = base_config
         .contact_push(format!("mailto:{email}"))
-        .cache(DirCache::new(acme_cache_dir))
+        .cache_compose(
+            ExpiryCache {
+                cache: DirCache::new(acme_cache_dir.clone()),
+                metrics,
+            },
+            DirCache::new(acme_cache_dir),
+        )
         .challenge_type(UseChallenge::Http01);
 
     if let Some(url) = acme_url {